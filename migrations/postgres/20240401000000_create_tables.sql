@@ -0,0 +1,36 @@
+-- Ids are ObjectId hex strings generated by the application, so they are portable between the backends
+CREATE TABLE users (
+    id TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    location TEXT NOT NULL,
+    title TEXT NOT NULL,
+    -- Same weights as the text index of MongoDB, the name first, then the title and the location
+    search TSVECTOR GENERATED ALWAYS AS (
+        setweight(to_tsvector('simple', name), 'A') ||
+        setweight(to_tsvector('simple', title), 'B') ||
+        setweight(to_tsvector('simple', location), 'C')
+    ) STORED
+);
+
+CREATE INDEX users_name ON users (name, id);
+CREATE INDEX users_location ON users (location, id);
+CREATE INDEX users_title ON users (title, id);
+CREATE INDEX users_search ON users USING GIN (search);
+
+CREATE TABLE api_keys (
+    key_id TEXT PRIMARY KEY,
+    api_key JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE TABLE audit_records (
+    id BIGSERIAL PRIMARY KEY,
+    actor_subject TEXT NOT NULL,
+    target_id TEXT,
+    recorded_at TIMESTAMPTZ NOT NULL,
+    record JSONB NOT NULL
+);
+
+CREATE INDEX audit_records_recorded_at ON audit_records (recorded_at DESC);
+CREATE INDEX audit_records_actor_subject ON audit_records (actor_subject, recorded_at DESC);
+CREATE INDEX audit_records_target_id ON audit_records (target_id, recorded_at DESC);
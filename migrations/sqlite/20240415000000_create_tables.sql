@@ -0,0 +1,54 @@
+-- Ids are ObjectId hex strings generated by the application, so they are portable between the backends
+CREATE TABLE users (
+    id TEXT PRIMARY KEY NOT NULL,
+    name TEXT NOT NULL,
+    location TEXT NOT NULL,
+    title TEXT NOT NULL
+);
+
+CREATE INDEX users_name ON users (name, id);
+CREATE INDEX users_location ON users (location, id);
+CREATE INDEX users_title ON users (title, id);
+
+-- Full text index of the users, kept in sync by the triggers
+CREATE VIRTUAL TABLE users_search USING fts5 (
+    name, title, location,
+    content = 'users',
+    tokenize = 'unicode61'
+);
+
+CREATE TRIGGER users_search_insert AFTER INSERT ON users BEGIN
+    INSERT INTO users_search (rowid, name, title, location)
+    VALUES (new.rowid, new.name, new.title, new.location);
+END;
+
+CREATE TRIGGER users_search_delete AFTER DELETE ON users BEGIN
+    INSERT INTO users_search (users_search, rowid, name, title, location)
+    VALUES ('delete', old.rowid, old.name, old.title, old.location);
+END;
+
+CREATE TRIGGER users_search_update AFTER UPDATE ON users BEGIN
+    INSERT INTO users_search (users_search, rowid, name, title, location)
+    VALUES ('delete', old.rowid, old.name, old.title, old.location);
+    INSERT INTO users_search (rowid, name, title, location)
+    VALUES (new.rowid, new.name, new.title, new.location);
+END;
+
+CREATE TABLE api_keys (
+    key_id TEXT PRIMARY KEY NOT NULL,
+    api_key TEXT NOT NULL,
+    created_at TEXT NOT NULL
+);
+
+-- recorded_at is in milliseconds since the epoch, so the records are compared and sorted as numbers
+CREATE TABLE audit_records (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    actor_subject TEXT NOT NULL,
+    target_id TEXT,
+    recorded_at INTEGER NOT NULL,
+    record TEXT NOT NULL
+);
+
+CREATE INDEX audit_records_recorded_at ON audit_records (recorded_at);
+CREATE INDEX audit_records_actor_subject ON audit_records (actor_subject, recorded_at);
+CREATE INDEX audit_records_target_id ON audit_records (target_id, recorded_at);
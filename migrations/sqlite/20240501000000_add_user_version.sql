@@ -0,0 +1,2 @@
+-- Incremented by every write that changes the user, for the If-Match of the API
+ALTER TABLE users ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
@@ -0,0 +1,3 @@
+-- Names identify the keys in the audit log and the rate limits, so enabled keys can't share them
+CREATE UNIQUE INDEX api_keys_enabled_name ON api_keys (json_extract(api_key, '$.name'))
+    WHERE json_extract(api_key, '$.enabled');
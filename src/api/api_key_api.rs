@@ -0,0 +1,197 @@
+use crate::api::{audit_api::record_audit, error::ApiError, request_id::RequestId};
+use crate::auth::api_key::{
+    generate_api_key, generate_key_id, generate_salt, hash_api_key, ApiKeyEntry,
+};
+use crate::auth::{
+    claims::Permission, permission_middleware::RequirePermissions, principal::Principal,
+};
+use crate::database::error::RepositoryError;
+use crate::models::api_key_model::{ApiKey, ApiKeyInfo, CreateApiKeyRequest, CreatedApiKey};
+use crate::models::app::AppData;
+use crate::models::audit_model::{AuditAction, AuditRecord};
+use actix_web::{
+    delete, get, post,
+    web::{Data, Json, Path},
+    HttpResponse,
+};
+use chrono::Utc;
+
+#[post(
+    "/api-keys",
+    wrap = "RequirePermissions::new(&[Permission::ApiKeysManage])"
+)]
+pub async fn create_api_key(
+    app_data: Data<AppData>,
+    new_key: Json<CreateApiKeyRequest>,
+    principal: Principal,
+    request_id: RequestId,
+) -> Result<HttpResponse, ApiError> {
+    // The names identify the keys in the audit log and the rate limits, so blanks don't make a new one
+    let name = new_key.name.trim();
+    if name.is_empty() {
+        return Err(ApiError::Validation("name is required".to_string()));
+    }
+    if let Some(permission) = missing_permission(&principal, &new_key.permissions) {
+        return Err(ApiError::Forbidden(format!(
+            "The new key can't have {}, which the caller doesn't have",
+            permission
+        )));
+    }
+    let key_id = generate_key_id();
+    let plaintext_key = generate_api_key(&key_id);
+    let salt = generate_salt();
+    let api_key = ApiKey {
+        key_id,
+        entry: ApiKeyEntry {
+            name: name.to_string(),
+            hash: hash_api_key(&app_data.config.api_key_data.pepper, &salt, &plaintext_key),
+            salt,
+            permissions: new_key.permissions.to_owned(),
+            expires_at: new_key.expires_at,
+            enabled: true,
+        },
+        created_at: Utc::now(),
+    };
+    let info = ApiKeyInfo::from(&api_key);
+    let audit = AuditRecord::new(&principal, AuditAction::ApiKeyCreate, &request_id.0)
+        .target(&info.key_id)
+        .after(&info);
+    // Enabled keys can't share a name, the repository rejects it atomically
+    let result = app_data
+        .api_keys
+        .create_api_key(api_key)
+        .await
+        .map_err(|err| match err {
+            RepositoryError::Conflict(_) => {
+                ApiError::Conflict(format!("An API key named {} already exists", name))
+            }
+            err => ApiError::from(err),
+        });
+    let audit = match &result {
+        Ok(_) => audit,
+        Err(err) => audit.failed(&err.to_string()),
+    };
+    record_audit(&app_data, audit).await;
+    result?;
+    Ok(HttpResponse::Created().json(CreatedApiKey {
+        info,
+        api_key: plaintext_key,
+    }))
+}
+
+// Otherwise managing the keys would grant every permission, e.g. with a key holding Write
+fn missing_permission<'a>(principal: &Principal, permissions: &'a [String]) -> Option<&'a String> {
+    permissions
+        .iter()
+        .find(|permission| !principal.grants(permission))
+}
+
+#[get(
+    "/api-keys",
+    wrap = "RequirePermissions::new(&[Permission::ApiKeysManage])"
+)]
+pub async fn list_api_keys(app_data: Data<AppData>) -> Result<HttpResponse, ApiError> {
+    let api_keys = app_data.api_keys.list_api_keys().await?;
+    Ok(HttpResponse::Ok().json(api_keys.iter().map(ApiKeyInfo::from).collect::<Vec<_>>()))
+}
+
+// Replaces the secret of the key, keeping its id, name and permissions, the old key stops working right away
+#[post(
+    "/api-keys/{key_id}/rotate",
+    wrap = "RequirePermissions::new(&[Permission::ApiKeysManage])"
+)]
+pub async fn rotate_api_key(
+    app_data: Data<AppData>,
+    path: Path<String>,
+    principal: Principal,
+    request_id: RequestId,
+) -> Result<HttpResponse, ApiError> {
+    let key_id = path.into_inner();
+    let audit =
+        AuditRecord::new(&principal, AuditAction::ApiKeyRotate, &request_id.0).target(&key_id);
+    let mut api_key = match find_api_key(&app_data, &key_id).await {
+        Ok(api_key) => api_key,
+        Err(err) => {
+            record_audit(&app_data, audit.failed(&err.to_string())).await;
+            return Err(err);
+        }
+    };
+    let audit = audit.before(&ApiKeyInfo::from(&api_key));
+    // The new secret works with every permission of the key
+    if let Some(permission) = missing_permission(&principal, &api_key.entry.permissions) {
+        let err = ApiError::Forbidden(format!(
+            "The key has {}, which the caller doesn't have",
+            permission
+        ));
+        record_audit(&app_data, audit.failed(&err.to_string())).await;
+        return Err(err);
+    }
+    let plaintext_key = generate_api_key(&key_id);
+    api_key.entry.salt = generate_salt();
+    api_key.entry.hash = hash_api_key(
+        &app_data.config.api_key_data.pepper,
+        &api_key.entry.salt,
+        &plaintext_key,
+    );
+    let info = ApiKeyInfo::from(&api_key);
+    update_api_key(&app_data, api_key, audit.after(&info)).await?;
+    Ok(HttpResponse::Ok().json(CreatedApiKey {
+        info,
+        api_key: plaintext_key,
+    }))
+}
+
+// Revoked keys are kept disabled, so they still show up when listing the keys
+#[delete(
+    "/api-keys/{key_id}",
+    wrap = "RequirePermissions::new(&[Permission::ApiKeysManage])"
+)]
+pub async fn revoke_api_key(
+    app_data: Data<AppData>,
+    path: Path<String>,
+    principal: Principal,
+    request_id: RequestId,
+) -> Result<HttpResponse, ApiError> {
+    let key_id = path.into_inner();
+    let audit =
+        AuditRecord::new(&principal, AuditAction::ApiKeyRevoke, &request_id.0).target(&key_id);
+    let mut api_key = match find_api_key(&app_data, &key_id).await {
+        Ok(api_key) => api_key,
+        Err(err) => {
+            record_audit(&app_data, audit.failed(&err.to_string())).await;
+            return Err(err);
+        }
+    };
+    let audit = audit.before(&ApiKeyInfo::from(&api_key));
+    api_key.entry.enabled = false;
+    let info = ApiKeyInfo::from(&api_key);
+    update_api_key(&app_data, api_key, audit.after(&info)).await?;
+    Ok(HttpResponse::Ok().json(info))
+}
+
+async fn find_api_key(app_data: &AppData, key_id: &str) -> Result<ApiKey, ApiError> {
+    app_data
+        .api_keys
+        .get_api_key(key_id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("API key not found".to_string()))
+}
+
+// Stores the key and its audit record, that is marked as failed if the key couldn't be updated
+async fn update_api_key(
+    app_data: &AppData,
+    api_key: ApiKey,
+    audit: AuditRecord,
+) -> Result<(), ApiError> {
+    let result = match app_data.api_keys.update_api_key(api_key).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(ApiError::NotFound("API key not found".to_string())),
+        Err(err) => Err(ApiError::from(err)),
+    };
+    let audit = match &result {
+        Ok(_) => audit,
+        Err(err) => audit.failed(&err.to_string()),
+    };
+    record_audit(app_data, audit).await;
+    result
+}
@@ -0,0 +1,58 @@
+use crate::api::error::ApiError;
+use crate::auth::{claims::Permission, permission_middleware::RequirePermissions};
+use crate::models::app::AppData;
+use crate::models::audit_model::{AuditQuery, AuditRecord};
+use actix_web::{
+    get,
+    web::{Data, Query},
+    HttpResponse,
+};
+
+// The mutation already happened when it's audited, so a failure to store the record is logged instead of
+// failing the request
+pub(crate) async fn record_audit(app_data: &AppData, record: AuditRecord) {
+    let action = record.action;
+    let request_id = record.request_id.to_string();
+    if let Err(err) = app_data.audit.record_audit(record).await {
+        log::error!(
+            "failed to store audit record of {} for request {}: {}",
+            action,
+            request_id,
+            err
+        );
+    }
+}
+
+pub(crate) async fn record_audits(app_data: &AppData, records: Vec<AuditRecord>) {
+    let Some(record) = records.first() else {
+        return;
+    };
+    let action = record.action;
+    let request_id = record.request_id.to_string();
+    let count = records.len();
+    if let Err(err) = app_data.audit.record_audits(records).await {
+        log::error!(
+            "failed to store {} audit records of {} for request {}: {}",
+            count,
+            action,
+            request_id,
+            err
+        );
+    }
+}
+
+// e.g. GET /api/admin/audit?actor=auth0|123&target=65f0bbf848c60e78920bfd4c&from=2024-03-01T00:00:00Z&limit=50
+#[get("/audit", wrap = "RequirePermissions::new(&[Permission::AuditRead])")]
+pub async fn get_audit_records(
+    app_data: Data<AppData>,
+    query: Query<AuditQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let query = query.into_inner();
+    if let (Some(from), Some(to)) = (query.from, query.to) {
+        if from > to {
+            return Err(ApiError::InvalidQuery("from must be before to".to_string()));
+        }
+    }
+    let records = app_data.audit.find_audit_records(query).await?;
+    Ok(HttpResponse::Ok().json(records))
+}
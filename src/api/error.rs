@@ -1 +1,340 @@
+use crate::auth::error::ClientError;
+use crate::database::error::RepositoryError;
+use actix_web::{
+    error::{JsonPayloadError, PathError, QueryPayloadError},
+    http::{header::ContentType, StatusCode},
+    web::{self, JsonConfig, PathConfig, QueryConfig, ServiceConfig},
+    HttpRequest, HttpResponse, ResponseError,
+};
+use serde::{Deserialize, Serialize};
+use validator::ValidationErrors;
 
+// Every error of the API, rendered as RFC 7807 application/problem+json. The codes are part of the API
+// contract, clients may rely on them, so existing ones must not be renamed
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum ApiError {
+    #[error("{0}")]
+    InvalidId(String),
+    #[error("{0}")]
+    InvalidBody(String),
+    #[error("{0}")]
+    InvalidQuery(String),
+    #[error("{0}")]
+    Unauthenticated(String),
+    #[error("{0}")]
+    InvalidCredentials(String),
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    Conflict(String),
+    // The resource changed since the client read it, e.g. an If-Match with an old ETag
+    #[error("{0}")]
+    PreconditionFailed(String),
+    #[error("{0}")]
+    Validation(String),
+    // The body is well-formed, but some of its fields break the rules of the model
+    #[error("invalid fields: {}", field_names(.0))]
+    InvalidFields(Vec<FieldError>),
+    #[error("{0}")]
+    UnsupportedMediaType(String),
+    #[error("{0}")]
+    PayloadTooLarge(String),
+    #[error("too many requests")]
+    RateLimited,
+    #[error("{0}")]
+    Internal(String),
+    #[error("{0}")]
+    NotImplemented(String),
+    #[error("{0}")]
+    Unavailable(String),
+}
+
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FieldError {
+    pub field: String,
+    pub code: String,
+    pub message: String,
+}
+
+fn field_names(errors: &[FieldError]) -> String {
+    errors
+        .iter()
+        .map(|error| error.field.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    pub title: String,
+    pub status: u16,
+    pub detail: String,
+    pub code: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+}
+
+impl ApiError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::InvalidId(_) => "invalid_id",
+            Self::InvalidBody(_) => "invalid_body",
+            Self::InvalidQuery(_) => "invalid_query",
+            Self::Unauthenticated(_) => "unauthenticated",
+            Self::InvalidCredentials(_) => "invalid_credentials",
+            Self::Forbidden(_) => "insufficient_permissions",
+            Self::NotFound(_) => "not_found",
+            Self::Conflict(_) => "conflict",
+            Self::PreconditionFailed(_) => "precondition_failed",
+            Self::Validation(_) | Self::InvalidFields(_) => "validation_failed",
+            Self::UnsupportedMediaType(_) => "unsupported_media_type",
+            Self::PayloadTooLarge(_) => "payload_too_large",
+            Self::RateLimited => "rate_limited",
+            Self::Internal(_) => "internal_error",
+            Self::NotImplemented(_) => "not_implemented",
+            Self::Unavailable(_) => "service_unavailable",
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match self {
+            Self::InvalidId(_) => "Invalid id",
+            Self::InvalidBody(_) => "Invalid request body",
+            Self::InvalidQuery(_) => "Invalid query string",
+            Self::Unauthenticated(_) => "Requires authentication",
+            Self::InvalidCredentials(_) => "Bad credentials",
+            Self::Forbidden(_) => "Insufficient permissions",
+            Self::NotFound(_) => "Not found",
+            Self::Conflict(_) => "Conflict",
+            Self::PreconditionFailed(_) => "Precondition failed",
+            Self::Validation(_) | Self::InvalidFields(_) => "Validation failed",
+            Self::UnsupportedMediaType(_) => "Unsupported media type",
+            Self::PayloadTooLarge(_) => "Payload too large",
+            Self::RateLimited => "Too many requests",
+            Self::Internal(_) => "Internal server error",
+            Self::NotImplemented(_) => "Not implemented",
+            Self::Unavailable(_) => "Service unavailable",
+        }
+    }
+
+    pub fn problem(&self, request_id: Option<&str>) -> Problem {
+        Problem {
+            problem_type: format!("/problems/{}", self.code()),
+            title: self.title().to_string(),
+            status: self.status_code().as_u16(),
+            detail: self.to_string(),
+            code: self.code().to_string(),
+            request_id: request_id.map(str::to_string),
+            errors: match self {
+                Self::InvalidFields(errors) => errors.to_owned(),
+                _ => vec![],
+            },
+        }
+    }
+
+    pub fn problem_response(&self, request_id: Option<&str>) -> HttpResponse {
+        let body = serde_json::to_string(&self.problem(request_id)).unwrap_or_default();
+        HttpResponse::build(self.status_code())
+            .insert_header(ContentType(PROBLEM_JSON.parse().unwrap()))
+            .body(body)
+    }
+
+    // JSON that doesn't match the model, e.g. a missing field
+    pub fn from_data_error(err: &serde_json::Error) -> ApiError {
+        match deserialize_field_error(err) {
+            Some(field_error) => ApiError::InvalidFields(vec![field_error]),
+            None => ApiError::Validation(err.to_string()),
+        }
+    }
+
+    // Errors of one item of a bulk request, e.g. a row of an import, the ones that don't concern a field, e.g.
+    // malformed JSON, have an empty field
+    pub fn into_field_errors(self) -> Vec<FieldError> {
+        match self {
+            ApiError::InvalidFields(errors) => errors,
+            err => vec![FieldError {
+                field: String::new(),
+                code: err.code().to_string(),
+                message: err.to_string(),
+            }],
+        }
+    }
+
+    // The errors of the other layers are rendered as problems too, so every error response looks the same
+    pub fn from_error(err: &actix_web::Error) -> Option<ApiError> {
+        if let Some(err) = err.as_error::<ApiError>() {
+            return Some(err.clone());
+        }
+        if let Some(err) = err.as_error::<ClientError>() {
+            return Some(ApiError::from(err));
+        }
+        err.as_error::<RepositoryError>().map(ApiError::from)
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::InvalidId(_) | Self::InvalidBody(_) | Self::InvalidQuery(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            Self::Unauthenticated(_) | Self::InvalidCredentials(_) => StatusCode::UNAUTHORIZED,
+            Self::Forbidden(_) => StatusCode::FORBIDDEN,
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::Conflict(_) => StatusCode::CONFLICT,
+            Self::PreconditionFailed(_) => StatusCode::PRECONDITION_FAILED,
+            Self::Validation(_) | Self::InvalidFields(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Self::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::NotImplemented(_) => StatusCode::NOT_IMPLEMENTED,
+            Self::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        self.problem_response(None)
+    }
+}
+
+impl From<&ClientError> for ApiError {
+    fn from(err: &ClientError) -> Self {
+        match err {
+            ClientError::Authentication(_) => ApiError::Unauthenticated(
+                "Authorization header value must follow this format: Bearer access-token"
+                    .to_string(),
+            ),
+            ClientError::MissingCredentials => {
+                ApiError::Unauthenticated("The caller isn't authenticated".to_string())
+            }
+            ClientError::Decode(err) => ApiError::InvalidCredentials(err.to_string()),
+            ClientError::NotFound(msg) | ClientError::InvalidApiKey(msg) => {
+                ApiError::InvalidCredentials(msg.to_string())
+            }
+            ClientError::UnsupportedAlgorithm(alg) => {
+                ApiError::InvalidCredentials(format!("Unsupported signing algorithm {:?}", alg))
+            }
+            ClientError::NoPermission(permissions) => {
+                ApiError::Forbidden(format!("Your user doesn't have {} permission", permissions))
+            }
+            // The IdP or the key store couldn't be reached, the credentials may be valid
+            ClientError::SendRequestError(err) => ApiError::Unavailable(err.to_string()),
+            ClientError::JsonPayloadError(err) => ApiError::Unavailable(err.to_string()),
+            ClientError::ApiKeyStore(msg) => ApiError::Unavailable(msg.to_string()),
+            ClientError::InvalidConfiguration(msg) => ApiError::Internal(msg.to_string()),
+        }
+    }
+}
+
+impl From<ClientError> for ApiError {
+    fn from(err: ClientError) -> Self {
+        ApiError::from(&err)
+    }
+}
+
+impl From<&RepositoryError> for ApiError {
+    fn from(err: &RepositoryError) -> Self {
+        match err {
+            RepositoryError::InvalidId(_) => ApiError::InvalidId(err.to_string()),
+            RepositoryError::NotFound(_) => ApiError::NotFound(err.to_string()),
+            RepositoryError::Conflict(_) => ApiError::Conflict(err.to_string()),
+            RepositoryError::VersionMismatch(_) => ApiError::PreconditionFailed(err.to_string()),
+            RepositoryError::Unavailable(_) | RepositoryError::Timeout(_) => {
+                ApiError::Unavailable(err.to_string())
+            }
+            RepositoryError::Unsupported(_) => ApiError::NotImplemented(err.to_string()),
+            RepositoryError::CreateUpdateUser(_)
+            | RepositoryError::DeleteUser(_)
+            | RepositoryError::CreateUpdateApiKey(_)
+            | RepositoryError::CreateAuditRecord(_)
+            | RepositoryError::GeneralError(_) => ApiError::Internal(err.to_string()),
+        }
+    }
+}
+
+impl From<RepositoryError> for ApiError {
+    fn from(err: RepositoryError) -> Self {
+        ApiError::from(&err)
+    }
+}
+
+impl From<ValidationErrors> for ApiError {
+    fn from(errors: ValidationErrors) -> Self {
+        let mut field_errors: Vec<FieldError> = errors
+            .field_errors()
+            .into_iter()
+            .flat_map(|(field, errors)| {
+                errors.iter().map(move |error| FieldError {
+                    field: field.to_string(),
+                    code: error.code.to_string(),
+                    message: error
+                        .message
+                        .as_ref()
+                        .map(|message| message.to_string())
+                        .unwrap_or_else(|| error.to_string()),
+                })
+            })
+            .collect();
+        field_errors.sort_by(|a, b| a.field.cmp(&b.field));
+        ApiError::InvalidFields(field_errors)
+    }
+}
+
+// serde only reports the field in the message, e.g. "missing field `title` at line 1 column 17"
+fn deserialize_field_error(err: &serde_json::Error) -> Option<FieldError> {
+    let message = err.to_string();
+    let (code, rest) = [
+        ("required", "missing field `"),
+        ("unknown_field", "unknown field `"),
+    ]
+    .into_iter()
+    .find_map(|(code, prefix)| Some((code, message.split_once(prefix)?.1)))?;
+    let field = rest.split('`').next()?.to_string();
+    Some(FieldError {
+        field,
+        code: code.to_string(),
+        message,
+    })
+}
+
+// Error handlers of the extractors, registered with JsonConfig, QueryConfig and PathConfig
+fn json_error_handler(err: JsonPayloadError, _: &HttpRequest) -> actix_web::Error {
+    match err {
+        // Syntax errors are malformed JSON, data errors are JSON that doesn't match the model
+        JsonPayloadError::Deserialize(err) if err.is_data() => {
+            ApiError::from_data_error(&err).into()
+        }
+        err => ApiError::InvalidBody(err.to_string()).into(),
+    }
+}
+
+fn query_error_handler(err: QueryPayloadError, _: &HttpRequest) -> actix_web::Error {
+    ApiError::InvalidQuery(err.to_string()).into()
+}
+
+fn path_error_handler(err: PathError, _: &HttpRequest) -> actix_web::Error {
+    ApiError::InvalidId(err.to_string()).into()
+}
+
+async fn not_found() -> Result<HttpResponse, ApiError> {
+    Err(ApiError::NotFound(
+        "No route matches the request".to_string(),
+    ))
+}
+
+// Makes the errors of the extractors and unknown routes problems too, e.g. App::new().configure(error_handlers)
+pub fn error_handlers(cfg: &mut ServiceConfig) {
+    cfg.app_data(JsonConfig::default().error_handler(json_error_handler))
+        .app_data(QueryConfig::default().error_handler(query_error_handler))
+        .app_data(PathConfig::default().error_handler(path_error_handler))
+        .default_service(web::to(not_found));
+}
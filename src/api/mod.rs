@@ -1,4 +1,10 @@
-mod error;
+pub mod api_key_api;
+pub mod audit_api;
+pub mod error;
+pub mod request_id;
 pub mod routes;
 mod tests;
 pub mod user_api;
+pub mod user_batch_api;
+pub mod user_export_api;
+pub mod user_import_api;
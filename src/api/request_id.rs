@@ -0,0 +1,142 @@
+use std::future::{ready, Ready};
+
+use crate::api::error::ApiError;
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    error::InternalError,
+    http::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE},
+    Error, FromRequest, HttpMessage, HttpRequest,
+};
+use futures_util::future::LocalBoxFuture;
+use std::rc::Rc;
+use uuid::Uuid;
+
+pub const X_REQUEST_ID: &str = "x-request-id";
+
+// Identifies a request across the logs, the audit records and the response sent to the client
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    // Ids sent by a proxy or the client are kept, unless they could be used to forge log lines
+    fn from_header(value: &HeaderValue) -> Option<Self> {
+        let value = value.to_str().ok()?;
+        let valid = !value.is_empty()
+            && value.len() <= 128
+            && value
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c));
+        valid.then(|| RequestId(value.to_string()))
+    }
+
+    fn generate() -> Self {
+        RequestId(Uuid::new_v4().to_string())
+    }
+}
+
+impl FromRequest for RequestId {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        // Handlers outside of RequestIdMiddleware (e.g. in unit tests) still get an id
+        let request_id = req.extensions().get::<RequestId>().cloned();
+        ready(Ok(request_id.unwrap_or_else(RequestId::generate)))
+    }
+}
+
+// RequestIdMiddleware stores the RequestId in the request extensions, and returns it in the x-request-id header
+pub struct RequestIdMiddleware;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestIdMiddleware
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RequestIdService<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestIdService {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct RequestIdService<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestIdService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+
+        Box::pin(async move {
+            let request_id = req
+                .headers()
+                .get(X_REQUEST_ID)
+                .and_then(RequestId::from_header)
+                .unwrap_or_else(RequestId::generate);
+            req.extensions_mut().insert(request_id.clone());
+
+            let header_value = HeaderValue::from_str(&request_id.0).ok();
+            match service.call(req).await {
+                Ok(res) => {
+                    // Problems returned by the handlers are rendered again, to include the request id
+                    let api_error = res.response().error().and_then(ApiError::from_error);
+                    let mut res = match api_error {
+                        Some(api_error) => {
+                            let mut response = api_error.problem_response(Some(&request_id.0));
+                            copy_headers(res.headers(), response.headers_mut());
+                            res.into_response(response).map_into_right_body()
+                        }
+                        None => res.map_into_left_body(),
+                    };
+                    if let Some(header_value) = header_value {
+                        res.headers_mut()
+                            .insert(HeaderName::from_static(X_REQUEST_ID), header_value);
+                    }
+                    Ok(res)
+                }
+                // Errors of the inner middlewares are rendered here, so they also get the request id
+                Err(err) => {
+                    let mut response = match ApiError::from_error(&err) {
+                        Some(api_error) => api_error.problem_response(Some(&request_id.0)),
+                        None => err.error_response(),
+                    };
+                    if let Some(header_value) = header_value {
+                        response
+                            .headers_mut()
+                            .insert(HeaderName::from_static(X_REQUEST_ID), header_value);
+                    }
+                    Err(InternalError::from_response(err, response).into())
+                }
+            }
+        })
+    }
+}
+
+// Keeps the headers set by the inner middlewares, e.g. Retry-After, but not the ones describing the old body
+fn copy_headers(from: &HeaderMap, to: &mut HeaderMap) {
+    for (name, value) in from {
+        if name != CONTENT_TYPE && name != CONTENT_LENGTH && !to.contains_key(name) {
+            to.insert(name.clone(), value.clone());
+        }
+    }
+}
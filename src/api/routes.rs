@@ -1,16 +1,54 @@
-use crate::api::user_api::{create_user, delete_user, get_all_users, get_user, update_user};
+use crate::api::api_key_api::{create_api_key, list_api_keys, revoke_api_key, rotate_api_key};
+use crate::api::audit_api::get_audit_records;
+use crate::api::user_api::{
+    create_user, delete_user, get_all_users, get_user, patch_user, search_users, update_user,
+};
+use crate::api::user_batch_api::batch_users;
+use crate::api::user_export_api::export_users;
+use crate::api::user_import_api::import_users;
 use crate::auth::auth_middleware::AuthMiddleware;
+use crate::auth::claims::Permission;
+use crate::rate_limit::middleware::RateLimiters;
+use actix_web::middleware::Condition;
 use actix_web::web::scope;
 use actix_web::Scope;
 
-pub fn routes(auth_middleware: AuthMiddleware) -> Scope {
+// Each admin handler declares its own permissions, with RequirePermissions, while the public handlers
+// only require users:read, when protect_read_endpoints is enabled.
+// The rate limiters are wrapped first, so they run after the authentication and can limit each principal, the
+// failed authentications are limited per client IP before it
+pub fn routes(
+    auth_middleware: AuthMiddleware,
+    protect_read_endpoints: bool,
+    rate_limiters: RateLimiters,
+) -> Scope {
+    let read_auth_middleware = auth_middleware.clone().require(&[Permission::UsersRead]);
     scope("/api")
         .service(
             scope("/admin")
+                .wrap(rate_limiters.group("admin"))
                 .wrap(auth_middleware)
+                .wrap(rate_limiters.auth_failures())
                 .service(create_user)
                 .service(update_user)
-                .service(delete_user),
+                .service(patch_user)
+                .service(delete_user)
+                .service(import_users)
+                .service(export_users)
+                .service(batch_users)
+                .service(create_api_key)
+                .service(list_api_keys)
+                .service(rotate_api_key)
+                .service(revoke_api_key)
+                .service(get_audit_records),
+        )
+        .service(
+            scope("")
+                .wrap(rate_limiters.group("public"))
+                .wrap(Condition::new(protect_read_endpoints, read_auth_middleware))
+                .wrap(rate_limiters.auth_failures())
+                .service(get_user)
+                .service(get_all_users)
+                .service(search_users),
         )
-        .service(scope("").service(get_user).service(get_all_users))
 }
@@ -1,42 +1,125 @@
 #[cfg(test)]
 mod tests {
+    use crate::api::api_key_api::{create_api_key, rotate_api_key};
+    use crate::api::audit_api::get_audit_records;
+    use crate::api::error::{error_handlers, Problem};
+    use crate::api::request_id::RequestIdMiddleware;
+    use crate::api::routes::routes;
     use crate::api::user_api::delete_user;
-    use crate::api::user_api::{create_user, get_all_users, get_user};
-    use crate::auth::api_key::ApiKeyData;
-    use crate::auth::auth0::Auth0Data;
+    use crate::api::user_api::{
+        create_user, get_all_users, get_user, patch_user, search_users, update_user,
+    };
+    use crate::api::user_batch_api::batch_users;
+    use crate::api::user_export_api::export_users;
+    use crate::api::user_import_api::{
+        import_users, MAX_IMPORT_BYTES, MAX_IMPORT_ROWS, MAX_RECORD_BYTES,
+    };
+    use crate::auth::api_key::{
+        generate_api_key, hash_api_key, parse_api_key, ApiKeyData, ApiKeyEntry,
+    };
+    use crate::auth::auth_middleware::AuthMiddleware;
+    use crate::auth::claims::AccessLevel;
+    use crate::auth::principal::{AuthMethod, Principal};
+    use crate::auth::rbac::RoleMap;
+    use crate::auth::verifier::build_verifier;
     use crate::configuration::config::Config;
-    use crate::database::repository::MockRepository;
+    use crate::database::error::RepositoryError;
+    use crate::database::repository::{
+        MockApiKeyRepository, MockAuditRepository, MockDatabase, MockRepository,
+    };
+    use crate::models::api_key_model::{ApiKey, CreatedApiKey};
     use crate::models::app::AppData;
-    use crate::models::user_model::{CreateUserResult, DeleteUserResult, User};
+    use crate::models::audit_model::{AuditAction, AuditOutcome, AuditRecord};
+    use crate::models::batch_model::{BatchReport, BatchStatus, UserWrite};
+    use crate::models::import_model::{ImportReport, ImportRowStatus};
+    use crate::models::user_model::{
+        CreateUserRequest, CreateUserResult, DeleteUserResult, UpdateUserResult, User, UserCursor,
+        UserId, UserPage, UserPatch, UserQuery, UserSearchResult, UserSort, UserSortField,
+    };
+    use crate::rate_limit::middleware::{RateLimitData, RateLimiters};
+    use crate::rate_limit::store::{InMemoryStore, RateLimit};
+    use actix_web::body::MessageBody;
+    use actix_web::dev::{Service, ServiceResponse};
+    use actix_web::http::StatusCode;
     use actix_web::web::Data;
-    use actix_web::{test, App};
+    use actix_web::{get, test, App, Error, HttpMessage, HttpResponse};
     use awc::http;
     use mockall::predicate;
     use mockall::predicate::*;
     use serde_json;
+    use std::collections::{HashMap, HashSet};
     use std::sync::Arc;
 
     const USER_ID: &str = "65f0bbf848c60e78920bfd4c";
+
+    fn user_id() -> UserId {
+        UserId::parse(USER_ID).unwrap()
+    }
+
+    // Errors returned by middlewares don't reach the test as a response, so we render them like the server does
+    async fn call_status<S, R, B>(app: &S, req: R) -> StatusCode
+    where
+        S: Service<R, Response = ServiceResponse<B>, Error = Error>,
+    {
+        match test::try_call_service(app, req).await {
+            Ok(resp) => resp.status(),
+            Err(err) => err.error_response().status(),
+        }
+    }
+
+    fn principal(permissions: &[&str]) -> Principal {
+        Principal {
+            subject: "auth0|test".to_string(),
+            issuer: Some("https://test.auth0.com/".to_string()),
+            permissions: permissions.iter().map(|p| p.to_string()).collect(),
+            auth_method: AuthMethod::Jwt,
+            key_id: None,
+        }
+    }
+
+    fn rate_limiters() -> RateLimiters {
+        RateLimiters::new(Default::default(), Arc::new(InMemoryStore::new()))
+    }
+
     fn get_app_data() -> Data<AppData> {
+        get_app_data_with(MockApiKeyRepository::new(), any_audit())
+    }
+
+    fn any_audit() -> MockAuditRepository {
+        let mut audit = MockAuditRepository::new();
+        audit.expect_record_audit().returning(|_| Ok(()));
+        audit
+    }
+
+    fn get_app_data_with(
+        api_keys: MockApiKeyRepository,
+        audit: MockAuditRepository,
+    ) -> Data<AppData> {
         let mut mock = MockRepository::new();
 
-        mock.expect_get_all_users().returning(|| {
-            Ok(vec![User {
-                id: None,
-                name: "test".to_string(),
-                title: "test".to_string(),
-                location: "test".to_string(),
-            }])
+        mock.expect_list_users().returning(|_| {
+            Ok(UserPage {
+                users: vec![User {
+                    id: None,
+                    name: "test".to_string(),
+                    title: "test".to_string(),
+                    location: "test".to_string(),
+                    version: 1,
+                }],
+                next: None,
+                total: None,
+            })
         });
 
         mock.expect_get_user()
-            .with(predicate::eq(USER_ID.to_string()))
+            .with(predicate::eq(user_id()))
             .returning(|_| {
                 Ok(Some(User {
                     id: None,
                     name: "test".to_string(),
                     title: "test".to_string(),
                     location: "test".to_string(),
+                    version: 1,
                 }))
             });
 
@@ -46,30 +129,29 @@ mod tests {
                 name: "test".to_string(),
                 title: "test".to_string(),
                 location: "test".to_string(),
+                version: 0,
             }))
-            .returning(|_| {
-                Ok(CreateUserResult {
-                    id: "test".to_string(),
-                })
-            });
+            .returning(|_| Ok(CreateUserResult { id: user_id() }));
 
         mock.expect_delete_user()
-            .with(predicate::eq(USER_ID.to_string()))
-            .returning(|_| Ok(DeleteUserResult { deleted_count: 1 }));
+            .with(predicate::eq(user_id()), predicate::eq(None))
+            .returning(|_, _| Ok(DeleteUserResult { deleted_count: 1 }));
 
         let app_data = AppData {
             db: Arc::new(mock),
+            api_keys: Arc::new(api_keys),
+            audit: Arc::new(audit),
             config: Config {
-                auth0_data: Auth0Data {
-                    audience: "".to_string(),
-                    domain: "".to_string(),
-                },
+                issuers: vec![],
+                protect_read_endpoints: false,
+                rbac: Default::default(),
+                rate_limit: Default::default(),
                 env: "test".to_string(),
-                api_key_data: ApiKeyData {
-                    api_key: "".to_string(),
-                    enable_api_key: false,
-                },
+                api_key_data: Default::default(),
+                storage: Default::default(),
                 mongo_uri: "".to_string(),
+                postgres: Default::default(),
+                sqlite: Default::default(),
             },
         };
         Data::new(app_data)
@@ -95,6 +177,97 @@ mod tests {
         assert_eq!(users.get(0).unwrap().name, "test")
     }
 
+    #[actix_web::test]
+    async fn test_list_users_pagination() {
+        let last = User {
+            id: Some(user_id()),
+            name: "Anne".to_string(),
+            title: "test".to_string(),
+            location: "Lisbon".to_string(),
+            version: 1,
+        };
+        let sort = UserSort {
+            field: UserSortField::Name,
+            descending: true,
+        };
+        let next = UserCursor::after(&last, sort).unwrap();
+        let mut mock = MockRepository::new();
+        let page_next = next.clone();
+        mock.expect_list_users()
+            .with(predicate::function(move |query: &UserQuery| {
+                query.location.as_deref() == Some("Lisbon")
+                    && query.name_prefix.as_deref() == Some("An")
+                    && query.sort() == sort
+                    && query.limit() == 1
+                    && query.after.is_none()
+            }))
+            .returning(move |_| {
+                Ok(UserPage {
+                    users: vec![last.clone()],
+                    next: Some(page_next.clone()),
+                    total: Some(3),
+                })
+            });
+        mock.expect_list_users()
+            .with(predicate::function(move |query: &UserQuery| {
+                query.after.as_ref() == Some(&next)
+            }))
+            .returning(|_| {
+                Ok(UserPage {
+                    users: vec![],
+                    next: None,
+                    total: None,
+                })
+            });
+        let app_data = AppData {
+            db: Arc::new(mock),
+            ..get_app_data().as_ref().clone()
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(app_data))
+                .configure(error_handlers)
+                .service(get_all_users),
+        )
+        .await;
+
+        let req = test::TestRequest::with_uri(
+            "/users?location=Lisbon&name_prefix=An&sort=-name&limit=1&count=true",
+        )
+        .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers().get("x-total-count").unwrap(), "3");
+        let link = resp.headers().get("link").unwrap().to_str().unwrap();
+        let next_uri = link
+            .strip_prefix('<')
+            .and_then(|link| link.strip_suffix(">; rel=\"next\""))
+            .unwrap()
+            .to_string();
+        assert!(next_uri.starts_with("/users?"));
+        // The ids are plain strings, whatever the backend
+        let users: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(users[0]["id"], USER_ID);
+
+        // The filters and the order are kept in the link to the next page
+        let req = test::TestRequest::with_uri(&next_uri).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        assert!(resp.headers().get("link").is_none());
+
+        let req = test::TestRequest::with_uri("/users?sort=password").to_request();
+        assert_eq!(call_status(&app, req).await, 400);
+
+        let req = test::TestRequest::with_uri("/users?next=not-a-cursor").to_request();
+        assert_eq!(call_status(&app, req).await, 400);
+
+        // The cursor was created for another order
+        let cursor = next_uri.split("next=").nth(1).unwrap();
+        let req =
+            test::TestRequest::with_uri(&format!("/users?sort=name&next={}", cursor)).to_request();
+        assert_eq!(call_status(&app, req).await, 400);
+    }
+
     #[actix_web::test]
     async fn test_get_user() {
         let mut app = test::init_service(
@@ -116,9 +289,8 @@ mod tests {
 
     #[actix_web::test]
     async fn test_create_user() {
-        let user_to_create = User {
+        let user_to_create = CreateUserRequest {
             name: "test".to_string(),
-            id: None,
             location: "test".to_string(),
             title: "test".to_string(),
         };
@@ -133,13 +305,17 @@ mod tests {
             .method(http::Method::POST)
             .set_json(user_to_create)
             .to_request();
+        req.extensions_mut().insert(Principal::api_key(
+            "admin",
+            HashSet::from([AccessLevel::Write.to_string()]),
+        ));
         let resp = test::call_service(&mut app, req).await;
 
         assert_eq!(resp.status(), 200);
         //
         let body = test::read_body(resp).await;
         let create_user_result = serde_json::from_slice::<CreateUserResult>(body.as_ref()).unwrap();
-        assert_eq!(create_user_result.id, "test")
+        assert_eq!(create_user_result.id, user_id())
     }
 
     #[actix_web::test]
@@ -154,6 +330,10 @@ mod tests {
         let req = test::TestRequest::with_uri(format!("/user/{}", USER_ID).as_str())
             .method(http::Method::DELETE)
             .to_request();
+        req.extensions_mut().insert(Principal::api_key(
+            "admin",
+            HashSet::from([AccessLevel::Write.to_string()]),
+        ));
         let resp = test::call_service(&mut app, req).await;
 
         assert_eq!(resp.status(), 200);
@@ -162,4 +342,1470 @@ mod tests {
         let delete_user_result = serde_json::from_slice::<String>(body.as_ref()).unwrap();
         assert_eq!(delete_user_result, "User successfully deleted!")
     }
+
+    #[actix_web::test]
+    async fn test_repository_errors() {
+        let mut mock = MockRepository::new();
+        mock.expect_get_user()
+            .with(predicate::eq(user_id()))
+            .returning(|_| Err(RepositoryError::Timeout("find".to_string())));
+        mock.expect_get_user().returning(|_| Ok(None));
+        mock.expect_delete_user().returning(|id, _| {
+            Err(RepositoryError::NotFound(format!(
+                "No user found with id {}",
+                id
+            )))
+        });
+        let app_data = AppData {
+            db: Arc::new(mock),
+            ..get_app_data().as_ref().clone()
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(app_data))
+                .service(get_user)
+                .service(delete_user),
+        )
+        .await;
+
+        // Malformed ids are rejected before reaching the repository
+        let req = test::TestRequest::with_uri("/user/abc").to_request();
+        assert_eq!(call_status(&app, req).await, 400);
+
+        let req = test::TestRequest::with_uri(format!("/user/{}", USER_ID).as_str()).to_request();
+        assert_eq!(call_status(&app, req).await, 503);
+
+        // The user can't be read to check If-Match, which isn't a failed precondition
+        let req = test::TestRequest::with_uri(format!("/user/{}", USER_ID).as_str())
+            .method(http::Method::DELETE)
+            .insert_header(("if-match", "\"1\""))
+            .to_request();
+        req.extensions_mut().insert(principal(&["users:delete"]));
+        assert_eq!(call_status(&app, req).await, 503);
+
+        let req = test::TestRequest::with_uri("/user/65f0bbf848c60e78920bfd4d")
+            .method(http::Method::DELETE)
+            .to_request();
+        req.extensions_mut().insert(principal(&["users:delete"]));
+        assert_eq!(call_status(&app, req).await, 404);
+    }
+
+    #[actix_web::test]
+    async fn test_delete_user_permissions() {
+        let app = test::init_service(
+            App::new()
+                .app_data(get_app_data().clone())
+                .service(delete_user),
+        )
+        .await;
+
+        // users:create doesn't grant users:delete
+        let req = test::TestRequest::with_uri(format!("/user/{}", USER_ID).as_str())
+            .method(http::Method::DELETE)
+            .to_request();
+        req.extensions_mut().insert(principal(&["users:create"]));
+        assert_eq!(call_status(&app, req).await, 403);
+
+        let req = test::TestRequest::with_uri(format!("/user/{}", USER_ID).as_str())
+            .method(http::Method::DELETE)
+            .to_request();
+        req.extensions_mut().insert(principal(&["users:delete"]));
+        assert_eq!(call_status(&app, req).await, 200);
+
+        // Without AuthMiddleware, there is no principal to check
+        let req = test::TestRequest::with_uri(format!("/user/{}", USER_ID).as_str())
+            .method(http::Method::DELETE)
+            .to_request();
+        assert_eq!(call_status(&app, req).await, 401);
+    }
+
+    #[actix_web::test]
+    async fn test_protected_read_endpoints() {
+        let auth_middleware = AuthMiddleware::new(
+            ApiKeyData {
+                enable_api_key: true,
+                pepper: "pepper".to_string(),
+                keys: vec![ApiKeyEntry {
+                    name: "reader".to_string(),
+                    salt: "salt".to_string(),
+                    hash: hash_api_key("pepper", "salt", "test"),
+                    permissions: vec!["users:read".to_string()],
+                    expires_at: None,
+                    enabled: true,
+                }],
+            },
+            build_verifier(&[]).unwrap(),
+            Arc::new(RoleMap::default()),
+        );
+        let app = test::init_service(App::new().app_data(get_app_data().clone()).service(routes(
+            auth_middleware.clone(),
+            true,
+            rate_limiters(),
+        )))
+        .await;
+
+        let req = test::TestRequest::with_uri("/api/users").to_request();
+        assert_eq!(call_status(&app, req).await, 401);
+
+        let req = test::TestRequest::with_uri("/api/users")
+            .insert_header(("x-api-key", "test"))
+            .to_request();
+        assert_eq!(call_status(&app, req).await, 200);
+
+        let app = test::init_service(App::new().app_data(get_app_data().clone()).service(routes(
+            auth_middleware,
+            false,
+            rate_limiters(),
+        )))
+        .await;
+        let req = test::TestRequest::with_uri("/api/users").to_request();
+        assert_eq!(call_status(&app, req).await, 200);
+    }
+
+    #[actix_web::test]
+    async fn test_failed_authentications_are_limited() {
+        let auth_middleware = AuthMiddleware::new(
+            ApiKeyData {
+                enable_api_key: true,
+                pepper: "pepper".to_string(),
+                keys: vec![ApiKeyEntry {
+                    name: "admin".to_string(),
+                    salt: "salt".to_string(),
+                    hash: hash_api_key("pepper", "salt", "test"),
+                    permissions: vec!["Write".to_string()],
+                    expires_at: None,
+                    enabled: true,
+                }],
+            },
+            build_verifier(&[]).unwrap(),
+            Arc::new(RoleMap::default()),
+        );
+        let rate_limiters = RateLimiters::new(
+            RateLimitData {
+                groups: HashMap::from([(
+                    "auth_failures".to_string(),
+                    RateLimit {
+                        requests_per_minute: 1,
+                        burst: 2,
+                    },
+                )]),
+                trust_forwarded_headers: false,
+            },
+            Arc::new(InMemoryStore::new()),
+        );
+        let app = test::init_service(App::new().app_data(get_app_data().clone()).service(routes(
+            auth_middleware,
+            true,
+            rate_limiters,
+        )))
+        .await;
+        let request = |uri: &str, api_key: &str| {
+            test::TestRequest::with_uri(uri)
+                .peer_addr("10.0.0.1:1234".parse().unwrap())
+                .insert_header(("x-api-key", api_key))
+                .to_request()
+        };
+
+        // The valid key doesn't take a token
+        for _ in 0..3 {
+            let req = request("/api/users", "test");
+            assert_eq!(call_status(&app, req).await, 200);
+        }
+        // The guesses of both route groups share the bucket of the IP
+        let req = request("/api/admin/api-keys", "guess");
+        assert_eq!(call_status(&app, req).await, 401);
+        let req = request("/api/users", "guess");
+        assert_eq!(call_status(&app, req).await, 401);
+        let resp = test::call_service(&app, request("/api/admin/api-keys", "guess")).await;
+        assert_eq!(resp.status(), 429);
+        assert!(resp.headers().get("retry-after").is_some());
+        // Even the valid key, so a right guess isn't told apart
+        let req = request("/api/admin/api-keys", "test");
+        assert_eq!(call_status(&app, req).await, 429);
+        let req = test::TestRequest::with_uri("/api/admin/api-keys")
+            .peer_addr("10.0.0.2:1234".parse().unwrap())
+            .insert_header(("x-api-key", "guess"))
+            .to_request();
+        assert_eq!(call_status(&app, req).await, 401);
+    }
+
+    #[get("/me")]
+    async fn me(principal: Principal) -> HttpResponse {
+        HttpResponse::Ok().body(principal.subject)
+    }
+
+    #[actix_web::test]
+    async fn test_principal_extractor() {
+        let app = test::init_service(App::new().service(me)).await;
+
+        let req = test::TestRequest::with_uri("/me").to_request();
+        req.extensions_mut().insert(principal(&[]));
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(test::read_body(resp).await, "auth0|test");
+
+        let req = test::TestRequest::with_uri("/me").to_request();
+        assert_eq!(call_status(&app, req).await, 401);
+    }
+
+    fn stored_api_key(key_id: &str, api_key: &str, enabled: bool) -> ApiKey {
+        ApiKey {
+            key_id: key_id.to_string(),
+            entry: ApiKeyEntry {
+                name: "ci".to_string(),
+                salt: "salt".to_string(),
+                hash: hash_api_key("", "salt", api_key),
+                permissions: vec!["api_keys:manage".to_string()],
+                expires_at: None,
+                enabled,
+            },
+            created_at: chrono::Utc::now(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_create_api_key() {
+        let mut api_keys = MockApiKeyRepository::new();
+        api_keys
+            .expect_create_api_key()
+            .withf(|api_key| api_key.entry.name == "ci")
+            .returning(|_| {
+                Err(RepositoryError::Conflict(
+                    "E11000 duplicate key".to_string(),
+                ))
+            });
+        api_keys
+            .expect_create_api_key()
+            .times(1)
+            .withf(|api_key| api_key.entry.name == "deploy" && api_key.entry.enabled)
+            .returning(|_| Ok(()));
+        let app = test::init_service(
+            App::new()
+                .app_data(get_app_data_with(api_keys, any_audit()))
+                .service(create_api_key),
+        )
+        .await;
+
+        // An active key is already named ci, the blanks around the name don't make it another one
+        let req = test::TestRequest::with_uri("/api-keys")
+            .method(http::Method::POST)
+            .set_json(serde_json::json!({"name": " ci "}))
+            .to_request();
+        req.extensions_mut().insert(principal(&["api_keys:manage"]));
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 409);
+        assert_eq!(
+            read_problem(resp).await.detail,
+            "An API key named ci already exists"
+        );
+
+        let req = test::TestRequest::with_uri("/api-keys")
+            .method(http::Method::POST)
+            .set_json(serde_json::json!({"name": "deploy", "permissions": ["users:read"]}))
+            .to_request();
+        req.extensions_mut()
+            .insert(principal(&["api_keys:manage", "Read"]));
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 201);
+
+        // Only the plaintext key is returned, never its hash
+        let body = test::read_body(resp).await;
+        assert!(!String::from_utf8_lossy(&body).contains("hash"));
+        let created = serde_json::from_slice::<CreatedApiKey>(body.as_ref()).unwrap();
+        assert_eq!(
+            parse_api_key(&created.api_key),
+            Some(created.info.key_id.as_str())
+        );
+        assert_eq!(created.info.permissions, vec!["users:read".to_string()]);
+
+        let req = test::TestRequest::with_uri("/api-keys")
+            .method(http::Method::POST)
+            .set_json(serde_json::json!({"name": "ci"}))
+            .to_request();
+        req.extensions_mut().insert(principal(&["users:create"]));
+        assert_eq!(call_status(&app, req).await, 403);
+
+        let req = test::TestRequest::with_uri("/api-keys")
+            .method(http::Method::POST)
+            .set_json(serde_json::json!({"name": "  "}))
+            .to_request();
+        req.extensions_mut().insert(principal(&["api_keys:manage"]));
+        assert_eq!(call_status(&app, req).await, 422);
+
+        // The new key can't have permissions the caller doesn't have
+        for permissions in [vec!["Write"], vec!["users:read", "users:delete"]] {
+            let req = test::TestRequest::with_uri("/api-keys")
+                .method(http::Method::POST)
+                .set_json(serde_json::json!({"name": "admin", "permissions": permissions}))
+                .to_request();
+            req.extensions_mut()
+                .insert(principal(&["api_keys:manage", "users:read"]));
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), 403);
+            assert_eq!(read_problem(resp).await.code, "insufficient_permissions");
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_rotate_api_key() {
+        let old_key = generate_api_key("0123456789abcdef");
+        let mut api_keys = MockApiKeyRepository::new();
+        let stored = stored_api_key("0123456789abcdef", &old_key, true);
+        let mut writer = stored_api_key("fedcba9876543210", "", true);
+        writer.entry.permissions = vec!["Write".to_string()];
+        api_keys.expect_get_api_key().returning(move |key_id| {
+            Ok(Some(match key_id {
+                "fedcba9876543210" => writer.clone(),
+                _ => stored.clone(),
+            }))
+        });
+        api_keys
+            .expect_update_api_key()
+            .times(1)
+            .withf(|api_key| api_key.entry.salt != "salt")
+            .returning(|_| Ok(true));
+        let app = test::init_service(
+            App::new()
+                .app_data(get_app_data_with(api_keys, any_audit()))
+                .service(rotate_api_key),
+        )
+        .await;
+
+        let req = test::TestRequest::with_uri("/api-keys/0123456789abcdef/rotate")
+            .method(http::Method::POST)
+            .to_request();
+        req.extensions_mut().insert(principal(&["api_keys:manage"]));
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        let rotated = test::read_body_json::<CreatedApiKey, _>(resp).await;
+        assert_eq!(rotated.info.key_id, "0123456789abcdef");
+        assert_ne!(rotated.api_key, old_key);
+
+        // The new secret of a key holding Write would give it to a caller who only manages the keys
+        let req = test::TestRequest::with_uri("/api-keys/fedcba9876543210/rotate")
+            .method(http::Method::POST)
+            .to_request();
+        req.extensions_mut().insert(principal(&["api_keys:manage"]));
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 403);
+        assert_eq!(read_problem(resp).await.code, "insufficient_permissions");
+    }
+
+    #[actix_web::test]
+    async fn test_stored_api_key_authentication() {
+        let active_key = generate_api_key("active");
+        let revoked_key = generate_api_key("revoked");
+        let mut api_keys = MockApiKeyRepository::new();
+        let active = stored_api_key("active", &active_key, true);
+        let revoked = stored_api_key("revoked", &revoked_key, false);
+        api_keys.expect_get_api_key().returning(move |key_id| {
+            Ok(match key_id {
+                "active" => Some(active.clone()),
+                "revoked" => Some(revoked.clone()),
+                _ => None,
+            })
+        });
+        api_keys.expect_list_api_keys().returning(|| Ok(vec![]));
+        let auth_middleware = AuthMiddleware::new(
+            ApiKeyData {
+                enable_api_key: true,
+                pepper: "".to_string(),
+                keys: vec![],
+            },
+            build_verifier(&[]).unwrap(),
+            Arc::new(RoleMap::default()),
+        );
+        let app = test::init_service(
+            App::new()
+                .app_data(get_app_data_with(api_keys, any_audit()))
+                .service(routes(auth_middleware, false, rate_limiters())),
+        )
+        .await;
+
+        let req = test::TestRequest::with_uri("/api/admin/api-keys")
+            .insert_header(("x-api-key", active_key.as_str()))
+            .to_request();
+        assert_eq!(call_status(&app, req).await, 200);
+
+        // Revoked keys are rejected without restarting the server
+        let req = test::TestRequest::with_uri("/api/admin/api-keys")
+            .insert_header(("x-api-key", revoked_key.as_str()))
+            .to_request();
+        assert_eq!(call_status(&app, req).await, 401);
+
+        let req = test::TestRequest::with_uri("/api/admin/api-keys")
+            .insert_header(("x-api-key", generate_api_key("unknown").as_str()))
+            .to_request();
+        assert_eq!(call_status(&app, req).await, 401);
+
+        // Right key id, wrong secret
+        let req = test::TestRequest::with_uri("/api/admin/api-keys")
+            .insert_header(("x-api-key", generate_api_key("active").as_str()))
+            .to_request();
+        assert_eq!(call_status(&app, req).await, 401);
+    }
+
+    #[actix_web::test]
+    async fn test_delete_user_is_audited() {
+        let mut audit = MockAuditRepository::new();
+        audit
+            .expect_record_audit()
+            .withf(|record| {
+                record.action == AuditAction::UserDelete
+                    && record.actor.subject == "auth0|test"
+                    && record.target_id.as_deref() == Some(USER_ID)
+                    && record
+                        .before
+                        .as_ref()
+                        .is_some_and(|user| user["name"] == "test")
+                    && record.request_id == "request-1"
+                    && record.outcome == AuditOutcome::Success
+            })
+            .times(1)
+            .returning(|_| Ok(()));
+        let app = test::init_service(
+            App::new()
+                .app_data(get_app_data_with(MockApiKeyRepository::new(), audit))
+                .wrap(RequestIdMiddleware)
+                .service(delete_user),
+        )
+        .await;
+
+        let req = test::TestRequest::with_uri(format!("/user/{}", USER_ID).as_str())
+            .method(http::Method::DELETE)
+            .insert_header(("x-request-id", "request-1"))
+            .to_request();
+        req.extensions_mut().insert(principal(&["users:delete"]));
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers().get("x-request-id").unwrap(), "request-1");
+    }
+
+    #[actix_web::test]
+    async fn test_get_audit_records() {
+        let mut audit = MockAuditRepository::new();
+        audit
+            .expect_find_audit_records()
+            .withf(|query| {
+                query.actor.as_deref() == Some("auth0|test")
+                    && query.target.is_none()
+                    && query.from.is_some()
+                    && query.limit() == 10
+            })
+            .returning(|_| {
+                Ok(vec![AuditRecord::new(
+                    &principal(&[]),
+                    AuditAction::UserCreate,
+                    "request-1",
+                )
+                .target(USER_ID)])
+            });
+        let app = test::init_service(
+            App::new()
+                .app_data(get_app_data_with(MockApiKeyRepository::new(), audit))
+                .service(get_audit_records),
+        )
+        .await;
+
+        let req = test::TestRequest::with_uri(
+            "/audit?actor=auth0%7Ctest&from=2024-03-01T00:00:00Z&limit=10",
+        )
+        .to_request();
+        req.extensions_mut().insert(principal(&["audit:read"]));
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        let records = test::read_body_json::<Vec<AuditRecord>, _>(resp).await;
+        assert_eq!(records.len(), 1);
+        assert_eq!(records.first().unwrap().action, AuditAction::UserCreate);
+
+        let req =
+            test::TestRequest::with_uri("/audit?from=2024-03-02T00:00:00Z&to=2024-03-01T00:00:00Z")
+                .to_request();
+        req.extensions_mut().insert(principal(&["audit:read"]));
+        assert_eq!(call_status(&app, req).await, 400);
+
+        let req = test::TestRequest::with_uri("/audit").to_request();
+        req.extensions_mut().insert(principal(&["users:read"]));
+        assert_eq!(call_status(&app, req).await, 403);
+    }
+
+    #[actix_web::test]
+    async fn test_request_id() {
+        let app = test::init_service(
+            App::new()
+                .app_data(get_app_data())
+                .wrap(RequestIdMiddleware)
+                .service(delete_user)
+                .service(get_all_users),
+        )
+        .await;
+
+        let req = test::TestRequest::with_uri("/users").to_request();
+        let resp = test::call_service(&app, req).await;
+        let generated = resp.headers().get("x-request-id").unwrap();
+        assert!(uuid::Uuid::parse_str(generated.to_str().unwrap()).is_ok());
+
+        // Ids that could inject content into the logs are replaced
+        let req = test::TestRequest::with_uri("/users")
+            .insert_header(("x-request-id", "bad id \" injected=true"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let replaced = resp.headers().get("x-request-id").unwrap();
+        assert!(uuid::Uuid::parse_str(replaced.to_str().unwrap()).is_ok());
+
+        // Errors of the middlewares also carry the id
+        let req = test::TestRequest::with_uri(format!("/user/{}", USER_ID).as_str())
+            .method(http::Method::DELETE)
+            .insert_header(("x-request-id", "request-2"))
+            .to_request();
+        let resp = test::try_call_service(&app, req)
+            .await
+            .unwrap_err()
+            .error_response();
+        assert_eq!(resp.status(), 401);
+        assert_eq!(resp.headers().get("x-request-id").unwrap(), "request-2");
+    }
+
+    async fn read_problem<B: MessageBody>(resp: ServiceResponse<B>) -> Problem {
+        assert_eq!(
+            resp.headers().get("content-type").unwrap(),
+            "application/problem+json"
+        );
+        let body = test::read_body(resp).await;
+        serde_json::from_slice::<Problem>(body.as_ref()).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn test_problem_responses() {
+        let app = test::init_service(
+            App::new()
+                .app_data(get_app_data())
+                .configure(error_handlers)
+                .wrap(RequestIdMiddleware)
+                .service(get_user)
+                .service(create_user)
+                .service(get_audit_records),
+        )
+        .await;
+
+        let req = test::TestRequest::with_uri("/user/not-an-id")
+            .insert_header(("x-request-id", "request-1"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+        let problem = read_problem(resp).await;
+        assert_eq!(problem.code, "invalid_id");
+        assert_eq!(problem.status, 400);
+        assert_eq!(problem.request_id.as_deref(), Some("request-1"));
+
+        let req = test::TestRequest::with_uri("/unknown").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 404);
+        assert_eq!(read_problem(resp).await.code, "not_found");
+
+        // A body that doesn't match the model fails its validation
+        let req = test::TestRequest::with_uri("/user")
+            .method(http::Method::POST)
+            .set_json(serde_json::json!({"name": "test"}))
+            .to_request();
+        req.extensions_mut().insert(principal(&["users:create"]));
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 422);
+        assert_eq!(read_problem(resp).await.code, "validation_failed");
+
+        let req = test::TestRequest::with_uri("/audit?limit=many").to_request();
+        req.extensions_mut().insert(principal(&["audit:read"]));
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+        assert_eq!(read_problem(resp).await.code, "invalid_query");
+
+        let req = test::TestRequest::with_uri("/audit").to_request();
+        req.extensions_mut().insert(principal(&["users:read"]));
+        let resp = test::try_call_service(&app, req)
+            .await
+            .unwrap_err()
+            .error_response();
+        assert_eq!(resp.status(), 403);
+        let body = actix_web::body::to_bytes(resp.into_body()).await.unwrap();
+        let problem = serde_json::from_slice::<Problem>(body.as_ref()).unwrap();
+        assert_eq!(problem.code, "insufficient_permissions");
+        assert!(problem.request_id.is_some());
+    }
+
+    #[actix_web::test]
+    async fn test_user_validation() {
+        let app = test::init_service(
+            App::new()
+                .app_data(get_app_data())
+                .configure(error_handlers)
+                .service(create_user),
+        )
+        .await;
+        let post_user = |body: serde_json::Value| {
+            let req = test::TestRequest::with_uri("/user")
+                .method(http::Method::POST)
+                .set_json(body)
+                .to_request();
+            req.extensions_mut().insert(principal(&["users:create"]));
+            req
+        };
+
+        // Values are trimmed before being validated and stored
+        let req =
+            post_user(serde_json::json!({"name": "  test ", "location": "test", "title": "test"}));
+        assert_eq!(call_status(&app, req).await, 200);
+
+        let req = post_user(serde_json::json!({
+            "name": "   ",
+            "location": "test",
+            "title": "t".repeat(101),
+        }));
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 422);
+        let problem = read_problem(resp).await;
+        assert_eq!(problem.code, "validation_failed");
+        let fields: Vec<(&str, &str)> = problem
+            .errors
+            .iter()
+            .map(|error| (error.field.as_str(), error.code.as_str()))
+            .collect();
+        assert_eq!(fields, vec![("name", "length"), ("title", "length")]);
+
+        let req =
+            post_user(serde_json::json!({"name": "<script>", "location": "test", "title": "test"}));
+        let problem = read_problem(test::call_service(&app, req).await).await;
+        assert_eq!(problem.errors[0].field, "name");
+        assert_eq!(problem.errors[0].code, "invalid_characters");
+
+        // The id is generated by the repository
+        let req = post_user(serde_json::json!({
+            "_id": USER_ID,
+            "name": "test",
+            "location": "test",
+            "title": "test",
+        }));
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 422);
+        assert_eq!(read_problem(resp).await.errors[0].field, "_id");
+
+        let req = post_user(serde_json::json!({"name": "test", "location": "test"}));
+        let problem = read_problem(test::call_service(&app, req).await).await;
+        assert_eq!(problem.errors[0].field, "title");
+        assert_eq!(problem.errors[0].code, "required");
+
+        let req = test::TestRequest::with_uri("/user")
+            .method(http::Method::POST)
+            .insert_header(("content-type", "application/json"))
+            .set_payload("{\"name\": ")
+            .to_request();
+        req.extensions_mut().insert(principal(&["users:create"]));
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+        assert_eq!(read_problem(resp).await.code, "invalid_body");
+    }
+
+    #[actix_web::test]
+    async fn test_patch_user() {
+        let mut mock = MockRepository::new();
+        mock.expect_get_user().returning(|_| {
+            Ok(Some(User {
+                id: None,
+                name: "test".to_string(),
+                title: "test".to_string(),
+                location: "test".to_string(),
+                version: 1,
+            }))
+        });
+        // Only the changed field is written, by both patch formats, with or without a charset
+        mock.expect_patch_user()
+            .with(
+                predicate::eq(user_id()),
+                predicate::eq(UserPatch {
+                    title: Some("CTO".to_string()),
+                    ..Default::default()
+                }),
+                predicate::eq(None),
+            )
+            .times(3)
+            .returning(|_, patch, _| {
+                Ok(User {
+                    id: None,
+                    name: "test".to_string(),
+                    title: patch.title.unwrap(),
+                    location: "test".to_string(),
+                    version: 1,
+                })
+            });
+        let app_data = AppData {
+            db: Arc::new(mock),
+            ..get_app_data().as_ref().clone()
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(app_data))
+                .configure(error_handlers)
+                .service(patch_user),
+        )
+        .await;
+        let patch_request = |content_type: &str, body: serde_json::Value| {
+            let req = test::TestRequest::with_uri(format!("/user/{}", USER_ID).as_str())
+                .method(http::Method::PATCH)
+                .insert_header(("content-type", content_type))
+                .set_payload(body.to_string())
+                .to_request();
+            req.extensions_mut().insert(principal(&["users:update"]));
+            req
+        };
+
+        let req = patch_request(
+            "application/merge-patch+json",
+            serde_json::json!({"title": "CTO", "name": "test"}),
+        );
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        let body = test::read_body(resp).await;
+        assert_eq!(serde_json::from_slice::<User>(&body).unwrap().title, "CTO");
+
+        let req = patch_request(
+            "application/merge-patch+json; charset=utf-8",
+            serde_json::json!({"title": "CTO"}),
+        );
+        assert_eq!(call_status(&app, req).await, 200);
+
+        let req = patch_request(
+            "application/json-patch+json",
+            serde_json::json!([
+                {"op": "test", "path": "/title", "value": "test"},
+                {"op": "replace", "path": "/title", "value": " CTO "},
+            ]),
+        );
+        assert_eq!(call_status(&app, req).await, 200);
+
+        // Nothing to write
+        let req = patch_request(
+            "application/merge-patch+json",
+            serde_json::json!({"title": "test"}),
+        );
+        assert_eq!(call_status(&app, req).await, 200);
+
+        let req = patch_request(
+            "application/json-patch+json",
+            serde_json::json!([{"op": "test", "path": "/title", "value": "CEO"}]),
+        );
+        assert_eq!(call_status(&app, req).await, 409);
+
+        // Removing a required field, or adding an unknown one, fails the validation
+        let req = patch_request(
+            "application/merge-patch+json",
+            serde_json::json!({"name": null}),
+        );
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 422);
+        assert_eq!(read_problem(resp).await.errors[0].field, "name");
+
+        let req = patch_request(
+            "application/json-patch+json",
+            serde_json::json!([{"op": "add", "path": "/_id", "value": USER_ID}]),
+        );
+        assert_eq!(call_status(&app, req).await, 422);
+
+        let req = patch_request("application/json", serde_json::json!({"title": "CTO"}));
+        assert_eq!(call_status(&app, req).await, 415);
+    }
+
+    #[actix_web::test]
+    async fn test_conditional_requests() {
+        let mut mock = MockRepository::new();
+        mock.expect_get_user().returning(|_| {
+            Ok(Some(User {
+                id: Some(user_id()),
+                name: "test".to_string(),
+                title: "test".to_string(),
+                location: "test".to_string(),
+                version: 2,
+            }))
+        });
+        // The version of the If-Match reaches the repository, which checks it again with the write
+        mock.expect_update_user()
+            .with(
+                predicate::eq(user_id()),
+                predicate::always(),
+                predicate::eq(Some(2)),
+            )
+            .times(1)
+            .returning(|_, _, _| {
+                Ok(UpdateUserResult {
+                    matched_count: 1,
+                    modified_count: 1,
+                    upserted_id: "".to_string(),
+                })
+            });
+        mock.expect_delete_user().never();
+        let app_data = AppData {
+            db: Arc::new(mock),
+            ..get_app_data().as_ref().clone()
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(app_data))
+                .service(get_user)
+                .service(update_user)
+                .service(delete_user),
+        )
+        .await;
+        let uri = format!("/user/{}", USER_ID);
+
+        let resp = test::call_service(&app, test::TestRequest::with_uri(&uri).to_request()).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers().get("etag").unwrap(), "\"2\"");
+        let req = test::TestRequest::with_uri(&uri)
+            .insert_header(("if-none-match", "\"2\""))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 304);
+        assert!(test::read_body(resp).await.is_empty());
+        let req = test::TestRequest::with_uri(&uri)
+            .insert_header(("if-none-match", "\"1\""))
+            .to_request();
+        assert_eq!(call_status(&app, req).await, 200);
+
+        let put_request = |if_match: &str| {
+            let req = test::TestRequest::with_uri(&uri)
+                .method(http::Method::PUT)
+                .insert_header(("if-match", if_match))
+                .set_json(serde_json::json!({"name": "test", "location": "test", "title": "CTO"}))
+                .to_request();
+            req.extensions_mut().insert(principal(&["users:update"]));
+            req
+        };
+        let resp = test::call_service(&app, put_request("\"1\"")).await;
+        assert_eq!(resp.status(), 412);
+        assert_eq!(read_problem(resp).await.code, "precondition_failed");
+        let resp = test::call_service(&app, put_request("\"1\", \"2\"")).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers().get("etag").unwrap(), "\"2\"");
+
+        let req = test::TestRequest::with_uri(&uri)
+            .method(http::Method::DELETE)
+            .insert_header(("if-match", "\"3\""))
+            .to_request();
+        req.extensions_mut().insert(principal(&["users:delete"]));
+        assert_eq!(call_status(&app, req).await, 412);
+    }
+
+    #[actix_web::test]
+    async fn test_search_users() {
+        // MockDatabase searches its user like the repositories without a text index
+        let db = MockDatabase {
+            test_user: User {
+                id: None,
+                name: "Anne Smith".to_string(),
+                title: "Software Engineer".to_string(),
+                location: "Lisbon".to_string(),
+                version: 1,
+            },
+            should_error: false,
+            create_user_result: CreateUserResult { id: user_id() },
+            update_user_result: UpdateUserResult {
+                matched_count: 0,
+                modified_count: 0,
+                upserted_id: "".to_string(),
+            },
+            delete_user_result: DeleteUserResult { deleted_count: 0 },
+        };
+        let app_data = AppData {
+            db: Arc::new(db),
+            ..get_app_data().as_ref().clone()
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(app_data))
+                .configure(error_handlers)
+                .service(search_users),
+        )
+        .await;
+
+        let req = test::TestRequest::with_uri("/users/search?q=anne%20eng").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        let body = test::read_body(resp).await;
+        let results = serde_json::from_slice::<Vec<UserSearchResult>>(&body).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].user.name, "Anne Smith");
+        assert!(results[0].score > 0.0);
+
+        let req = test::TestRequest::with_uri("/users/search?q=porto").to_request();
+        let body = test::read_body(test::call_service(&app, req).await).await;
+        assert!(serde_json::from_slice::<Vec<UserSearchResult>>(&body)
+            .unwrap()
+            .is_empty());
+
+        let req = test::TestRequest::with_uri("/users/search?q=%20").to_request();
+        assert_eq!(call_status(&app, req).await, 400);
+        let req = test::TestRequest::with_uri("/users/search").to_request();
+        assert_eq!(call_status(&app, req).await, 400);
+    }
+
+    #[actix_web::test]
+    async fn test_stream_users() {
+        let user = |name: &str| User {
+            id: None,
+            name: name.to_string(),
+            title: "test".to_string(),
+            location: "Lisbon".to_string(),
+            version: 1,
+        };
+        let mut mock = MockRepository::new();
+        mock.expect_stream_users()
+            .with(predicate::function(|query: &UserQuery| {
+                query.location.as_deref() == Some("Lisbon")
+            }))
+            .returning(move |_| {
+                let users = vec![Ok(user("first")), Ok(user("second"))];
+                Ok(Box::pin(futures_util::stream::iter(users)))
+            });
+        mock.expect_stream_users().returning(move |_| {
+            let users = vec![
+                Ok(user("first")),
+                Err(RepositoryError::Unavailable("connection reset".to_string())),
+            ];
+            Ok(Box::pin(futures_util::stream::iter(users)))
+        });
+        let app_data = AppData {
+            db: Arc::new(mock),
+            ..get_app_data().as_ref().clone()
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(app_data))
+                .service(get_all_users),
+        )
+        .await;
+
+        let req = test::TestRequest::with_uri("/users?location=Lisbon")
+            .insert_header(("accept", "application/x-ndjson"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(
+            resp.headers().get("content-type").unwrap(),
+            "application/x-ndjson"
+        );
+        let body = test::read_body(resp).await;
+        let names: Vec<String> = String::from_utf8(body.to_vec())
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<User>(line).unwrap().name)
+            .collect();
+        assert_eq!(names, vec!["first", "second"]);
+
+        // The status was already sent, the body is cut short
+        let req = test::TestRequest::with_uri("/users")
+            .insert_header(("accept", "application/x-ndjson"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        assert!(actix_web::body::to_bytes(resp.into_body()).await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_import_users() {
+        let mut mock = MockRepository::new();
+        // Only the valid rows are written, the second one already exists
+        mock.expect_create_users()
+            .withf(|users| {
+                users
+                    .iter()
+                    .map(|user| user.name.as_str())
+                    .collect::<Vec<_>>()
+                    == vec!["Anne", "Ana"]
+            })
+            .times(1)
+            .returning(|_| {
+                Ok(vec![
+                    Ok(CreateUserResult { id: user_id() }),
+                    Err(RepositoryError::Conflict("duplicate key".to_string())),
+                ])
+            });
+        mock.expect_create_users()
+            .withf(|users| users.len() == 1 && users[0].name == "Rui")
+            .times(1)
+            .returning(|_| Ok(vec![Ok(CreateUserResult { id: user_id() })]));
+        // The batch written before the byte limit is kept
+        mock.expect_create_users()
+            .withf(|users| users.len() == 1 && users[0].name == "Lia")
+            .times(1)
+            .returning(|_| Ok(vec![Ok(CreateUserResult { id: user_id() })]));
+        // One write per batch, with a record per created user
+        let mut audit = MockAuditRepository::new();
+        audit
+            .expect_record_audits()
+            .withf(|records| {
+                records.len() == 1
+                    && records[0].action == AuditAction::UserImport
+                    && records[0].target_id == Some(USER_ID.to_string())
+                    && records[0].after.is_some()
+            })
+            .times(3)
+            .returning(|_| Ok(()));
+        let app_data = AppData {
+            db: Arc::new(mock),
+            audit: Arc::new(audit),
+            ..get_app_data().as_ref().clone()
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(app_data))
+                .service(import_users),
+        )
+        .await;
+        let import = |uri: &str, content_type: &str, body: &str| {
+            let req = test::TestRequest::with_uri(uri)
+                .method(http::Method::POST)
+                .insert_header(("content-type", content_type))
+                .set_payload(body.to_string())
+                .to_request();
+            req.extensions_mut().insert(principal(&["users:create"]));
+            req
+        };
+        // Quoted fields can have commas and line breaks, the columns can be in any order
+        let csv = "title,name,location\r\n\
+                   Engineer,Anne,\"Lisbon, PT\"\r\n\
+                   Engineer,,Lisbon\r\n\
+                   \r\n\
+                   Designer,Rui,\"Porto\nPT\"\r\n\
+                   Designer,Rui\r\n\
+                   Designer,Ana,Porto";
+
+        let resp = test::call_service(&app, import("/users:import", "text/csv", csv)).await;
+        assert_eq!(resp.status(), 200);
+        let body = test::read_body(resp).await;
+        let report = serde_json::from_slice::<ImportReport>(&body).unwrap();
+        assert_eq!((report.succeeded, report.failed), (1, 4));
+        let statuses: Vec<(usize, ImportRowStatus)> = report
+            .rows
+            .iter()
+            .map(|row| (row.row, row.status))
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                (1, ImportRowStatus::Created),
+                (2, ImportRowStatus::Failed),
+                (3, ImportRowStatus::Failed),
+                (4, ImportRowStatus::Failed),
+                (5, ImportRowStatus::Failed),
+            ]
+        );
+        assert_eq!(report.rows[0].id, Some(user_id()));
+        assert_eq!(report.rows[1].errors[0].field, "name");
+        assert_eq!(report.rows[2].errors[0].code, "invalid_characters");
+        assert_eq!(report.rows[3].errors[0].code, "invalid_body");
+        assert_eq!(report.rows[4].errors[0].code, "conflict");
+
+        // A dry run only validates the rows
+        let resp = test::call_service(
+            &app,
+            import("/users:import?dry_run=true", "text/csv; charset=utf-8", csv),
+        )
+        .await;
+        let body = test::read_body(resp).await;
+        let report = serde_json::from_slice::<ImportReport>(&body).unwrap();
+        assert!(report.dry_run);
+        assert_eq!((report.succeeded, report.failed), (2, 3));
+        assert_eq!(report.rows[0].status, ImportRowStatus::Valid);
+
+        let ndjson = "{\"name\": \"Rui\", \"location\": \"Porto\", \"title\": \"Designer\"}\n\
+                      {\"name\": \"Rui\"\n\
+                      {\"_id\": \"1\", \"name\": \"Rui\", \"location\": \"Porto\", \"title\": \"Designer\"}";
+        let resp = test::call_service(
+            &app,
+            import("/users:import", "application/x-ndjson", ndjson),
+        )
+        .await;
+        let body = test::read_body(resp).await;
+        let report = serde_json::from_slice::<ImportReport>(&body).unwrap();
+        assert_eq!((report.succeeded, report.failed), (1, 2));
+        assert_eq!(report.rows[1].errors[0].code, "invalid_body");
+        assert_eq!(report.rows[2].errors[0].field, "_id");
+
+        let req = import("/users:import", "application/json", "[]");
+        assert_eq!(call_status(&app, req).await, 415);
+
+        // A row that is too long fails on its own, the next ones are still read
+        let long_name = "a".repeat(MAX_RECORD_BYTES);
+        let csv = format!(
+            "name,location,title\n{},Porto,Designer\nRui,Porto,Designer",
+            long_name
+        );
+        let resp =
+            test::call_service(&app, import("/users:import?dry_run=true", "text/csv", &csv)).await;
+        let body = test::read_body(resp).await;
+        let report = serde_json::from_slice::<ImportReport>(&body).unwrap();
+        assert_eq!((report.succeeded, report.failed), (1, 1));
+        assert_eq!(report.rows[0].errors[0].code, "invalid_body");
+
+        // The import stops at the limits, the first row that isn't read fails
+        let ndjson = "{\"name\": \"Rui\", \"location\": \"Porto\", \"title\": \"Designer\"}\n"
+            .repeat(MAX_IMPORT_ROWS + 2);
+        let resp = test::call_service(
+            &app,
+            import(
+                "/users:import?dry_run=true",
+                "application/x-ndjson",
+                &ndjson,
+            ),
+        )
+        .await;
+        let body = test::read_body(resp).await;
+        let report = serde_json::from_slice::<ImportReport>(&body).unwrap();
+        assert_eq!((report.succeeded, report.failed), (MAX_IMPORT_ROWS, 1));
+        let last = report.rows.last().unwrap();
+        assert_eq!(last.row, MAX_IMPORT_ROWS + 1);
+        assert_eq!(last.errors[0].code, "payload_too_large");
+
+        let csv = format!(
+            "name,location,title\nLia,Porto,Designer\nRui,{}",
+            " ".repeat(MAX_IMPORT_BYTES)
+        );
+        let resp = test::call_service(&app, import("/users:import", "text/csv", &csv)).await;
+        assert_eq!(resp.status(), 200);
+        let body = test::read_body(resp).await;
+        let report = serde_json::from_slice::<ImportReport>(&body).unwrap();
+        assert_eq!((report.succeeded, report.failed), (1, 1));
+        assert_eq!(report.rows[0].status, ImportRowStatus::Created);
+        assert_eq!(report.rows[1].row, 2);
+        assert_eq!(report.rows[1].errors[0].code, "payload_too_large");
+
+        // Nothing is written when the Content-Length is already over the limit
+        let req = test::TestRequest::with_uri("/users:import")
+            .method(http::Method::POST)
+            .insert_header(("content-type", "text/csv"))
+            .insert_header(("content-length", (MAX_IMPORT_BYTES + 1).to_string()))
+            .set_payload(csv)
+            .to_request();
+        req.extensions_mut().insert(principal(&["users:create"]));
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 413);
+        assert_eq!(read_problem(resp).await.code, "payload_too_large");
+    }
+
+    #[actix_web::test]
+    async fn test_export_users() {
+        let mut mock = MockRepository::new();
+        mock.expect_stream_users()
+            .with(predicate::function(|query: &UserQuery| {
+                query.location.as_deref() == Some("Porto")
+            }))
+            .returning(|_| {
+                let users = vec![
+                    Ok(User {
+                        id: Some(user_id()),
+                        name: "Ana".to_string(),
+                        location: "Porto".to_string(),
+                        title: "Designer, UX".to_string(),
+                        version: 1,
+                    }),
+                    Ok(User {
+                        id: None,
+                        name: "Rui".to_string(),
+                        location: "Porto".to_string(),
+                        title: "Engineer".to_string(),
+                        version: 1,
+                    }),
+                ];
+                Ok(Box::pin(futures_util::stream::iter(users)))
+            });
+        let app_data = AppData {
+            db: Arc::new(mock),
+            ..get_app_data().as_ref().clone()
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(app_data))
+                .service(export_users),
+        )
+        .await;
+        let export = |format: &str| {
+            let req = test::TestRequest::with_uri(&format!(
+                "/users:export?location=Porto&format={}",
+                format
+            ))
+            .to_request();
+            req.extensions_mut().insert(principal(&["users:read"]));
+            req
+        };
+
+        let resp = test::call_service(&app, export("csv")).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers().get("content-type").unwrap(), "text/csv");
+        let disposition = resp.headers().get("content-disposition").unwrap();
+        assert!(disposition
+            .to_str()
+            .unwrap()
+            .starts_with("attachment; filename=\"users-"));
+        assert!(disposition.to_str().unwrap().ends_with(".csv\""));
+        let body = test::read_body(resp).await;
+        assert_eq!(
+            String::from_utf8(body.to_vec()).unwrap(),
+            format!(
+                "id,name,location,title\n{},Ana,Porto,\"Designer, UX\"\n,Rui,Porto,Engineer\n",
+                USER_ID
+            )
+        );
+
+        let resp = test::call_service(&app, export("json")).await;
+        assert_eq!(
+            resp.headers().get("content-type").unwrap(),
+            "application/json"
+        );
+        let body = test::read_body(resp).await;
+        let users = serde_json::from_slice::<Vec<User>>(&body).unwrap();
+        assert_eq!(users.len(), 2);
+        assert_eq!(users[1].name, "Rui");
+
+        let resp = test::call_service(&app, export("ndjson")).await;
+        assert_eq!(
+            resp.headers().get("content-type").unwrap(),
+            "application/x-ndjson"
+        );
+        let body = test::read_body(resp).await;
+        assert_eq!(String::from_utf8(body.to_vec()).unwrap().lines().count(), 2);
+
+        assert_eq!(call_status(&app, export("xml")).await, 400);
+        let req = test::TestRequest::with_uri("/users:export").to_request();
+        req.extensions_mut().insert(principal(&["users:create"]));
+        assert_eq!(call_status(&app, req).await, 403);
+    }
+
+    #[actix_web::test]
+    async fn test_batch_users() {
+        let mut mock = MockRepository::new();
+        // The ordered batch stops at the failed update
+        mock.expect_bulk_write()
+            .withf(|writes, options| {
+                options.ordered && !options.atomic && matches!(writes[0], UserWrite::Create(_))
+            })
+            .returning(|_, _| {
+                Ok(vec![
+                    Ok(user_id()),
+                    Err(RepositoryError::NotFound("No user found".to_string())),
+                ])
+            });
+        mock.expect_bulk_write()
+            .withf(|writes, options| options.atomic && writes.len() == 2)
+            .returning(|_, _| {
+                Ok(vec![
+                    Ok(user_id()),
+                    Err(RepositoryError::Conflict("duplicate key".to_string())),
+                ])
+            });
+        mock.expect_bulk_write()
+            .withf(|writes, options| options.atomic && writes.len() == 1)
+            .returning(|_, _| Err(RepositoryError::Unsupported("standalone".to_string())));
+        // The users before the writes are read at once
+        mock.expect_get_users()
+            .withf(|ids| ids == &vec![user_id()])
+            .returning(|_| {
+                Ok(vec![User {
+                    id: Some(user_id()),
+                    name: "Rui".to_string(),
+                    location: "Lisbon".to_string(),
+                    title: "Engineer".to_string(),
+                    version: 1,
+                }])
+            });
+        // One record per operation that ran, except the rolled back delete, and one for the batch that failed
+        let mut audit = MockAuditRepository::new();
+        audit
+            .expect_record_audits()
+            .withf(|records| {
+                records.len() == 2
+                    && records[0].action == AuditAction::UserCreate
+                    && records[0].target_id == Some(USER_ID.to_string())
+                    && records[0].outcome == AuditOutcome::Success
+                    && records[1].action == AuditAction::UserUpdate
+                    && records[1].target_id == Some(USER_ID.to_string())
+                    && records[1].before.is_some()
+                    && records[1].after.is_none()
+                    && records[1].outcome != AuditOutcome::Success
+            })
+            .times(1)
+            .returning(|_| Ok(()));
+        audit
+            .expect_record_audits()
+            .withf(|records| {
+                records.len() == 1
+                    && records[0].action == AuditAction::UserCreate
+                    && records[0].target_id.is_none()
+                    && records[0].outcome != AuditOutcome::Success
+            })
+            .times(1)
+            .returning(|_| Ok(()));
+        audit
+            .expect_record_audit()
+            .withf(|record| record.action == AuditAction::UserBatch)
+            .times(1)
+            .returning(|_| Ok(()));
+        let app_data = AppData {
+            db: Arc::new(mock),
+            audit: Arc::new(audit),
+            ..get_app_data().as_ref().clone()
+        };
+        let app = test::init_service(
+            App::new()
+                .configure(error_handlers)
+                .app_data(Data::new(app_data))
+                .service(batch_users),
+        )
+        .await;
+        let batch = |body: serde_json::Value, permissions: &[&str]| {
+            let req = test::TestRequest::with_uri("/users:batch")
+                .method(http::Method::POST)
+                .set_json(body)
+                .to_request();
+            req.extensions_mut().insert(principal(permissions));
+            req
+        };
+        let user = serde_json::json!({"name": "Ana", "location": "Porto", "title": "Designer"});
+        let all = ["users:create", "users:update", "users:delete"];
+
+        let operations = serde_json::json!({"operations": [
+            {"op": "create", "user": user},
+            {"op": "update", "id": USER_ID, "user": user},
+            {"op": "delete", "id": USER_ID},
+        ]});
+        let resp = test::call_service(&app, batch(operations.clone(), &all)).await;
+        assert_eq!(resp.status(), 200);
+        let report: BatchReport = test::read_body_json(resp).await;
+        let statuses: Vec<BatchStatus> = report.results.iter().map(|r| r.status).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                BatchStatus::Created,
+                BatchStatus::Failed,
+                BatchStatus::Skipped
+            ]
+        );
+        assert_eq!((report.succeeded, report.failed), (1, 2));
+        assert_eq!(report.results[0].id, Some(user_id()));
+        assert_eq!(report.results[1].errors[0].code, "not_found");
+
+        // Every kind of operation of the batch needs its permission
+        let req = batch(operations, &["users:create", "users:update"]);
+        assert_eq!(call_status(&app, req).await, 403);
+
+        // Nothing is kept when an operation of an atomic batch fails
+        let atomic = serde_json::json!({"atomic": true, "operations": [
+            {"op": "delete", "id": USER_ID},
+            {"op": "create", "user": user},
+        ]});
+        let resp = test::call_service(&app, batch(atomic, &all)).await;
+        let report: BatchReport = test::read_body_json(resp).await;
+        assert_eq!(report.results[0].status, BatchStatus::RolledBack);
+        assert_eq!(report.results[1].errors[0].code, "conflict");
+        assert_eq!((report.succeeded, report.failed), (0, 2));
+
+        let atomic =
+            serde_json::json!({"atomic": true, "operations": [{"op": "delete", "id": USER_ID}]});
+        assert_eq!(call_status(&app, batch(atomic, &all)).await, 501);
+
+        // The operations are validated before any is run
+        let invalid = serde_json::json!({"operations": [
+            {"op": "create", "user": {"name": "", "location": "Porto", "title": "Designer"}},
+            {"op": "update", "id": "abc", "user": user},
+        ]});
+        let resp = test::call_service(&app, batch(invalid, &all)).await;
+        assert_eq!(resp.status(), 422);
+        let problem = read_problem(resp).await;
+        let fields: Vec<&str> = problem.errors.iter().map(|e| e.field.as_str()).collect();
+        assert_eq!(fields, vec!["operations[0].user.name", "operations[1].id"]);
+
+        let empty = serde_json::json!({"operations": []});
+        assert_eq!(call_status(&app, batch(empty, &all)).await, 422);
+    }
+
+    #[actix_web::test]
+    async fn test_batch_users_audit() {
+        const OTHER_ID: &str = "65f0bbf848c60e78920bfd4d";
+        let other_id = || UserId::parse(OTHER_ID).unwrap();
+        let user = |id: UserId, name: &str, version: u64| User {
+            id: Some(id),
+            name: name.to_string(),
+            location: "Porto".to_string(),
+            title: "Designer".to_string(),
+            version,
+        };
+        let mut mock = MockRepository::new();
+        let mut sequence = mockall::Sequence::new();
+        mock.expect_get_users()
+            .times(1)
+            .in_sequence(&mut sequence)
+            .returning(move |_| Ok(vec![user(user_id(), "Rui", 1), user(other_id(), "Rui", 1)]));
+        // An id the database can't have generated only fails its own operation
+        mock.expect_bulk_write()
+            .withf(|writes, options| !options.ordered && writes.len() == 4)
+            .returning(move |_, _| {
+                Ok(vec![
+                    Ok(user_id()),
+                    Ok(other_id()),
+                    Ok(other_id()),
+                    Err(RepositoryError::InvalidId("1".to_string())),
+                ])
+            });
+        // Read again after the writes, the other user is deleted by then
+        mock.expect_get_users()
+            .withf(|ids| ids == &vec![user_id()])
+            .times(1)
+            .in_sequence(&mut sequence)
+            .returning(move |_| Ok(vec![user(user_id(), "Ana", 5)]));
+        let mut audit = MockAuditRepository::new();
+        audit
+            .expect_record_audits()
+            .withf(move |records| {
+                let after = |index: usize| {
+                    serde_json::from_value::<User>(records[index].after.clone().unwrap()).unwrap()
+                };
+                let before = |index: usize| {
+                    serde_json::from_value::<User>(records[index].before.clone().unwrap()).unwrap()
+                };
+                records.len() == 4
+                    // The user as it was read after the writes
+                    && after(0) == user(user_id(), "Ana", 5)
+                    // Deleted later in the batch, so the version is the one of the write
+                    && after(1) == user(other_id(), "Ana", 2)
+                    && before(2) == user(other_id(), "Ana", 2)
+                    && records[3].outcome != AuditOutcome::Success
+            })
+            .times(1)
+            .returning(|_| Ok(()));
+        let app_data = AppData {
+            db: Arc::new(mock),
+            audit: Arc::new(audit),
+            ..get_app_data().as_ref().clone()
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(app_data))
+                .service(batch_users),
+        )
+        .await;
+        let ana = serde_json::json!({"name": "Ana", "location": "Porto", "title": "Designer"});
+        let req = test::TestRequest::with_uri("/users:batch")
+            .method(http::Method::POST)
+            .set_json(serde_json::json!({"ordered": false, "operations": [
+                {"op": "update", "id": USER_ID, "user": ana},
+                {"op": "update", "id": OTHER_ID, "user": ana},
+                {"op": "delete", "id": OTHER_ID},
+                {"op": "delete", "id": "1"},
+            ]}))
+            .to_request();
+        req.extensions_mut()
+            .insert(principal(&["users:update", "users:delete"]));
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        let report: BatchReport = test::read_body_json(resp).await;
+        assert_eq!((report.succeeded, report.failed), (3, 1));
+        assert_eq!(report.results[3].errors[0].code, "invalid_id");
+    }
 }
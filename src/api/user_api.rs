@@ -1,99 +1,342 @@
-use crate::models::{app::AppData, user_model::User};
+use crate::api::{audit_api::record_audit, error::ApiError, request_id::RequestId};
+use crate::auth::{
+    claims::Permission, permission_middleware::RequirePermissions, principal::Principal,
+};
+use crate::database::error::RepositoryError;
+use crate::models::audit_model::{AuditAction, AuditRecord};
+use crate::models::{
+    app::AppData,
+    user_model::{
+        CreateUserRequest, UpdateUserRequest, User, UserId, UserPatch, UserQuery, UserSearchQuery,
+    },
+};
 use actix_web::{
-    delete, get, post, put,
-    web::{Data, Json, Path},
-    HttpResponse,
+    delete, get,
+    http::header::{EntityTag, IfMatch, IfNoneMatch, ACCEPT, ETAG, LINK},
+    patch, post, put,
+    web::{Bytes, Data, Json, Query},
+    HttpMessage, HttpRequest, HttpResponse,
 };
-use mongodb::bson::oid::ObjectId;
+use futures_util::stream::{BoxStream, Stream, StreamExt};
+use json_patch::PatchErrorKind;
+use serde::Serialize;
+use serde_json::{json, Value};
+use validator::Validate;
+
+// RFC 7396 and RFC 6902
+pub const MERGE_PATCH_JSON: &str = "application/merge-patch+json";
+pub const JSON_PATCH_JSON: &str = "application/json-patch+json";
+pub const X_TOTAL_COUNT: &str = "x-total-count";
+pub const NDJSON: &str = "application/x-ndjson";
 
-#[post("/user")]
-pub async fn create_user(app_data: Data<AppData>, new_user: Json<User>) -> HttpResponse {
+#[post("/user", wrap = "RequirePermissions::new(&[Permission::UsersCreate])")]
+pub async fn create_user(
+    app_data: Data<AppData>,
+    new_user: Json<CreateUserRequest>,
+    principal: Principal,
+    request_id: RequestId,
+) -> Result<HttpResponse, ApiError> {
+    new_user.validate()?;
+    let new_user = new_user.into_inner();
     let data = User {
         id: None,
-        name: new_user.name.to_owned(),
-        location: new_user.location.to_owned(),
-        title: new_user.title.to_owned(),
+        name: new_user.name,
+        location: new_user.location,
+        title: new_user.title,
+        version: 0,
     };
-    let user_detail = app_data.db.create_user(data).await;
-    match user_detail {
-        Ok(user) => HttpResponse::Ok().json(user),
-        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
-    }
+    let audit = AuditRecord::new(&principal, AuditAction::UserCreate, &request_id.0).after(&data);
+    let result = app_data.db.create_user(data).await;
+    let audit = match &result {
+        Ok(user) => audit.target(user.id.as_str()),
+        Err(err) => audit.failed(&err.to_string()),
+    };
+    record_audit(&app_data, audit).await;
+    Ok(HttpResponse::Ok().json(result?))
 }
 
+// The ETag is the version of the user, sent back in If-Match to only write the version that was read
 #[get("/user/{id}")]
-pub async fn get_user(app_data: Data<AppData>, path: Path<String>) -> HttpResponse {
-    let id = path.into_inner();
-    if id.is_empty() {
-        return HttpResponse::BadRequest().body("invalid ID");
+pub async fn get_user(
+    app_data: Data<AppData>,
+    id: UserId,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let found = app_data
+        .db
+        .get_user(&id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("user not found".to_string()))?;
+    let etag = etag(&found);
+    let not_modified = match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+        None => false,
+    };
+    if not_modified {
+        return Ok(HttpResponse::NotModified()
+            .insert_header((ETAG, etag.to_string()))
+            .finish());
     }
-    let user_detail = app_data.db.get_user(id).await;
-    match user_detail {
-        Ok(user) => match user {
-            Some(found) => HttpResponse::Ok().json(found),
-            None => HttpResponse::NotFound().body("user not found"),
-        },
-        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    Ok(HttpResponse::Ok()
+        .insert_header((ETAG, etag.to_string()))
+        .json(found))
+}
+
+fn etag(user: &User) -> EntityTag {
+    EntityTag::new_strong(user.version.to_string())
+}
+
+// The version the write requires with If-Match, or None to write any version. Without a current user,
+// no tag matches
+fn expected_version(req: &HttpRequest, current: Option<&User>) -> Result<Option<u64>, ApiError> {
+    let tags = match req.get_header::<IfMatch>() {
+        None | Some(IfMatch::Any) => return Ok(None),
+        Some(IfMatch::Items(tags)) => tags,
+    };
+    match current {
+        Some(current) if tags.iter().any(|tag| tag.strong_eq(&etag(current))) => {
+            Ok(Some(current.version))
+        }
+        _ => Err(ApiError::PreconditionFailed(
+            "The user doesn't match If-Match".to_string(),
+        )),
     }
 }
 
-#[put("/user/{id}")]
+#[put(
+    "/user/{id}",
+    wrap = "RequirePermissions::new(&[Permission::UsersUpdate])"
+)]
 pub async fn update_user(
     app_data: Data<AppData>,
-    path: Path<String>,
-    new_user: Json<User>,
-) -> HttpResponse {
-    let id = path.into_inner();
-    if id.is_empty() {
-        return HttpResponse::BadRequest().body("invalid ID");
-    };
+    user_id: UserId,
+    req: HttpRequest,
+    new_user: Json<UpdateUserRequest>,
+    principal: Principal,
+    request_id: RequestId,
+) -> Result<HttpResponse, ApiError> {
+    new_user.validate()?;
+    let new_user = new_user.into_inner();
     let data = User {
-        id: Some(ObjectId::parse_str(&id).unwrap()),
-        name: new_user.name.to_owned(),
-        location: new_user.location.to_owned(),
-        title: new_user.title.to_owned(),
+        id: Some(user_id.clone()),
+        name: new_user.name,
+        location: new_user.location,
+        title: new_user.title,
+        version: 0,
     };
-    let update_result = app_data.db.update_user(&id, data).await;
-    match update_result {
-        Ok(update) => {
-            if update.matched_count == 1 {
-                let updated_user_info = app_data.db.get_user(id).await;
-                match updated_user_info {
-                    Ok(user) => HttpResponse::Ok().json(user),
-                    Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
-                }
-            } else {
-                HttpResponse::NotFound().body("No user found with specified ID")
-            }
-        }
-        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    let audit = AuditRecord::new(&principal, AuditAction::UserUpdate, &request_id.0)
+        .target(user_id.as_str());
+    let result = async {
+        let before = app_data.db.get_user(&user_id).await?;
+        let expected_version = expected_version(&req, before.as_ref())?;
+        app_data
+            .db
+            .update_user(&user_id, data, expected_version)
+            .await?;
+        let updated = app_data
+            .db
+            .get_user(&user_id)
+            .await?
+            .ok_or_else(|| ApiError::NotFound("No user found with specified ID".to_string()))?;
+        Ok::<_, ApiError>((before, updated))
     }
+    .await;
+    let audit = match &result {
+        Ok((Some(before), updated)) => audit.before(before).after(updated),
+        Ok((None, updated)) => audit.after(updated),
+        Err(err) => audit.failed(&err.to_string()),
+    };
+    record_audit(&app_data, audit).await;
+    let (_, updated) = result?;
+    Ok(HttpResponse::Ok()
+        .insert_header((ETAG, etag(&updated).to_string()))
+        .json(updated))
 }
 
-#[delete("/user/{id}")]
-pub async fn delete_user(app_data: Data<AppData>, path: Path<String>) -> HttpResponse {
-    let id = path.into_inner();
-    if id.is_empty() {
-        return HttpResponse::BadRequest().body("invalid ID");
+// e.g. PATCH /api/admin/user/{id} with Content-Type: application/merge-patch+json and {"title": "CTO"}
+#[patch(
+    "/user/{id}",
+    wrap = "RequirePermissions::new(&[Permission::UsersUpdate])"
+)]
+pub async fn patch_user(
+    app_data: Data<AppData>,
+    user_id: UserId,
+    req: HttpRequest,
+    body: Json<Value>,
+    principal: Principal,
+    request_id: RequestId,
+) -> Result<HttpResponse, ApiError> {
+    let audit = AuditRecord::new(&principal, AuditAction::UserUpdate, &request_id.0)
+        .target(user_id.as_str());
+    let result = async {
+        let current = app_data
+            .db
+            .get_user(&user_id)
+            .await?
+            .ok_or_else(|| ApiError::NotFound("No user found with specified ID".to_string()))?;
+        let expected_version = expected_version(&req, Some(&current))?;
+        // Parameters like the charset are ignored, the body is JSON either way
+        let mime = req
+            .mime_type()
+            .map_err(|err| ApiError::UnsupportedMediaType(err.to_string()))?;
+        let content_type = mime.as_ref().map(|mime| mime.essence_str());
+        let patch = apply_patch(&current, content_type, body.into_inner())?;
+        if patch.is_empty() {
+            return Ok((current.clone(), current));
+        }
+        let updated = app_data
+            .db
+            .patch_user(&user_id, patch, expected_version)
+            .await?;
+        Ok::<_, ApiError>((current, updated))
+    }
+    .await;
+    let audit = match &result {
+        Ok((current, updated)) => audit.before(current).after(updated),
+        Err(err) => audit.failed(&err.to_string()),
     };
-    let result = app_data.db.delete_user(&id).await;
-    match result {
-        Ok(res) => {
-            if res.deleted_count == 1 {
-                HttpResponse::Ok().json("User successfully deleted!")
-            } else {
-                HttpResponse::NotFound().json("User with specified ID not found!")
-            }
+    record_audit(&app_data, audit).await;
+    let (_, updated) = result?;
+    Ok(HttpResponse::Ok()
+        .insert_header((ETAG, etag(&updated).to_string()))
+        .json(updated))
+}
+
+// The patch is applied to the fields of the user, so the result is validated like the body of a PUT
+fn apply_patch(
+    current: &User,
+    content_type: Option<&str>,
+    patch: Value,
+) -> Result<UserPatch, ApiError> {
+    let mut document = json!({
+        "name": current.name,
+        "location": current.location,
+        "title": current.title,
+    });
+    match content_type {
+        Some(MERGE_PATCH_JSON) => json_patch::merge(&mut document, &patch),
+        Some(JSON_PATCH_JSON) => {
+            let operations: json_patch::Patch = serde_json::from_value(patch)
+                .map_err(|err| ApiError::Validation(err.to_string()))?;
+            json_patch::patch(&mut document, &operations).map_err(|err| match err.kind {
+                PatchErrorKind::TestFailed => ApiError::Conflict(err.to_string()),
+                _ => ApiError::Validation(err.to_string()),
+            })?;
+        }
+        _ => {
+            return Err(ApiError::UnsupportedMediaType(format!(
+                "PATCH requires {} or {}",
+                MERGE_PATCH_JSON, JSON_PATCH_JSON
+            )))
         }
-        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
     }
+    let patched: UpdateUserRequest =
+        serde_json::from_value(document).map_err(|err| ApiError::from_data_error(&err))?;
+    patched.validate()?;
+    Ok(UserPatch::diff(current, patched))
+}
+
+#[delete(
+    "/user/{id}",
+    wrap = "RequirePermissions::new(&[Permission::UsersDelete])"
+)]
+pub async fn delete_user(
+    app_data: Data<AppData>,
+    user_id: UserId,
+    req: HttpRequest,
+    principal: Principal,
+    request_id: RequestId,
+) -> Result<HttpResponse, ApiError> {
+    let audit = AuditRecord::new(&principal, AuditAction::UserDelete, &request_id.0)
+        .target(user_id.as_str());
+    let result = async {
+        let before = app_data.db.get_user(&user_id).await?;
+        let expected_version = expected_version(&req, before.as_ref())?;
+        app_data.db.delete_user(&user_id, expected_version).await?;
+        Ok::<_, ApiError>(before)
+    }
+    .await;
+    let audit = match &result {
+        Ok(Some(before)) => audit.before(before),
+        Ok(None) => audit,
+        Err(err) => audit.failed(&err.to_string()),
+    };
+    record_audit(&app_data, audit).await;
+    result?;
+    Ok(HttpResponse::Ok().json("User successfully deleted!"))
 }
 
+// e.g. GET /api/users?title_prefix=Eng&sort=-name&limit=20, the following page is linked in the Link header.
+// With Accept: application/x-ndjson, every matching user is streamed instead, one JSON document per line
 #[get("/users")]
-pub async fn get_all_users(app_data: Data<AppData>) -> HttpResponse {
-    let users = app_data.db.get_all_users().await;
-    match users {
-        Ok(users) => HttpResponse::Ok().json(users),
-        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+pub async fn get_all_users(
+    app_data: Data<AppData>,
+    req: HttpRequest,
+    query: Query<UserQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let query = query.into_inner();
+    if accepts_ndjson(&req) {
+        let users = app_data.db.stream_users(query).await?;
+        return Ok(HttpResponse::Ok()
+            .content_type(NDJSON)
+            .streaming(ndjson_lines(users)));
+    }
+    query.check_cursor()?;
+    let page = app_data.db.list_users(query.clone()).await?;
+    let mut response = HttpResponse::Ok();
+    if let Some(total) = page.total {
+        response.insert_header((X_TOTAL_COUNT, total));
+    }
+    if let Some(next) = page.next {
+        let next_query = UserQuery {
+            after: Some(next),
+            ..query
+        };
+        let query_string = serde_urlencoded::to_string(&next_query)
+            .map_err(|err| ApiError::Internal(err.to_string()))?;
+        response.insert_header((
+            LINK,
+            format!("<{}?{}>; rel=\"next\"", req.path(), query_string),
+        ));
+    }
+    Ok(response.json(page.users))
+}
+
+fn accepts_ndjson(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains(NDJSON))
+}
+
+// The response is written as the users are read, the stream is only polled when the client can receive more.
+// The status is already sent when an error happens, so the response is cut short and the error logged
+pub fn ndjson_lines<T: Serialize>(
+    items: BoxStream<'static, Result<T, RepositoryError>>,
+) -> impl Stream<Item = Result<Bytes, ApiError>> {
+    items.map(|item| {
+        let item = item.map_err(|err| {
+            log::error!("failed to stream the response: {}", err);
+            ApiError::from(err)
+        })?;
+        let mut line =
+            serde_json::to_vec(&item).map_err(|err| ApiError::Internal(err.to_string()))?;
+        line.push(b'\n');
+        Ok(Bytes::from(line))
+    })
+}
+
+// e.g. GET /api/users/search?q=rust engineer, the users are returned with their relevance score, best first
+#[get("/users/search")]
+pub async fn search_users(
+    app_data: Data<AppData>,
+    query: Query<UserSearchQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let query = query.into_inner();
+    if query.q.trim().is_empty() {
+        return Err(ApiError::InvalidQuery("q is required".to_string()));
     }
+    let results = app_data.db.search_users(query).await?;
+    Ok(HttpResponse::Ok().json(results))
 }
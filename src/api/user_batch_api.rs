@@ -0,0 +1,308 @@
+use crate::api::{
+    audit_api::{record_audit, record_audits},
+    error::{ApiError, FieldError},
+    request_id::RequestId,
+};
+use crate::auth::{
+    auth_middleware::format_permissions, claims::Permission, error::ClientError,
+    principal::Principal,
+};
+use crate::models::audit_model::{AuditAction, AuditRecord};
+use crate::models::batch_model::{
+    BatchOperation, BatchReport, BatchRequest, BatchResult, BatchStatus, BulkWriteOptions,
+    UserWrite, MAX_BATCH_OPERATIONS,
+};
+use crate::models::{
+    app::AppData,
+    user_model::{User, UserId},
+};
+use actix_web::{
+    post,
+    web::{Data, Json},
+    HttpResponse,
+};
+use std::collections::{HashMap, HashSet};
+use validator::{Validate, ValidationErrors};
+
+// e.g. POST /api/admin/users:batch with {"ordered": false, "operations": [{"op": "delete", "id": "..."}, ...]}
+// Every operation is validated before any is run, the response has the outcome of each one
+#[post("/users:batch")]
+pub async fn batch_users(
+    app_data: Data<AppData>,
+    batch: Json<BatchRequest>,
+    principal: Principal,
+    request_id: RequestId,
+) -> Result<HttpResponse, ApiError> {
+    let batch = batch.into_inner();
+    if batch.operations.is_empty() || batch.operations.len() > MAX_BATCH_OPERATIONS {
+        return Err(ApiError::Validation(format!(
+            "A batch has between 1 and {} operations",
+            MAX_BATCH_OPERATIONS
+        )));
+    }
+    // The caller needs the permission of each kind of operation in the batch
+    let required: Vec<Permission> = [
+        Permission::UsersCreate,
+        Permission::UsersUpdate,
+        Permission::UsersDelete,
+    ]
+    .into_iter()
+    .filter(|permission| {
+        batch
+            .operations
+            .iter()
+            .any(|operation| required_permission(operation) == *permission)
+    })
+    .collect();
+    if !principal.validate_permissions(&required) {
+        return Err(ClientError::NoPermission(format_permissions(&required)).into());
+    }
+
+    let statuses: Vec<BatchStatus> = batch.operations.iter().map(success_status).collect();
+    let writes = user_writes(batch.operations)?;
+    let options = BulkWriteOptions {
+        ordered: batch.ordered,
+        atomic: batch.atomic,
+    };
+    // The users before their update or delete, for the audit records, read at once. An id the database can't
+    // have generated fails on its own operation
+    let targets: HashSet<UserId> = writes
+        .iter()
+        .filter_map(|write| match write {
+            UserWrite::Update(id, _) | UserWrite::Delete(id) => Some(id.clone()),
+            UserWrite::Create(_) => None,
+        })
+        .collect();
+    let mut users = users_by_id(app_data.db.get_users(targets.into_iter().collect()).await?);
+    let results = match app_data.db.bulk_write(writes.clone(), options).await {
+        Ok(results) => results,
+        Err(err) => {
+            let audit = AuditRecord::new(&principal, AuditAction::UserBatch, &request_id.0);
+            record_audit(&app_data, audit.failed(&err.to_string())).await;
+            return Err(err.into());
+        }
+    };
+
+    let rolled_back = batch.atomic && results.iter().any(Result::is_err);
+    // The updated users as they were written, read again like after a single update. The last successful write
+    // of each user is the one they were read after
+    let mut last_writes = HashMap::new();
+    for (index, (write, result)) in writes.iter().zip(&results).enumerate() {
+        if let (UserWrite::Update(id, _) | UserWrite::Delete(id), Ok(_)) = (write, result) {
+            last_writes.insert(id.clone(), index);
+        }
+    }
+    let updated: Vec<UserId> = last_writes
+        .iter()
+        .filter(|(_, index)| matches!(writes[**index], UserWrite::Update(..)))
+        .map(|(id, _)| id.clone())
+        .collect();
+    let mut written = match rolled_back || updated.is_empty() {
+        true => HashMap::new(),
+        false => match app_data.db.get_users(updated).await {
+            Ok(written) => users_by_id(written),
+            // The writes are done, the audit records then have the users derived from the ones before
+            Err(err) => {
+                log::warn!(
+                    "failed to read the users of batch {}: {}",
+                    request_id.0,
+                    err
+                );
+                HashMap::new()
+            }
+        },
+    };
+    // One record per operation that ran and was kept, or that failed, all with the request id of the batch
+    let mut audits = vec![];
+    for (index, (write, result)) in writes.into_iter().zip(&results).enumerate() {
+        if rolled_back && result.is_ok() {
+            continue;
+        }
+        let (action, target, after) = match write {
+            UserWrite::Create(user) => (
+                AuditAction::UserCreate,
+                result.as_ref().ok().cloned(),
+                Some(user),
+            ),
+            // A failed update wrote nothing, like with a single update
+            UserWrite::Update(id, _) if result.is_err() => {
+                (AuditAction::UserUpdate, Some(id), None)
+            }
+            UserWrite::Update(id, user) => {
+                let after = match last_writes.get(&id) == Some(&index) {
+                    true => written.remove(&id),
+                    false => None,
+                };
+                let after = after.unwrap_or_else(|| updated_user(users.get(&id), user));
+                (AuditAction::UserUpdate, Some(id), Some(after))
+            }
+            UserWrite::Delete(id) => (AuditAction::UserDelete, Some(id), None),
+        };
+        let mut audit = AuditRecord::new(&principal, action, &request_id.0);
+        if let Some(target) = &target {
+            audit = audit.target(target.as_str());
+            if let Some(before) = users.get(target) {
+                audit = audit.before(before);
+            }
+        }
+        if let Some(after) = &after {
+            audit = audit.after(after);
+        }
+        audit = match result {
+            Ok(_) => audit,
+            Err(err) => audit.failed(&err.to_string()),
+        };
+        // A later operation on the same user starts from this one
+        if let (Some(target), Ok(_)) = (target, result) {
+            match after {
+                Some(after) => users.insert(target, after),
+                None => users.remove(&target),
+            };
+        }
+        audits.push(audit);
+    }
+    record_audits(&app_data, audits).await;
+
+    let mut results = results.into_iter();
+    let mut report = BatchReport {
+        ordered: batch.ordered,
+        atomic: batch.atomic,
+        ..Default::default()
+    };
+    for (index, status) in statuses.into_iter().enumerate() {
+        report.push(match results.next() {
+            Some(Ok(_)) if rolled_back => BatchResult {
+                index,
+                status: BatchStatus::RolledBack,
+                id: None,
+                errors: vec![],
+            },
+            Some(Ok(id)) => BatchResult {
+                index,
+                status,
+                id: Some(id),
+                errors: vec![],
+            },
+            Some(Err(err)) => BatchResult {
+                index,
+                status: BatchStatus::Failed,
+                id: None,
+                errors: ApiError::from(err).into_field_errors(),
+            },
+            None => BatchResult {
+                index,
+                status: BatchStatus::Skipped,
+                id: None,
+                errors: vec![],
+            },
+        });
+    }
+
+    Ok(HttpResponse::Ok().json(report))
+}
+
+fn users_by_id(users: Vec<User>) -> HashMap<UserId, User> {
+    users
+        .into_iter()
+        .filter_map(|user| Some((user.id.clone()?, user)))
+        .collect()
+}
+
+// The user as an update writes it, when it can't be read again. Like with every backend, the version only
+// changes with a field
+fn updated_user(before: Option<&User>, user: User) -> User {
+    let Some(before) = before else {
+        return user;
+    };
+    let changed = (&before.name, &before.location, &before.title)
+        != (&user.name, &user.location, &user.title);
+    User {
+        version: before.version + changed as u64,
+        ..user
+    }
+}
+
+fn required_permission(operation: &BatchOperation) -> Permission {
+    match operation {
+        BatchOperation::Create { .. } => Permission::UsersCreate,
+        BatchOperation::Update { .. } => Permission::UsersUpdate,
+        BatchOperation::Delete { .. } => Permission::UsersDelete,
+    }
+}
+
+fn success_status(operation: &BatchOperation) -> BatchStatus {
+    match operation {
+        BatchOperation::Create { .. } => BatchStatus::Created,
+        BatchOperation::Update { .. } => BatchStatus::Updated,
+        BatchOperation::Delete { .. } => BatchStatus::Deleted,
+    }
+}
+
+fn user_writes(operations: Vec<BatchOperation>) -> Result<Vec<UserWrite>, ApiError> {
+    let mut writes = vec![];
+    let mut errors = vec![];
+    for (index, operation) in operations.into_iter().enumerate() {
+        match user_write(operation, &format!("operations[{}]", index)) {
+            Ok(write) => writes.push(write),
+            Err(operation_errors) => errors.extend(operation_errors),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(ApiError::InvalidFields(errors));
+    }
+    Ok(writes)
+}
+
+// The fields of the errors are prefixed with the operation, e.g. operations[2].user.name or operations[2].id
+fn user_write(operation: BatchOperation, prefix: &str) -> Result<UserWrite, Vec<FieldError>> {
+    let parse_id =
+        |id: &str| UserId::parse(id).map_err(|err| prefixed(err, &format!("{}.id", prefix)));
+    let check = |result: Result<(), ValidationErrors>| {
+        result.map_err(|err| prefixed(err.into(), &format!("{}.user", prefix)))
+    };
+    match operation {
+        BatchOperation::Create { user } => {
+            check(user.validate())?;
+            Ok(UserWrite::Create(User {
+                id: None,
+                name: user.name,
+                location: user.location,
+                title: user.title,
+                version: 0,
+            }))
+        }
+        BatchOperation::Update { id, user } => match (parse_id(&id), check(user.validate())) {
+            (Ok(user_id), Ok(())) => Ok(UserWrite::Update(
+                user_id.clone(),
+                User {
+                    id: Some(user_id),
+                    name: user.name,
+                    location: user.location,
+                    title: user.title,
+                    version: 0,
+                },
+            )),
+            // Both the id and the user are reported
+            (id_result, user_result) => Err(id_result
+                .err()
+                .into_iter()
+                .chain(user_result.err())
+                .flatten()
+                .collect()),
+        },
+        BatchOperation::Delete { id } => Ok(UserWrite::Delete(parse_id(&id)?)),
+    }
+}
+
+fn prefixed(err: ApiError, prefix: &str) -> Vec<FieldError> {
+    err.into_field_errors()
+        .into_iter()
+        .map(|mut error| {
+            error.field = match error.field.as_str() {
+                "" => prefix.to_string(),
+                field => format!("{}.{}", prefix, field),
+            };
+            error
+        })
+        .collect()
+}
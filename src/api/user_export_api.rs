@@ -0,0 +1,113 @@
+use crate::api::error::ApiError;
+use crate::api::user_api::{ndjson_lines, NDJSON};
+use crate::api::user_import_api::CSV;
+use crate::auth::{claims::Permission, permission_middleware::RequirePermissions};
+use crate::database::repository::UserStream;
+use crate::models::{app::AppData, user_model::UserQuery};
+use actix_web::{
+    get,
+    http::header::CONTENT_DISPOSITION,
+    web::{Bytes, Data, Query},
+    HttpResponse,
+};
+use chrono::Utc;
+use futures_util::stream::{self, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Csv,
+    Ndjson,
+    #[default]
+    Json,
+}
+
+impl ExportFormat {
+    fn content_type(&self) -> &'static str {
+        match self {
+            Self::Csv => CSV,
+            Self::Ndjson => NDJSON,
+            Self::Json => "application/json",
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::Ndjson => "ndjson",
+            Self::Json => "json",
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    pub format: ExportFormat,
+}
+
+// e.g. GET /api/admin/users:export?format=csv&location=Lisbon, the users are filtered and sorted like GET /api/users,
+// and streamed from the repository, so the whole collection is never in memory
+#[get(
+    "/users:export",
+    wrap = "RequirePermissions::new(&[Permission::UsersRead])"
+)]
+pub async fn export_users(
+    app_data: Data<AppData>,
+    export: Query<ExportQuery>,
+    query: Query<UserQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let format = export.format;
+    let users = app_data.db.stream_users(query.into_inner()).await?;
+    let filename = format!(
+        "users-{}.{}",
+        Utc::now().format("%Y%m%dT%H%M%SZ"),
+        format.extension()
+    );
+    let mut response = HttpResponse::Ok();
+    response.content_type(format.content_type()).insert_header((
+        CONTENT_DISPOSITION,
+        format!("attachment; filename=\"{}\"", filename),
+    ));
+    Ok(match format {
+        ExportFormat::Ndjson => response.streaming(ndjson_lines(users)),
+        ExportFormat::Json => response.streaming(json_array(users)),
+        ExportFormat::Csv => response.streaming(csv_rows(users)),
+    })
+}
+
+// The users are written between the brackets as they are read
+fn json_array(users: UserStream) -> impl Stream<Item = Result<Bytes, ApiError>> {
+    let items = ndjson_lines(users).enumerate().map(|(index, line)| {
+        let line = line?;
+        let separator: &[u8] = if index == 0 { b"" } else { b"," };
+        Ok([separator, line.trim_ascii_end()].concat().into())
+    });
+    stream::once(async { Ok(Bytes::from_static(b"[")) })
+        .chain(items)
+        .chain(stream::once(async { Ok(Bytes::from_static(b"]\n")) }))
+}
+
+fn csv_rows(users: UserStream) -> impl Stream<Item = Result<Bytes, ApiError>> {
+    let rows = users.map(|user| {
+        let user = user.map_err(|err| {
+            log::error!("failed to stream the response: {}", err);
+            ApiError::from(err)
+        })?;
+        let id = user.id.map(String::from).unwrap_or_default();
+        csv_row(&[&id, &user.name, &user.location, &user.title])
+    });
+    stream::once(async { csv_row(&["id", "name", "location", "title"]) }).chain(rows)
+}
+
+fn csv_row(fields: &[&str]) -> Result<Bytes, ApiError> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    writer
+        .write_record(fields)
+        .map_err(|err| ApiError::Internal(err.to_string()))?;
+    writer
+        .into_inner()
+        .map(Bytes::from)
+        .map_err(|err| ApiError::Internal(err.to_string()))
+}
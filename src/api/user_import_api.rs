@@ -0,0 +1,370 @@
+use crate::api::user_api::NDJSON;
+use crate::api::{audit_api::record_audits, error::ApiError, request_id::RequestId};
+use crate::auth::{
+    claims::Permission, permission_middleware::RequirePermissions, principal::Principal,
+};
+use crate::models::audit_model::{AuditAction, AuditRecord};
+use crate::models::import_model::{ImportQuery, ImportReport, ImportRow, ImportRowStatus};
+use crate::models::{
+    app::AppData,
+    user_model::{CreateUserRequest, User},
+};
+use actix_web::{
+    http::header::CONTENT_LENGTH,
+    post,
+    web::{Data, Payload, Query},
+    HttpMessage, HttpRequest, HttpResponse,
+};
+use futures_util::StreamExt;
+use serde_json::{Map, Value};
+use validator::Validate;
+
+pub const CSV: &str = "text/csv";
+// Users are inserted by batches of this size, so the memory used doesn't depend on the size of the file
+const BATCH_SIZE: usize = 500;
+// A larger Content-Length is rejected with a 413 before anything is written. Otherwise the import stops at the
+// limits, like at the end of the file, and the first row that isn't read fails
+pub const MAX_IMPORT_BYTES: usize = 16 * 1024 * 1024;
+pub const MAX_IMPORT_ROWS: usize = 50_000;
+// Longer records fail on their own, their bytes aren't kept
+pub const MAX_RECORD_BYTES: usize = 16 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ImportFormat {
+    Csv,
+    Ndjson,
+}
+
+// e.g. curl -X POST -H "Content-Type: text/csv" --data-binary @users.csv /api/admin/users:import?dry_run=true
+// The CSV needs a header with the name, location and title columns, NDJSON has a user object per line
+#[post(
+    "/users:import",
+    wrap = "RequirePermissions::new(&[Permission::UsersCreate])"
+)]
+pub async fn import_users(
+    app_data: Data<AppData>,
+    req: HttpRequest,
+    query: Query<ImportQuery>,
+    mut payload: Payload,
+    principal: Principal,
+    request_id: RequestId,
+) -> Result<HttpResponse, ApiError> {
+    // Parameters like the charset are ignored, the payload is read as UTF-8
+    let mime = req
+        .mime_type()
+        .map_err(|err| ApiError::UnsupportedMediaType(err.to_string()))?;
+    let format = match mime.as_ref().map(|mime| mime.essence_str()) {
+        Some(CSV) => ImportFormat::Csv,
+        Some(NDJSON) => ImportFormat::Ndjson,
+        _ => {
+            return Err(ApiError::UnsupportedMediaType(format!(
+                "Imports require {} or {}",
+                CSV, NDJSON
+            )))
+        }
+    };
+    let content_length = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok()?.parse::<usize>().ok());
+    if content_length.is_some_and(|length| length > MAX_IMPORT_BYTES) {
+        return Err(payload_too_large());
+    }
+
+    let mut importer = Importer::new(&app_data, format, query.dry_run, &principal, &request_id.0);
+    let mut splitter = RecordSplitter::new(format);
+    let mut size = 0;
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|err| ApiError::InvalidBody(err.to_string()))?;
+        // The records up to the limit are still imported
+        let allowed = chunk.len().min(MAX_IMPORT_BYTES - size);
+        size += chunk.len();
+        for record in splitter.push(&chunk[..allowed]) {
+            importer.record(record).await;
+        }
+        if size > MAX_IMPORT_BYTES {
+            importer.stop();
+        }
+        if importer.stopped {
+            break;
+        }
+    }
+    if let Some(record) = splitter.finish().filter(|_| !importer.stopped) {
+        importer.record(record).await;
+    }
+    let report = importer.finish().await;
+    Ok(HttpResponse::Ok().json(report))
+}
+
+fn payload_too_large() -> ApiError {
+    ApiError::PayloadTooLarge(format!(
+        "An import has at most {} bytes and {} rows",
+        MAX_IMPORT_BYTES, MAX_IMPORT_ROWS
+    ))
+}
+
+// Splits the payload into records as it arrives, a CSV record spans several lines when a quoted field has a
+// line break
+struct RecordSplitter {
+    format: ImportFormat,
+    buffer: Vec<u8>,
+    quoted: bool,
+    // The current record is longer than MAX_RECORD_BYTES, the rest of it is skipped
+    too_long: bool,
+}
+
+impl RecordSplitter {
+    fn new(format: ImportFormat) -> Self {
+        RecordSplitter {
+            format,
+            buffer: vec![],
+            quoted: false,
+            too_long: false,
+        }
+    }
+
+    fn push(&mut self, chunk: &[u8]) -> Vec<Result<Vec<u8>, ApiError>> {
+        let mut records = vec![];
+        for &byte in chunk {
+            if byte == b'"' && self.format == ImportFormat::Csv {
+                self.quoted = !self.quoted;
+            }
+            if byte == b'\n' && !self.quoted {
+                records.push(self.take());
+            } else if self.buffer.len() < MAX_RECORD_BYTES {
+                self.buffer.push(byte);
+            } else {
+                self.too_long = true;
+            }
+        }
+        records
+    }
+
+    // The last record, when the payload doesn't end with a line break
+    fn finish(mut self) -> Option<Result<Vec<u8>, ApiError>> {
+        (!self.buffer.is_empty()).then(|| self.take())
+    }
+
+    fn take(&mut self) -> Result<Vec<u8>, ApiError> {
+        let record = std::mem::take(&mut self.buffer);
+        match std::mem::take(&mut self.too_long) {
+            true => Err(ApiError::InvalidBody(format!(
+                "The row is longer than {} bytes",
+                MAX_RECORD_BYTES
+            ))),
+            false => Ok(record),
+        }
+    }
+}
+
+struct Importer<'a> {
+    app_data: &'a AppData,
+    principal: &'a Principal,
+    request_id: &'a str,
+    format: ImportFormat,
+    dry_run: bool,
+    header: Option<Vec<String>>,
+    row: usize,
+    batch: Vec<(usize, User)>,
+    report: ImportReport,
+    // A limit was reached, the next records aren't read
+    stopped: bool,
+}
+
+impl<'a> Importer<'a> {
+    fn new(
+        app_data: &'a AppData,
+        format: ImportFormat,
+        dry_run: bool,
+        principal: &'a Principal,
+        request_id: &'a str,
+    ) -> Self {
+        Importer {
+            app_data,
+            principal,
+            request_id,
+            format,
+            dry_run,
+            header: None,
+            row: 0,
+            batch: vec![],
+            report: ImportReport {
+                dry_run,
+                ..Default::default()
+            },
+            stopped: false,
+        }
+    }
+
+    async fn record(&mut self, record: Result<Vec<u8>, ApiError>) {
+        if self.stopped {
+            return;
+        }
+        let record = match record {
+            Ok(record) => record,
+            Err(err) => {
+                // Without its header, the rows of a CSV fail on their number of columns
+                if self.format == ImportFormat::Csv && self.header.is_none() {
+                    self.header = Some(vec![]);
+                    return;
+                }
+                if let Some(row) = self.next_row() {
+                    self.report.push(failed_row(row, err));
+                }
+                return;
+            }
+        };
+        let record = record.strip_suffix(b"\r").unwrap_or(&record);
+        if record.iter().all(u8::is_ascii_whitespace) {
+            return;
+        }
+        if self.format == ImportFormat::Csv && self.header.is_none() {
+            let header = parse_csv(record).unwrap_or_default();
+            self.header = Some(
+                header
+                    .iter()
+                    .map(|column| column.trim().to_string())
+                    .collect(),
+            );
+            return;
+        }
+        let Some(row) = self.next_row() else {
+            return;
+        };
+        match self.parse(record) {
+            Ok(_) if self.dry_run => self.report.push(ImportRow {
+                row,
+                status: ImportRowStatus::Valid,
+                id: None,
+                errors: vec![],
+            }),
+            Ok(user) => {
+                self.batch.push((row, user));
+                if self.batch.len() >= BATCH_SIZE {
+                    self.flush().await;
+                }
+            }
+            Err(err) => self.report.push(failed_row(row, err)),
+        }
+    }
+
+    // The number of the next row, an import stops at MAX_IMPORT_ROWS
+    fn next_row(&mut self) -> Option<usize> {
+        if self.row >= MAX_IMPORT_ROWS {
+            self.stop();
+            return None;
+        }
+        self.row += 1;
+        Some(self.row)
+    }
+
+    // The row after the last one read fails, so the report shows where the import stopped
+    fn stop(&mut self) {
+        if !self.stopped {
+            self.stopped = true;
+            self.report
+                .push(failed_row(self.row + 1, payload_too_large()));
+        }
+    }
+
+    // Each row is validated like the body of POST /api/admin/user
+    fn parse(&self, record: &[u8]) -> Result<User, ApiError> {
+        let document = match self.format {
+            ImportFormat::Ndjson => serde_json::from_slice::<Value>(record)
+                .map_err(|err| ApiError::InvalidBody(err.to_string()))?,
+            ImportFormat::Csv => {
+                let header = self.header.as_deref().unwrap_or_default();
+                let values = parse_csv(record).map_err(ApiError::InvalidBody)?;
+                if values.len() != header.len() {
+                    return Err(ApiError::InvalidBody(format!(
+                        "The row has {} columns, the header has {}",
+                        values.len(),
+                        header.len()
+                    )));
+                }
+                let fields: Map<String, Value> = header
+                    .iter()
+                    .cloned()
+                    .zip(values.into_iter().map(Value::String))
+                    .collect();
+                Value::Object(fields)
+            }
+        };
+        let new_user: CreateUserRequest =
+            serde_json::from_value(document).map_err(|err| ApiError::from_data_error(&err))?;
+        new_user.validate()?;
+        Ok(User {
+            id: None,
+            name: new_user.name,
+            location: new_user.location,
+            title: new_user.title,
+            version: 0,
+        })
+    }
+
+    async fn flush(&mut self) {
+        let batch = std::mem::take(&mut self.batch);
+        let (rows, users): (Vec<usize>, Vec<User>) = batch.into_iter().unzip();
+        let results = match self.app_data.db.create_users(users.clone()).await {
+            Ok(results) => results
+                .into_iter()
+                .map(|result| result.map_err(ApiError::from))
+                .collect(),
+            // The whole batch failed, e.g. the database is unavailable
+            Err(err) => {
+                let err = ApiError::from(err);
+                rows.iter().map(|_| Err(err.clone())).collect::<Vec<_>>()
+            }
+        };
+        // Each created user has its own record, sharing the request id of the import, they are written together
+        let mut audits = vec![];
+        for ((row, user), result) in rows.into_iter().zip(users).zip(results) {
+            self.report.push(match result {
+                Ok(created) => {
+                    audits.push(
+                        AuditRecord::new(self.principal, AuditAction::UserImport, self.request_id)
+                            .target(created.id.as_str())
+                            .after(&user),
+                    );
+                    ImportRow {
+                        row,
+                        status: ImportRowStatus::Created,
+                        id: Some(created.id),
+                        errors: vec![],
+                    }
+                }
+                Err(err) => failed_row(row, err),
+            });
+        }
+        record_audits(self.app_data, audits).await;
+    }
+
+    async fn finish(mut self) -> ImportReport {
+        if !self.batch.is_empty() {
+            self.flush().await;
+        }
+        // Invalid rows are reported right away, the valid ones once their batch is written
+        self.report.rows.sort_by_key(|row| row.row);
+        self.report
+    }
+}
+
+fn failed_row(row: usize, err: ApiError) -> ImportRow {
+    ImportRow {
+        row,
+        status: ImportRowStatus::Failed,
+        id: None,
+        errors: err.into_field_errors(),
+    }
+}
+
+fn parse_csv(record: &[u8]) -> Result<Vec<String>, String> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .from_reader(record);
+    match reader.records().next() {
+        Some(Ok(values)) => Ok(values.iter().map(str::to_string).collect()),
+        Some(Err(err)) => Err(err.to_string()),
+        None => Ok(vec![]),
+    }
+}
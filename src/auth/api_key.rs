@@ -1,7 +1,121 @@
+use crate::auth::{error::ClientError, principal::Principal};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
 
 #[derive(Debug, Default, Deserialize, Serialize, Clone)]
 pub struct ApiKeyData {
-    pub api_key: String,
     pub enable_api_key: bool,
+    // Secret mixed into every hash, kept out of the stored keys, so a leaked key list can't be brute-forced alone
+    #[serde(default)]
+    pub pepper: String,
+    #[serde(default)]
+    pub keys: Vec<ApiKeyEntry>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ApiKeyEntry {
+    pub name: String,
+    pub salt: String,
+    // Hex encoded SHA-256 of pepper + salt + key, see hash_api_key
+    pub hash: String,
+    #[serde(default)]
+    pub permissions: Vec<String>,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+pub fn hash_api_key(pepper: &str, salt: &str, api_key: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(pepper.as_bytes());
+    hasher.update(salt.as_bytes());
+    hasher.update(api_key.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+impl ApiKeyEntry {
+    fn matches(&self, pepper: &str, api_key: &str) -> bool {
+        let hash = hash_api_key(pepper, &self.salt, api_key);
+        hash.as_bytes()
+            .ct_eq(self.hash.to_lowercase().as_bytes())
+            .into()
+    }
+
+    fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.enabled && self.expires_at.is_none_or(|expires_at| now < expires_at)
+    }
+
+    pub fn authenticate(&self, pepper: &str, api_key: &str) -> Result<Principal, ClientError> {
+        if !self.matches(pepper, api_key) {
+            return Err(ClientError::InvalidApiKey(
+                "API key is not valid".to_string(),
+            ));
+        }
+        self.principal()
+    }
+
+    fn principal(&self) -> Result<Principal, ClientError> {
+        if !self.is_active(Utc::now()) {
+            return Err(ClientError::InvalidApiKey(format!(
+                "API key {} is disabled or expired",
+                self.name
+            )));
+        }
+        Ok(Principal::api_key(
+            &self.name,
+            self.permissions.iter().cloned().collect(),
+        ))
+    }
+}
+
+impl ApiKeyData {
+    // Every key is hashed and compared, so the time taken doesn't reveal which one matched
+    pub fn authenticate(&self, api_key: &str) -> Result<Principal, ClientError> {
+        let matched = self.keys.iter().fold(None, |matched, entry| {
+            match entry.matches(&self.pepper, api_key) {
+                true => Some(entry),
+                false => matched,
+            }
+        });
+        match matched {
+            Some(entry) => entry.principal(),
+            None => Err(ClientError::InvalidApiKey(
+                "API key is not valid".to_string(),
+            )),
+        }
+    }
+}
+
+// Keys created at runtime look like rcw_<key id>_<secret>, the key id is used to find the stored hash, so
+// only a single hash is computed per request
+pub const API_KEY_PREFIX: &str = "rcw_";
+
+pub fn parse_api_key(api_key: &str) -> Option<&str> {
+    let (key_id, secret) = api_key.strip_prefix(API_KEY_PREFIX)?.split_once('_')?;
+    if key_id.is_empty() || secret.is_empty() {
+        return None;
+    }
+    Some(key_id)
+}
+
+pub fn generate_key_id() -> String {
+    Uuid::new_v4().simple().to_string()[..16].to_string()
+}
+
+// Returns the plaintext key, which is only shown to the caller once, rotating a key keeps its key id
+pub fn generate_api_key(key_id: &str) -> String {
+    let secret = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+    format!("{}{}_{}", API_KEY_PREFIX, key_id, secret)
+}
+
+pub fn generate_salt() -> String {
+    Uuid::new_v4().simple().to_string()
 }
@@ -1,45 +1,58 @@
 use std::{
     future::{ready, Ready},
     rc::Rc,
+    sync::Arc,
 };
 
 use crate::auth::{
-    api_key::ApiKeyData,
-    auth0::Auth0Data,
-    claims::{AccessLevel, Claims},
+    api_key::{parse_api_key, ApiKeyData},
+    claims::Permission,
     error::ClientError,
+    principal::Principal,
+    rbac::RoleMap,
+    verifier::TokenVerifier,
 };
+use crate::models::app::AppData;
 use actix_web::{
     body::EitherBody,
     dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
-    http::Uri,
-    Error, FromRequest,
+    web::Data,
+    Error, FromRequest, HttpMessage,
 };
 use actix_web_httpauth::extractors::bearer::BearerAuth;
-use awc::Client;
 use futures_util::future::LocalBoxFuture;
-use jsonwebtoken::{
-    decode, decode_header,
-    jwk::{AlgorithmParameters, JwkSet, RSAKeyParameters},
-    Algorithm, DecodingKey, Validation,
-};
 
 const X_API_KEY: &str = "x-api-key";
 
+// AuthMiddleware authenticates the caller, and stores it as a Principal in the request extensions, so the permissions
+// of each handler can be checked by RequirePermissions
+#[derive(Clone)]
 pub struct AuthMiddleware {
     api_key_data: Rc<ApiKeyData>,
-    auth0_data: Rc<Auth0Data>,
-    access_level: Rc<AccessLevel>,
+    verifier: Arc<dyn TokenVerifier>,
+    roles: Arc<RoleMap>,
+    required_permissions: Rc<Vec<Permission>>,
 }
 
 impl AuthMiddleware {
-    pub fn new(auth_data: ApiKeyData, auth0_data: Auth0Data, access_level: AccessLevel) -> Self {
+    pub fn new(
+        auth_data: ApiKeyData,
+        verifier: Arc<dyn TokenVerifier>,
+        roles: Arc<RoleMap>,
+    ) -> Self {
         AuthMiddleware {
             api_key_data: Rc::new(auth_data),
-            auth0_data: Rc::new(auth0_data),
-            access_level: Rc::new(access_level),
+            verifier,
+            roles,
+            required_permissions: Rc::new(vec![]),
         }
     }
+
+    // Permissions required by every route of the wrapped scope
+    pub fn require(mut self, permissions: &[Permission]) -> Self {
+        self.required_permissions = Rc::new(permissions.to_vec());
+        self
+    }
 }
 
 impl<S, B> Transform<S, ServiceRequest> for AuthMiddleware
@@ -56,9 +69,10 @@ where
 
     fn new_transform(&self, service: S) -> Self::Future {
         ready(Ok(AuthMiddlewareFactory {
-            access_level: self.access_level.clone(),
+            required_permissions: self.required_permissions.clone(),
             api_key_data: self.api_key_data.clone(),
-            auth0_data: self.auth0_data.clone(),
+            verifier: self.verifier.clone(),
+            roles: self.roles.clone(),
             service: Rc::new(service), // convert S to Rc<S>
         }))
     }
@@ -68,8 +82,9 @@ pub struct AuthMiddlewareFactory<S> {
     // service: S,
     service: Rc<S>,
     api_key_data: Rc<ApiKeyData>,
-    auth0_data: Rc<Auth0Data>,
-    access_level: Rc<AccessLevel>,
+    verifier: Arc<dyn TokenVerifier>,
+    roles: Arc<RoleMap>,
+    required_permissions: Rc<Vec<Permission>>,
 }
 
 impl<S, B> Service<ServiceRequest> for AuthMiddlewareFactory<S>
@@ -88,66 +103,57 @@ where
         // Clone the service to keep reference after moving into async block
         let service = Rc::clone(&self.service);
         let api_key_data = self.api_key_data.clone();
-        let auth0_data = self.auth0_data.clone();
-        let access_level = self.access_level.clone();
+        let verifier = self.verifier.clone();
+        let roles = self.roles.clone();
+        let required_permissions = self.required_permissions.clone();
 
         let extractor = BearerAuth::extract(req.request());
 
         Box::pin(async move {
-            let api_key_header = req.headers().get(X_API_KEY);
-
-            // API KEY authentication has ADMIN rights and pass-through Auth0 authentication/authorization
-            if api_key_header.is_none() || api_key_header.unwrap().clone() != api_key_data.api_key {
-                // Using map_err and question mark will propagate errors
-                let credentials = extractor.await.map_err(ClientError::Authentication)?;
-                let token = credentials.token();
-                let header = decode_header(token).map_err(ClientError::Decode)?;
-                let kid = header.kid.ok_or_else(|| {
-                    ClientError::NotFound("kid not found in token header".to_string())
-                })?;
-                let jwks: JwkSet = Client::new()
-                    .get(
-                        Uri::builder()
-                            .scheme("https")
-                            .authority(auth0_data.domain.to_string())
-                            .path_and_query("/.well-known/jwks.json")
-                            .build()
-                            .unwrap(),
-                    )
-                    .send()
-                    .await
-                    .map_err(ClientError::SendRequestError)?
-                    .json()
-                    .await
-                    .map_err(ClientError::JsonPayloadError)?;
-                let jwk = jwks
-                    .find(&kid)
-                    .ok_or_else(|| ClientError::NotFound("No JWK found for kid".to_string()))?;
-
-                let rsa = match jwk.clone().algorithm {
-                    AlgorithmParameters::RSA(rsa) => Ok::<RSAKeyParameters, Error>(rsa),
-                    algorithm => Err(ClientError::UnsupportedAlgortithm(algorithm).into()),
-                }?;
-
-                let mut validation = Validation::new(Algorithm::RS256);
-                validation.set_audience(&[auth0_data.audience.to_string()]);
-                validation.set_issuer(&[Uri::builder()
-                    .scheme("https")
-                    .authority(auth0_data.domain.to_string())
-                    .path_and_query("/")
-                    .build()
-                    .unwrap()]);
-                let key = DecodingKey::from_rsa_components(&rsa.n, &rsa.e)
-                    .map_err(ClientError::Decode)?;
-                let token =
-                    decode::<Claims>(token, &key, &validation).map_err(ClientError::Decode)?;
-                // In this part, we are going to validate, if the user has the right permissions to access this endpoint
-                // be sure, to update your Auth0 API, to include permissions on your JWT access token
-                match token.claims.validate_permissions(access_level.to_string()) {
-                    false => Err(ClientError::NoPermission(access_level.to_string())),
-                    true => Ok(()),
-                }?;
+            let api_key_header = req
+                .headers()
+                .get(X_API_KEY)
+                .filter(|_| api_key_data.enable_api_key);
+
+            // API keys carry their own permissions, and take precedence over the bearer token
+            let principal = match api_key_header {
+                Some(api_key) => {
+                    let api_key = api_key.to_str().map_err(|_| {
+                        ClientError::InvalidApiKey("API key is not valid".to_string())
+                    })?;
+                    match (parse_api_key(api_key), req.app_data::<Data<AppData>>()) {
+                        // Keys created through the admin API, revoked keys are rejected right away
+                        (Some(key_id), Some(app_data)) => app_data
+                            .api_keys
+                            .get_api_key(key_id)
+                            .await
+                            .map_err(|err| ClientError::ApiKeyStore(err.to_string()))?
+                            .ok_or_else(|| {
+                                ClientError::InvalidApiKey("API key is not valid".to_string())
+                            })?
+                            .authenticate(&api_key_data.pepper, api_key)?,
+                        // Keys listed in the configuration
+                        _ => api_key_data.authenticate(api_key)?,
+                    }
+                }
+                None => {
+                    // Using map_err and question mark will propagate errors
+                    let credentials = extractor.await.map_err(ClientError::Authentication)?;
+                    // The verifier selected in the configuration checks the signature, issuer and audience
+                    let mut token = verifier.verify(credentials.token()).await?;
+                    // Permissions can also come from the roles of the user, mapped in the configuration
+                    roles.apply(&mut token.claims);
+                    Principal::from_claims(token.claims, token.header.kid)
+                }
+            };
+            // In this part, we are going to validate, if the user has the right permissions to access this scope
+            // be sure, to update your IdP API (e.g. Auth0), to include permissions on your JWT access token
+            if !principal.validate_permissions(&required_permissions) {
+                return Err(
+                    ClientError::NoPermission(format_permissions(&required_permissions)).into(),
+                );
             }
+            req.extensions_mut().insert(principal);
 
             // Continue with the next middleware / handler
             let res = service.call(req).await?;
@@ -157,6 +163,10 @@ where
     }
 }
 
-// async fn get_some_data() -> String {
-//     "Data".into()
-// }
+pub(crate) fn format_permissions(permissions: &[Permission]) -> String {
+    permissions
+        .iter()
+        .map(Permission::to_string)
+        .collect::<Vec<String>>()
+        .join(", ")
+}
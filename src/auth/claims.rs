@@ -1,27 +1,79 @@
-use crate::auth::claims::AccessLevel::Write;
+use crate::auth::claims::AccessLevel::{Read, Write};
 use serde::Deserialize;
-use std::collections::HashSet;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
 
-#[derive(strum_macros::Display, Debug)]
+#[derive(strum_macros::Display, Debug, Clone, Copy, PartialEq)]
 pub enum AccessLevel {
     Write,
-    #[allow(unused)]
     Read,
 }
 
+// Fine-grained permissions required by each handler, the names match the permissions of the IdP API
+#[derive(strum_macros::Display, Debug, Clone, Copy, PartialEq)]
+pub enum Permission {
+    #[strum(serialize = "users:create")]
+    UsersCreate,
+    #[strum(serialize = "users:read")]
+    UsersRead,
+    #[strum(serialize = "users:update")]
+    UsersUpdate,
+    #[strum(serialize = "users:delete")]
+    UsersDelete,
+    #[strum(serialize = "api_keys:manage")]
+    ApiKeysManage,
+    #[strum(serialize = "audit:read")]
+    AuditRead,
+}
+
+impl Permission {
+    // The coarse access level that also grants this permission
+    pub fn access_level(&self) -> AccessLevel {
+        match self {
+            Permission::UsersRead => Read,
+            _ => Write,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Claims {
+    pub(crate) sub: Option<String>,
+    pub(crate) iss: Option<String>,
     pub(crate) permissions: Option<HashSet<String>>,
+    // Every other claim of the token, like the registered ones and custom/namespaced ones
+    #[serde(flatten)]
+    pub(crate) extra: HashMap<String, Value>,
 }
 
 impl Claims {
-    pub fn validate_permissions(&self, required_access_level: String) -> bool {
-        self.permissions
-            .as_ref()
-            .is_some_and(|existing_permissions| {
-                // Users with WRITE permission, also have READ
-                existing_permissions.contains(&required_access_level)
-                    || existing_permissions.contains(&Write.to_string())
-            })
+    pub fn has_claim(&self, name: &str) -> bool {
+        match name {
+            "sub" => self.sub.is_some(),
+            "iss" => self.iss.is_some(),
+            "permissions" => self.permissions.is_some(),
+            _ => self.extra.contains_key(name),
+        }
     }
 }
+
+pub(crate) fn has_permissions(
+    existing_permissions: &HashSet<String>,
+    required_permissions: &[Permission],
+) -> bool {
+    required_permissions.iter().all(|permission| {
+        // Users with WRITE permission, also have READ
+        existing_permissions.contains(&permission.to_string())
+            || existing_permissions.contains(&Write.to_string())
+            || (permission.access_level() == Read
+                && existing_permissions.contains(&Read.to_string()))
+    })
+}
+
+// Whether the permissions include another one given by name, e.g. one requested for a new API key
+pub(crate) fn grants_permission(existing_permissions: &HashSet<String>, permission: &str) -> bool {
+    existing_permissions.contains(permission)
+        || existing_permissions.contains(&Write.to_string())
+        || (existing_permissions.contains(&Read.to_string())
+            && permission == Permission::UsersRead.to_string())
+}
@@ -0,0 +1,185 @@
+use crate::auth::error::ClientError;
+use actix_web::http::header::CACHE_CONTROL;
+use awc::Client;
+use jsonwebtoken::jwk::{Jwk, JwkSet};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct JwksCacheData {
+    // Used when the IdP response doesn't carry a Cache-Control max-age
+    pub default_max_age_secs: u64,
+    // Lower bound between two fetches, protects the IdP from a flood of unknown kids
+    pub min_refresh_interval_secs: u64,
+}
+
+impl Default for JwksCacheData {
+    fn default() -> Self {
+        JwksCacheData {
+            default_max_age_secs: 600,
+            min_refresh_interval_secs: 30,
+        }
+    }
+}
+
+#[derive(Default)]
+struct CachedJwks {
+    // Last key set successfully fetched, kept even after expiring, so we can keep serving it when the IdP is down
+    jwks: Option<JwkSet>,
+    expires_at: Option<Instant>,
+    last_fetch: Option<Instant>,
+}
+
+// JwksCache is shared by all the workers, so it needs to be thread safe, the awc Client isn't, that's why we
+// create one per fetch, which only happens when the cache expires or an unknown kid shows up
+pub struct JwksCache {
+    jwks_uri: String,
+    default_max_age: Duration,
+    min_refresh_interval: Duration,
+    state: RwLock<CachedJwks>,
+    // Serializes fetches, so concurrent requests hitting an expired cache only trigger one download
+    fetch_lock: tokio::sync::Mutex<()>,
+}
+
+impl JwksCache {
+    pub fn new(jwks_uri: String, cache_data: &JwksCacheData) -> Self {
+        JwksCache {
+            jwks_uri,
+            default_max_age: Duration::from_secs(cache_data.default_max_age_secs),
+            min_refresh_interval: Duration::from_secs(cache_data.min_refresh_interval_secs),
+            state: RwLock::new(CachedJwks::default()),
+            fetch_lock: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    pub async fn find(&self, kid: &str) -> Result<Jwk, ClientError> {
+        let (jwk, expired) = self.lookup(kid);
+        if let (Some(jwk), false) = (&jwk, expired) {
+            return Ok(jwk.clone());
+        }
+
+        // The key set expired or doesn't know this kid, the last one may have been rotated on the IdP side
+        let refresh_result = if expired || self.can_refetch() {
+            self.refresh().await
+        } else {
+            Ok(())
+        };
+
+        match (self.lookup(kid).0, refresh_result) {
+            (Some(jwk), _) => Ok(jwk),
+            (None, Err(err)) if !self.has_keys() => Err(err),
+            (None, _) => Err(ClientError::NotFound("No JWK found for kid".to_string())),
+        }
+    }
+
+    // Downloads the key set, keeping the last good one in case of failure
+    pub async fn refresh(&self) -> Result<(), ClientError> {
+        let last_fetch = self.state.read().unwrap().last_fetch;
+        let _guard = self.fetch_lock.lock().await;
+        if self.state.read().unwrap().last_fetch != last_fetch {
+            // Another request fetched the key set while we were waiting for the lock
+            return Ok(());
+        }
+
+        let fetch_result = self.fetch().await;
+        let mut state = self.state.write().unwrap();
+        let now = Instant::now();
+        state.last_fetch = Some(now);
+        match fetch_result {
+            Ok((jwks, max_age)) => {
+                state.jwks = Some(jwks);
+                state.expires_at = Some(now + max_age);
+                Ok(())
+            }
+            Err(err) => {
+                // Retry only after the refresh interval, instead of hammering the IdP on every request
+                state.expires_at = Some(now + self.min_refresh_interval);
+                if state.jwks.is_some() {
+                    log::warn!(
+                        "Failed refreshing JWKS from {}, serving the last good key set: {}",
+                        self.jwks_uri,
+                        err
+                    );
+                }
+                Err(err)
+            }
+        }
+    }
+
+    // Background task that keeps the key set warm, so requests rarely have to wait for the IdP
+    pub fn spawn_refresh(self: Arc<Self>) {
+        actix_web::rt::spawn(async move {
+            loop {
+                let _ = self.refresh().await;
+                actix_web::rt::time::sleep(self.time_to_expire()).await;
+            }
+        });
+    }
+
+    fn time_to_expire(&self) -> Duration {
+        self.state
+            .read()
+            .unwrap()
+            .expires_at
+            .map(|expires_at| expires_at.saturating_duration_since(Instant::now()))
+            .unwrap_or(self.min_refresh_interval)
+            .max(self.min_refresh_interval)
+    }
+
+    fn lookup(&self, kid: &str) -> (Option<Jwk>, bool) {
+        let state = self.state.read().unwrap();
+        let expired = state
+            .expires_at
+            .is_none_or(|expires_at| Instant::now() >= expires_at);
+        let jwk = state.jwks.as_ref().and_then(|jwks| jwks.find(kid)).cloned();
+        (jwk, expired)
+    }
+
+    fn has_keys(&self) -> bool {
+        self.state.read().unwrap().jwks.is_some()
+    }
+
+    fn can_refetch(&self) -> bool {
+        self.state
+            .read()
+            .unwrap()
+            .last_fetch
+            .is_none_or(|last_fetch| last_fetch.elapsed() >= self.min_refresh_interval)
+    }
+
+    async fn fetch(&self) -> Result<(JwkSet, Duration), ClientError> {
+        let mut response = Client::new()
+            .get(&self.jwks_uri)
+            .send()
+            .await
+            .map_err(ClientError::SendRequestError)?;
+        if !response.status().is_success() {
+            return Err(ClientError::NotFound(format!(
+                "JWKS endpoint answered with {}",
+                response.status()
+            )));
+        }
+        let max_age = response
+            .headers()
+            .get(CACHE_CONTROL)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_max_age)
+            .unwrap_or(self.default_max_age);
+        let jwks: JwkSet = response
+            .json()
+            .await
+            .map_err(ClientError::JsonPayloadError)?;
+        Ok((jwks, max_age))
+    }
+}
+
+pub(crate) fn parse_max_age(cache_control: &str) -> Option<Duration> {
+    cache_control
+        .split(',')
+        .filter_map(|directive| directive.trim().split_once('='))
+        .find(|(name, _)| name.eq_ignore_ascii_case("max-age"))
+        .and_then(|(_, value)| value.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+}
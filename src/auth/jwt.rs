@@ -0,0 +1,22 @@
+use crate::auth::verifier::VerifierData;
+use serde::{Deserialize, Serialize};
+
+// An issuer we trust, tokens are matched against the profiles by their iss claim
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct IssuerProfile {
+    // For Auth0 tenants it's https://{domain}/
+    pub issuer: String,
+    // A token is accepted if its aud claim contains any of these
+    pub audiences: Vec<String>,
+    // Clock skew tolerated on exp and nbf
+    #[serde(default = "default_leeway_secs")]
+    pub leeway_secs: u64,
+    // Claims that must be present on the token, besides exp
+    #[serde(default)]
+    pub required_claims: Vec<String>,
+    pub verifier: VerifierData,
+}
+
+fn default_leeway_secs() -> u64 {
+    60
+}
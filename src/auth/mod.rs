@@ -1,5 +1,11 @@
 pub mod api_key;
-pub mod auth0;
 pub mod auth_middleware;
 pub mod claims;
-mod error;
+pub(crate) mod error;
+pub mod jwks;
+pub mod jwt;
+pub mod permission_middleware;
+pub mod principal;
+pub mod rbac;
+mod tests;
+pub mod verifier;
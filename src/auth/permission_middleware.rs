@@ -0,0 +1,87 @@
+use std::{
+    future::{ready, Ready},
+    rc::Rc,
+};
+
+use crate::auth::{
+    auth_middleware::format_permissions, claims::Permission, error::ClientError,
+    principal::Principal,
+};
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    Error, HttpMessage,
+};
+use futures_util::future::LocalBoxFuture;
+
+// RequirePermissions declares the permissions of a single handler, e.g.
+// #[post("/user", wrap = "RequirePermissions::new(&[Permission::UsersCreate])")]
+// It relies on the Principal stored by AuthMiddleware, so the handler must be inside a scope wrapped by it
+pub struct RequirePermissions {
+    permissions: Rc<Vec<Permission>>,
+}
+
+impl RequirePermissions {
+    pub fn new(permissions: &[Permission]) -> Self {
+        RequirePermissions {
+            permissions: Rc::new(permissions.to_vec()),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequirePermissions
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RequirePermissionsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequirePermissionsMiddleware {
+            permissions: self.permissions.clone(),
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct RequirePermissionsMiddleware<S> {
+    service: Rc<S>,
+    permissions: Rc<Vec<Permission>>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequirePermissionsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let permissions = self.permissions.clone();
+
+        Box::pin(async move {
+            let allowed = match req.extensions().get::<Principal>() {
+                Some(principal) => principal.validate_permissions(&permissions),
+                // AuthMiddleware didn't run, so we don't know who the caller is
+                None => return Err(ClientError::MissingCredentials.into()),
+            };
+            if !allowed {
+                return Err(ClientError::NoPermission(format_permissions(&permissions)).into());
+            }
+
+            let res = service.call(req).await?;
+            Ok(res.map_into_left_body())
+        })
+    }
+}
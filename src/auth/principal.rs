@@ -0,0 +1,72 @@
+use crate::auth::{
+    claims::{grants_permission, has_permissions, Claims, Permission},
+    error::ClientError,
+};
+use actix_web::{dev::Payload, FromRequest, HttpMessage, HttpRequest};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::future::{ready, Ready};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMethod {
+    ApiKey,
+    Jwt,
+}
+
+// Principal is the authenticated caller, stored by AuthMiddleware in the request extensions, so handlers and
+// other middlewares can know who is acting, e.g. for auditing and ownership checks
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Principal {
+    pub subject: String,
+    pub issuer: Option<String>,
+    pub permissions: HashSet<String>,
+    pub auth_method: AuthMethod,
+    // kid of the key that signed the token, or the identifier of the API key
+    pub key_id: Option<String>,
+}
+
+impl Principal {
+    pub fn from_claims(claims: Claims, key_id: Option<String>) -> Self {
+        Principal {
+            subject: claims.sub.unwrap_or_default(),
+            issuer: claims.iss,
+            permissions: claims.permissions.unwrap_or_default(),
+            auth_method: AuthMethod::Jwt,
+            key_id,
+        }
+    }
+
+    pub fn api_key(name: &str, permissions: HashSet<String>) -> Self {
+        Principal {
+            subject: name.to_string(),
+            issuer: None,
+            permissions,
+            auth_method: AuthMethod::ApiKey,
+            key_id: Some(name.to_string()),
+        }
+    }
+
+    pub fn validate_permissions(&self, required_permissions: &[Permission]) -> bool {
+        has_permissions(&self.permissions, required_permissions)
+    }
+
+    pub fn grants(&self, permission: &str) -> bool {
+        grants_permission(&self.permissions, permission)
+    }
+}
+
+impl FromRequest for Principal {
+    type Error = ClientError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        // Only present on routes wrapped by AuthMiddleware, use Option<Principal> on routes where it's optional
+        ready(
+            req.extensions()
+                .get::<Principal>()
+                .cloned()
+                .ok_or(ClientError::MissingCredentials),
+        )
+    }
+}
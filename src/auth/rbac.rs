@@ -0,0 +1,116 @@
+use crate::auth::{claims::Claims, error::ClientError};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RbacData {
+    // Claim holding the roles of the user, Auth0 requires custom claims to be namespaced, e.g. https://my-app/roles
+    #[serde(default = "default_roles_claim")]
+    pub roles_claim: String,
+    #[serde(default)]
+    pub roles: HashMap<String, RoleData>,
+}
+
+impl Default for RbacData {
+    fn default() -> Self {
+        RbacData {
+            roles_claim: default_roles_claim(),
+            roles: HashMap::new(),
+        }
+    }
+}
+
+fn default_roles_claim() -> String {
+    "roles".to_string()
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub struct RoleData {
+    // Roles whose permissions are included in this one, e.g. admin inherits editor, that inherits viewer
+    #[serde(default)]
+    pub inherits: Vec<String>,
+    #[serde(default)]
+    pub permissions: Vec<String>,
+}
+
+// Role -> permissions mapping, with the hierarchy already flattened, so requests only do a lookup
+#[derive(Debug, Default)]
+pub struct RoleMap {
+    roles_claim: String,
+    permissions: HashMap<String, HashSet<String>>,
+}
+
+impl RoleMap {
+    pub fn resolve(rbac_data: &RbacData) -> Result<Self, ClientError> {
+        let mut permissions = HashMap::new();
+        for role in rbac_data.roles.keys() {
+            let mut visiting = Vec::new();
+            resolve_role(role, rbac_data, &mut permissions, &mut visiting)?;
+        }
+        Ok(RoleMap {
+            roles_claim: rbac_data.roles_claim.to_string(),
+            permissions,
+        })
+    }
+
+    pub fn permissions(&self, role: &str) -> Option<&HashSet<String>> {
+        self.permissions.get(role)
+    }
+
+    // Adds the permissions granted by the roles of the token to its permissions claim. Unknown roles are ignored,
+    // as the IdP may assign roles that are meaningful to other applications only
+    pub fn apply(&self, claims: &mut Claims) {
+        let roles: Vec<String> = match claims.extra.get(&self.roles_claim) {
+            Some(Value::String(role)) => vec![role.to_string()],
+            Some(Value::Array(roles)) => roles
+                .iter()
+                .filter_map(|role| role.as_str().map(str::to_string))
+                .collect(),
+            _ => return,
+        };
+        let granted: Vec<&String> = roles
+            .iter()
+            .filter_map(|role| self.permissions(role))
+            .flatten()
+            .collect();
+        if granted.is_empty() {
+            return;
+        }
+        claims
+            .permissions
+            .get_or_insert_with(HashSet::new)
+            .extend(granted.into_iter().cloned());
+    }
+}
+
+fn resolve_role(
+    role: &str,
+    rbac_data: &RbacData,
+    resolved: &mut HashMap<String, HashSet<String>>,
+    visiting: &mut Vec<String>,
+) -> Result<HashSet<String>, ClientError> {
+    if let Some(permissions) = resolved.get(role) {
+        return Ok(permissions.clone());
+    }
+    if visiting.iter().any(|visited| visited == role) {
+        visiting.push(role.to_string());
+        return Err(ClientError::InvalidConfiguration(format!(
+            "roles inherit each other in a cycle: {}",
+            visiting.join(" -> ")
+        )));
+    }
+    let role_data = rbac_data.roles.get(role).ok_or_else(|| {
+        ClientError::InvalidConfiguration(format!("inherited role {} is not defined", role))
+    })?;
+
+    visiting.push(role.to_string());
+    let mut permissions: HashSet<String> = role_data.permissions.iter().cloned().collect();
+    for parent in &role_data.inherits {
+        permissions.extend(resolve_role(parent, rbac_data, resolved, visiting)?);
+    }
+    visiting.pop();
+
+    resolved.insert(role.to_string(), permissions.clone());
+    Ok(permissions)
+}
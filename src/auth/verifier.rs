@@ -0,0 +1,297 @@
+use crate::auth::{
+    claims::Claims,
+    error::ClientError,
+    jwks::{JwksCache, JwksCacheData},
+    jwt::IssuerProfile,
+};
+use async_trait::async_trait;
+use jsonwebtoken::{
+    decode, decode_header, jwk::AlgorithmParameters, Algorithm, DecodingKey, TokenData, Validation,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// Asymmetric algorithms we accept from a JWKS, HMAC is left out on purpose, so a public key can never be used
+// as a shared secret (algorithm confusion)
+const JWKS_ALGORITHMS: [Algorithm; 9] = [
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::EdDSA,
+];
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum VerifierData {
+    // Keys published by the issuer, for Auth0 it's https://{domain}/.well-known/jwks.json
+    Jwks {
+        jwks_uri: String,
+        #[serde(default = "default_jwks_algorithms")]
+        algorithms: Vec<Algorithm>,
+        #[serde(default)]
+        cache: JwksCacheData,
+    },
+    // A single public key, for issuers that don't publish a JWKS
+    Pem {
+        algorithm: Algorithm,
+        public_key: String,
+    },
+    // HS256 tokens signed with a secret shared with the issuer
+    Secret {
+        secret: String,
+    },
+}
+
+impl Default for VerifierData {
+    fn default() -> Self {
+        VerifierData::Jwks {
+            jwks_uri: String::new(),
+            algorithms: default_jwks_algorithms(),
+            cache: JwksCacheData::default(),
+        }
+    }
+}
+
+fn default_jwks_algorithms() -> Vec<Algorithm> {
+    vec![Algorithm::RS256]
+}
+
+// TokenVerifier checks the signature and the standard claims of a bearer token. The futures aren't Send, because
+// the JWKS implementation uses the awc Client, which is bound to the worker thread
+#[async_trait(?Send)]
+pub trait TokenVerifier: Send + Sync {
+    async fn verify(&self, token: &str) -> Result<TokenData<Claims>, ClientError>;
+}
+
+// Builds one verifier per trusted issuer. The JWKS background refresh is started here, so this must be called
+// from within the actix runtime
+pub fn build_verifier(issuers: &[IssuerProfile]) -> Result<Arc<dyn TokenVerifier>, ClientError> {
+    let mut verifiers = HashMap::new();
+    for profile in issuers {
+        if verifiers
+            .insert(profile.issuer.to_string(), build_issuer_verifier(profile)?)
+            .is_some()
+        {
+            return Err(ClientError::InvalidConfiguration(format!(
+                "issuer {} is configured more than once",
+                profile.issuer
+            )));
+        }
+    }
+    Ok(Arc::new(MultiIssuerVerifier { verifiers }))
+}
+
+fn build_issuer_verifier(profile: &IssuerProfile) -> Result<Arc<dyn TokenVerifier>, ClientError> {
+    let verifier: Arc<dyn TokenVerifier> = match &profile.verifier {
+        VerifierData::Jwks {
+            jwks_uri,
+            algorithms,
+            cache,
+        } => {
+            let jwks_cache = Arc::new(JwksCache::new(jwks_uri.to_string(), cache));
+            jwks_cache.clone().spawn_refresh();
+            Arc::new(JwksVerifier::new(profile, jwks_cache, algorithms.clone())?)
+        }
+        VerifierData::Pem {
+            algorithm,
+            public_key,
+        } => Arc::new(StaticKeyVerifier::new(profile, *algorithm, public_key)?),
+        VerifierData::Secret { secret } => Arc::new(SharedSecretVerifier::new(profile, secret)),
+    };
+    Ok(verifier)
+}
+
+// Claims every profile checks, regardless of where the key comes from
+#[derive(Clone)]
+struct ClaimsValidation {
+    validation: Validation,
+    required_claims: Vec<String>,
+}
+
+impl ClaimsValidation {
+    fn new(profile: &IssuerProfile) -> Self {
+        let mut validation = Validation::default();
+        validation.set_audience(&profile.audiences);
+        validation.set_issuer(&[profile.issuer.to_string()]);
+        validation.leeway = profile.leeway_secs;
+        ClaimsValidation {
+            validation,
+            required_claims: profile.required_claims.clone(),
+        }
+    }
+
+    fn decode(
+        &self,
+        token: &str,
+        key: &DecodingKey,
+        algorithm: Algorithm,
+    ) -> Result<TokenData<Claims>, ClientError> {
+        let mut validation = self.validation.clone();
+        validation.algorithms = vec![algorithm];
+        let token_data = decode::<Claims>(token, key, &validation).map_err(ClientError::Decode)?;
+        match self
+            .required_claims
+            .iter()
+            .find(|name| !token_data.claims.has_claim(name))
+        {
+            Some(name) => Err(ClientError::NotFound(format!(
+                "{} claim not found in token",
+                name
+            ))),
+            None => Ok(token_data),
+        }
+    }
+}
+
+// Picks the profile by the token's iss claim, so tokens of several tenants or IdPs are accepted at the same time.
+// The claim is read before checking the signature, but it's only used to choose the key, the chosen profile
+// verifies everything else
+pub struct MultiIssuerVerifier {
+    verifiers: HashMap<String, Arc<dyn TokenVerifier>>,
+}
+
+#[derive(Deserialize)]
+struct UnverifiedClaims {
+    iss: Option<String>,
+}
+
+fn unverified_issuer(token: &str) -> Result<String, ClientError> {
+    let mut validation = Validation::default();
+    validation.insecure_disable_signature_validation();
+    validation.validate_exp = false;
+    validation.required_spec_claims.clear();
+    decode::<UnverifiedClaims>(token, &DecodingKey::from_secret(&[]), &validation)
+        .map_err(ClientError::Decode)?
+        .claims
+        .iss
+        .ok_or_else(|| ClientError::NotFound("iss claim not found in token".to_string()))
+}
+
+#[async_trait(?Send)]
+impl TokenVerifier for MultiIssuerVerifier {
+    async fn verify(&self, token: &str) -> Result<TokenData<Claims>, ClientError> {
+        let issuer = unverified_issuer(token)?;
+        let verifier = self
+            .verifiers
+            .get(&issuer)
+            .ok_or_else(|| ClientError::NotFound(format!("Untrusted token issuer {}", issuer)))?;
+        verifier.verify(token).await
+    }
+}
+
+pub struct JwksVerifier {
+    validation: ClaimsValidation,
+    jwks_cache: Arc<JwksCache>,
+    algorithms: Vec<Algorithm>,
+}
+
+impl JwksVerifier {
+    pub fn new(
+        profile: &IssuerProfile,
+        jwks_cache: Arc<JwksCache>,
+        algorithms: Vec<Algorithm>,
+    ) -> Result<Self, ClientError> {
+        if let Some(algorithm) = algorithms
+            .iter()
+            .find(|algorithm| !JWKS_ALGORITHMS.contains(algorithm))
+        {
+            return Err(ClientError::UnsupportedAlgorithm(*algorithm));
+        }
+        Ok(JwksVerifier {
+            validation: ClaimsValidation::new(profile),
+            jwks_cache,
+            algorithms,
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl TokenVerifier for JwksVerifier {
+    async fn verify(&self, token: &str) -> Result<TokenData<Claims>, ClientError> {
+        let header = decode_header(token).map_err(ClientError::Decode)?;
+        if !self.algorithms.contains(&header.alg) {
+            return Err(ClientError::UnsupportedAlgorithm(header.alg));
+        }
+        let kid = header
+            .kid
+            .ok_or_else(|| ClientError::NotFound("kid not found in token header".to_string()))?;
+        // The key set is cached and refreshed in the background, see JwksCache
+        let jwk = self.jwks_cache.find(&kid).await?;
+
+        // A key pinned to an algorithm by the IdP, can't be used with another one
+        if jwk.common.algorithm.is_some_and(|alg| alg != header.alg)
+            || matches!(jwk.algorithm, AlgorithmParameters::OctetKey(_))
+        {
+            return Err(ClientError::UnsupportedAlgorithm(header.alg));
+        }
+        let key = DecodingKey::from_jwk(&jwk).map_err(ClientError::Decode)?;
+        self.validation.decode(token, &key, header.alg)
+    }
+}
+
+pub struct StaticKeyVerifier {
+    validation: ClaimsValidation,
+    key: DecodingKey,
+    algorithm: Algorithm,
+}
+
+impl StaticKeyVerifier {
+    pub fn new(
+        profile: &IssuerProfile,
+        algorithm: Algorithm,
+        public_key: &str,
+    ) -> Result<Self, ClientError> {
+        let pem = public_key.as_bytes();
+        let key = match algorithm {
+            Algorithm::RS256
+            | Algorithm::RS384
+            | Algorithm::RS512
+            | Algorithm::PS256
+            | Algorithm::PS384
+            | Algorithm::PS512 => DecodingKey::from_rsa_pem(pem),
+            Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_pem(pem),
+            Algorithm::EdDSA => DecodingKey::from_ed_pem(pem),
+            algorithm => return Err(ClientError::UnsupportedAlgorithm(algorithm)),
+        }
+        .map_err(ClientError::Decode)?;
+        Ok(StaticKeyVerifier {
+            validation: ClaimsValidation::new(profile),
+            key,
+            algorithm,
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl TokenVerifier for StaticKeyVerifier {
+    async fn verify(&self, token: &str) -> Result<TokenData<Claims>, ClientError> {
+        self.validation.decode(token, &self.key, self.algorithm)
+    }
+}
+
+pub struct SharedSecretVerifier {
+    validation: ClaimsValidation,
+    key: DecodingKey,
+}
+
+impl SharedSecretVerifier {
+    pub fn new(profile: &IssuerProfile, secret: &str) -> Self {
+        SharedSecretVerifier {
+            validation: ClaimsValidation::new(profile),
+            key: DecodingKey::from_secret(secret.as_bytes()),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl TokenVerifier for SharedSecretVerifier {
+    async fn verify(&self, token: &str) -> Result<TokenData<Claims>, ClientError> {
+        self.validation.decode(token, &self.key, Algorithm::HS256)
+    }
+}
@@ -1,6 +1,9 @@
-use crate::auth::{api_key::ApiKeyData, auth0::Auth0Data};
+use crate::auth::{api_key::ApiKeyData, jwt::IssuerProfile, rbac::RbacData};
 use crate::configuration::prelude::Result as AppResult;
-use serde::Serialize;
+use crate::database::postgres_repo::PostgresConfig;
+use crate::database::sqlite_repo::SqliteConfig;
+use crate::rate_limit::middleware::RateLimitData;
+use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 use twelf::{config, Layer};
 
@@ -17,13 +20,43 @@ pub fn load_default() -> AppResult<Config> {
     Ok(conf)
 }
 
+// Where the users, API keys and audit records are kept
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Storage {
+    #[default]
+    Mongo,
+    // Nothing to install, e.g. for local development, but the data is lost on restart
+    Memory,
+    Postgres,
+    // A file next to the server, e.g. for single node deployments
+    Sqlite,
+}
+
 #[config]
 #[derive(Debug, Default, Serialize, Clone)]
 pub struct Config {
     pub env: String,
     pub api_key_data: ApiKeyData,
-    pub auth0_data: Auth0Data,
+    pub issuers: Vec<IssuerProfile>,
+    // Requires users:read (or the Read access level) on the public GET endpoints
+    #[serde(default)]
+    pub protect_read_endpoints: bool,
+    #[serde(default)]
+    pub rbac: RbacData,
+    #[serde(default)]
+    pub rate_limit: RateLimitData,
+    #[serde(default)]
+    pub storage: Storage,
+    // Only required with storage: mongo
+    #[serde(default)]
     pub mongo_uri: String,
+    // Only required with storage: postgres
+    #[serde(default)]
+    pub postgres: PostgresConfig,
+    // Only used with storage: sqlite
+    #[serde(default)]
+    pub sqlite: SqliteConfig,
 }
 
 // This struct simulates a S3 bucket, or a data storage, and we are using Arc, because we need multiple ownership,
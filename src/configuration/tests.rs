@@ -1,6 +1,6 @@
 #[cfg(test)]
 mod tests {
-    use crate::configuration::config::{load, Config};
+    use crate::configuration::config::{load, Config, Storage};
     use std::error::Error;
     use std::fs::{remove_file, File};
     use std::io::Write;
@@ -10,8 +10,14 @@ mod tests {
         let config = Config {
             env: "dev".to_string(),
             api_key_data: Default::default(),
-            auth0_data: Default::default(),
+            issuers: vec![],
+            protect_read_endpoints: false,
+            rbac: Default::default(),
+            rate_limit: Default::default(),
+            storage: Storage::Memory,
             mongo_uri: "http://test.com".to_string(),
+            postgres: Default::default(),
+            sqlite: Default::default(),
         };
 
         // Serialize the struct to YAML
@@ -43,6 +49,7 @@ mod tests {
         let config = config_result.unwrap();
         assert_eq!(config.env, "dev");
         assert_eq!(config.mongo_uri, "http://test.com");
+        assert_eq!(config.storage, Storage::Memory);
         delete_config_file();
     }
 }
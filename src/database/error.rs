@@ -1,14 +1,28 @@
-use crate::models::error::ErrorMessage;
-use actix_web::{HttpResponse, ResponseError};
+use crate::api::error::ApiError;
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
 use std::error::Error;
 use std::fmt::{Display, Formatter, Result};
 
 #[derive(Debug)]
 pub enum RepositoryError {
     // Using Trait std::error::Error, because as we are abstracting our database, different implementations of the database trait, may return different errors
-    CreateUpdateUser(Box<dyn Error>),
-    DeleteUser(Box<dyn Error>),
+    CreateUpdateUser(Box<dyn Error + Send + Sync>),
+    DeleteUser(Box<dyn Error + Send + Sync>),
+    CreateUpdateApiKey(Box<dyn Error + Send + Sync>),
+    CreateAuditRecord(Box<dyn Error + Send + Sync>),
     GeneralError(String),
+    // The id isn't valid for the backend, e.g. not an ObjectId in MongoDB
+    InvalidId(String),
+    NotFound(String),
+    // A unique constraint was violated, e.g. a duplicate key
+    Conflict(String),
+    // The user was written since the version the caller expected
+    VersionMismatch(String),
+    // The database couldn't be reached, the request may succeed later
+    Unavailable(String),
+    Timeout(String),
+    // The database can't do what was asked, e.g. a transaction on a standalone MongoDB
+    Unsupported(String),
 }
 
 impl Display for RepositoryError {
@@ -17,28 +31,27 @@ impl Display for RepositoryError {
             Self::CreateUpdateUser(err) => write!(f, "Error saving data to Database: {}", err),
             Self::GeneralError(msg) => write!(f, "{}", msg),
             Self::DeleteUser(err) => write!(f, "Error deleting data in the Database: {}", err),
+            Self::CreateUpdateApiKey(err) => write!(f, "Error saving API key to Database: {}", err),
+            Self::CreateAuditRecord(err) => {
+                write!(f, "Error saving audit record to Database: {}", err)
+            }
+            Self::InvalidId(id) => write!(f, "{} is not a valid id", id),
+            Self::NotFound(msg) | Self::Conflict(msg) | Self::VersionMismatch(msg) => {
+                write!(f, "{}", msg)
+            }
+            Self::Unavailable(msg) => write!(f, "Database unavailable: {}", msg),
+            Self::Timeout(msg) => write!(f, "Database operation timed out: {}", msg),
+            Self::Unsupported(msg) => write!(f, "Not supported by the database: {}", msg),
         }
     }
 }
 
 impl ResponseError for RepositoryError {
+    fn status_code(&self) -> StatusCode {
+        ApiError::from(self).status_code()
+    }
+
     fn error_response(&self) -> HttpResponse {
-        match self {
-            Self::CreateUpdateUser(err) => HttpResponse::InternalServerError().json(ErrorMessage {
-                error: Option::from(err.to_string()),
-                error_description: None,
-                message: "Error when inserting user".to_string(),
-            }),
-            Self::DeleteUser(err) => HttpResponse::InternalServerError().json(ErrorMessage {
-                error: Option::from(err.to_string()),
-                error_description: None,
-                message: "Error when deleting user".to_string(),
-            }),
-            Self::GeneralError(err) => HttpResponse::InternalServerError().json(ErrorMessage {
-                error: Option::from(err.to_string()),
-                error_description: None,
-                message: "General error".to_string(),
-            }),
-        }
+        ApiError::from(self).error_response()
     }
 }
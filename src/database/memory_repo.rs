@@ -0,0 +1,406 @@
+use async_trait::async_trait;
+use futures_util::stream;
+
+use crate::database::error::RepositoryError;
+use crate::database::repository::{ApiKeyRepository, AuditRepository, Repository, UserStream};
+use crate::database::search;
+use crate::models::api_key_model::ApiKey;
+use crate::models::audit_model::{AuditQuery, AuditRecord};
+use crate::models::batch_model::{BulkWriteOptions, UserWrite};
+use crate::models::user_model::{
+    CreateUserResult, DeleteUserResult, UpdateUserResult, User, UserCursor, UserId, UserPage,
+    UserPatch, UserQuery, UserSearchQuery, UserSearchResult, UserSort,
+};
+use std::cmp::{Ordering, Reverse};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+type Users = BTreeMap<u64, User>;
+
+// Keeps everything in the memory of the process, so the server runs without any external service, e.g. with
+// storage: memory in config.yaml. The data is lost on restart. Clones share the same data
+#[derive(Debug, Default, Clone)]
+pub struct InMemoryRepo {
+    // Ordered by id, like the natural order of a collection
+    users: Arc<RwLock<Users>>,
+    // The ids are integers, never reused, even for the users of a rolled back batch
+    last_id: Arc<AtomicU64>,
+    api_keys: Arc<RwLock<Vec<ApiKey>>>,
+    audit: Arc<RwLock<Vec<AuditRecord>>>,
+}
+
+// A writer that panicked can't leave a collection half written, every write is a single insert or removal
+fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn parse_id(id: &UserId) -> Result<u64, RepositoryError> {
+    id.as_str()
+        .parse()
+        .map_err(|_| RepositoryError::InvalidId(id.to_string()))
+}
+
+fn not_found(id: &UserId) -> RepositoryError {
+    RepositoryError::NotFound(format!("No user found with id {}", id))
+}
+
+fn check_version(
+    id: &UserId,
+    user: &User,
+    expected_version: Option<u64>,
+) -> Result<(), RepositoryError> {
+    match expected_version {
+        Some(version) if version != user.version => Err(RepositoryError::VersionMismatch(format!(
+            "User {} is no longer at version {}",
+            id, version
+        ))),
+        _ => Ok(()),
+    }
+}
+
+// The unique index of the other backends, checked under the write lock
+fn check_name(api_keys: &[ApiKey], api_key: &ApiKey) -> Result<(), RepositoryError> {
+    let taken = api_key.entry.enabled
+        && api_keys.iter().any(|stored| {
+            stored.key_id != api_key.key_id
+                && stored.entry.enabled
+                && stored.entry.name == api_key.entry.name
+        });
+    match taken {
+        true => Err(RepositoryError::Conflict(format!(
+            "An API key named {} already exists",
+            api_key.entry.name
+        ))),
+        false => Ok(()),
+    }
+}
+
+fn insert_user(users: &mut Users, last_id: &AtomicU64, new_user: User) -> CreateUserResult {
+    let id = last_id.fetch_add(1, AtomicOrdering::Relaxed) + 1;
+    users.insert(
+        id,
+        User {
+            id: Some(id.into()),
+            version: 1,
+            ..new_user
+        },
+    );
+    CreateUserResult { id: id.into() }
+}
+
+fn update_user(
+    users: &mut Users,
+    id: &UserId,
+    user: User,
+    expected_version: Option<u64>,
+) -> Result<UpdateUserResult, RepositoryError> {
+    let current = users.get_mut(&parse_id(id)?).ok_or_else(|| not_found(id))?;
+    check_version(id, current, expected_version)?;
+    let updated = User {
+        id: current.id.clone(),
+        version: current.version,
+        ..user
+    };
+    let modified = *current != updated;
+    *current = User {
+        version: updated.version + modified as u64,
+        ..updated
+    };
+    Ok(UpdateUserResult {
+        matched_count: 1,
+        modified_count: modified as u64,
+        upserted_id: "".to_string(),
+    })
+}
+
+fn delete_user(
+    users: &mut Users,
+    id: &UserId,
+    expected_version: Option<u64>,
+) -> Result<DeleteUserResult, RepositoryError> {
+    let key = parse_id(id)?;
+    let current = users.get(&key).ok_or_else(|| not_found(id))?;
+    check_version(id, current, expected_version)?;
+    users.remove(&key);
+    Ok(DeleteUserResult { deleted_count: 1 })
+}
+
+fn write_user(
+    users: &mut Users,
+    last_id: &AtomicU64,
+    write: UserWrite,
+) -> Result<UserId, RepositoryError> {
+    match write {
+        UserWrite::Create(user) => Ok(insert_user(users, last_id, user).id),
+        UserWrite::Update(id, user) => update_user(users, &id, user, None).map(|_| id),
+        UserWrite::Delete(id) => delete_user(users, &id, None).map(|_| id),
+    }
+}
+
+fn matches(user: &User, query: &UserQuery) -> bool {
+    query.filters().into_iter().all(|(field, value, prefix)| {
+        let field_value = match field {
+            "name" => &user.name,
+            "location" => &user.location,
+            _ => &user.title,
+        };
+        match prefix {
+            true => field_value.starts_with(value),
+            false => field_value == value,
+        }
+    })
+}
+
+type SortKey = (Option<String>, u64);
+
+// Same order as the sort of MongoRepo, ties are broken by the id
+fn compare(sort: UserSort, a: SortKey, b: SortKey) -> Ordering {
+    match sort.descending {
+        true => b.cmp(&a),
+        false => a.cmp(&b),
+    }
+}
+
+// The ids are numbers, so the users are sorted in the order they were created
+fn sort_key(id: u64, user: &User, sort: UserSort) -> SortKey {
+    (sort.field.value(user), id)
+}
+
+// The users matching the filters of the query, in its order, with their id
+fn find_users(users: &Users, query: &UserQuery) -> Vec<(u64, User)> {
+    let sort = query.sort();
+    let mut found: Vec<(u64, User)> = users
+        .iter()
+        .filter(|(_, user)| matches(user, query))
+        .map(|(id, user)| (*id, user.clone()))
+        .collect();
+    found.sort_by(|(a_id, a), (b_id, b)| {
+        compare(sort, sort_key(*a_id, a, sort), sort_key(*b_id, b, sort))
+    });
+    found
+}
+
+#[async_trait]
+impl Repository for InMemoryRepo {
+    async fn create_user(&self, new_user: User) -> Result<CreateUserResult, RepositoryError> {
+        Ok(insert_user(
+            &mut write(&self.users),
+            &self.last_id,
+            new_user,
+        ))
+    }
+
+    async fn create_users(
+        &self,
+        new_users: Vec<User>,
+    ) -> Result<Vec<Result<CreateUserResult, RepositoryError>>, RepositoryError> {
+        let mut users = write(&self.users);
+        Ok(new_users
+            .into_iter()
+            .map(|new_user| Ok(insert_user(&mut users, &self.last_id, new_user)))
+            .collect())
+    }
+
+    async fn get_user(&self, id: &UserId) -> Result<Option<User>, RepositoryError> {
+        Ok(read(&self.users).get(&parse_id(id)?).cloned())
+    }
+
+    async fn get_users(&self, ids: Vec<UserId>) -> Result<Vec<User>, RepositoryError> {
+        let users = read(&self.users);
+        Ok(ids
+            .iter()
+            .filter_map(|id| users.get(&parse_id(id).ok()?).cloned())
+            .collect())
+    }
+
+    async fn update_user(
+        &self,
+        id: &UserId,
+        user: User,
+        expected_version: Option<u64>,
+    ) -> Result<UpdateUserResult, RepositoryError> {
+        update_user(&mut write(&self.users), id, user, expected_version)
+    }
+
+    async fn delete_user(
+        &self,
+        id: &UserId,
+        expected_version: Option<u64>,
+    ) -> Result<DeleteUserResult, RepositoryError> {
+        delete_user(&mut write(&self.users), id, expected_version)
+    }
+
+    async fn patch_user(
+        &self,
+        id: &UserId,
+        patch: UserPatch,
+        expected_version: Option<u64>,
+    ) -> Result<User, RepositoryError> {
+        let mut users = write(&self.users);
+        let user = users.get_mut(&parse_id(id)?).ok_or_else(|| not_found(id))?;
+        check_version(id, user, expected_version)?;
+        if !patch.is_empty() {
+            user.version += 1;
+        }
+        if let Some(name) = patch.name {
+            user.name = name;
+        }
+        if let Some(location) = patch.location {
+            user.location = location;
+        }
+        if let Some(title) = patch.title {
+            user.title = title;
+        }
+        Ok(user.clone())
+    }
+
+    async fn list_users(&self, query: UserQuery) -> Result<UserPage, RepositoryError> {
+        let after = match &query.after {
+            Some(cursor) => Some((cursor.value.clone(), parse_id(&cursor.id)?)),
+            None => None,
+        };
+        let sort = query.sort();
+        let found = find_users(&read(&self.users), &query);
+        let total = query.count.unwrap_or(false).then_some(found.len() as u64);
+        let limit = query.limit() as usize;
+        let mut users: Vec<User> = found
+            .into_iter()
+            .filter(|(id, user)| {
+                after.as_ref().is_none_or(|after| {
+                    compare(sort, sort_key(*id, user, sort), after.clone()) == Ordering::Greater
+                })
+            })
+            .map(|(_, user)| user)
+            .take(limit + 1)
+            .collect();
+        let next = if users.len() > limit {
+            users.truncate(limit);
+            users.last().and_then(|user| UserCursor::after(user, sort))
+        } else {
+            None
+        };
+        Ok(UserPage { users, next, total })
+    }
+
+    // A snapshot of the users, the lock isn't held while the stream is read
+    async fn stream_users(&self, query: UserQuery) -> Result<UserStream, RepositoryError> {
+        let users = find_users(&read(&self.users), &query);
+        Ok(Box::pin(stream::iter(
+            users.into_iter().map(|(_, user)| Ok(user)),
+        )))
+    }
+
+    async fn search_users(
+        &self,
+        query: UserSearchQuery,
+    ) -> Result<Vec<UserSearchResult>, RepositoryError> {
+        Ok(search::search(read(&self.users).values(), &query))
+    }
+
+    // The writes hold the lock, so no other request sees a batch half written
+    async fn bulk_write(
+        &self,
+        writes: Vec<UserWrite>,
+        options: BulkWriteOptions,
+    ) -> Result<Vec<Result<UserId, RepositoryError>>, RepositoryError> {
+        let mut users = write(&self.users);
+        // An atomic batch is written to a copy, which replaces the users only when every write succeeded
+        let mut copy = options.atomic.then(|| users.clone());
+        let target = copy.as_mut().unwrap_or(&mut *users);
+        let mut results = vec![];
+        for user_write in writes {
+            let result = write_user(target, &self.last_id, user_write);
+            let failed = result.is_err();
+            results.push(result);
+            if failed && (options.ordered || options.atomic) {
+                break;
+            }
+        }
+        if let Some(copy) = copy {
+            if results.iter().all(Result::is_ok) {
+                *users = copy;
+            }
+        }
+        Ok(results)
+    }
+}
+
+#[async_trait]
+impl ApiKeyRepository for InMemoryRepo {
+    async fn create_api_key(&self, api_key: ApiKey) -> Result<(), RepositoryError> {
+        let mut api_keys = write(&self.api_keys);
+        check_name(&api_keys, &api_key)?;
+        api_keys.push(api_key);
+        Ok(())
+    }
+
+    async fn get_api_key(&self, key_id: &str) -> Result<Option<ApiKey>, RepositoryError> {
+        Ok(read(&self.api_keys)
+            .iter()
+            .find(|api_key| api_key.key_id == key_id)
+            .cloned())
+    }
+
+    async fn list_api_keys(&self) -> Result<Vec<ApiKey>, RepositoryError> {
+        Ok(read(&self.api_keys).clone())
+    }
+
+    async fn update_api_key(&self, api_key: ApiKey) -> Result<bool, RepositoryError> {
+        let mut api_keys = write(&self.api_keys);
+        match api_keys
+            .iter()
+            .position(|stored| stored.key_id == api_key.key_id)
+        {
+            Some(index) => {
+                check_name(&api_keys, &api_key)?;
+                api_keys[index] = api_key;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+#[async_trait]
+impl AuditRepository for InMemoryRepo {
+    async fn record_audit(&self, record: AuditRecord) -> Result<(), RepositoryError> {
+        write(&self.audit).push(record);
+        Ok(())
+    }
+
+    async fn record_audits(&self, records: Vec<AuditRecord>) -> Result<(), RepositoryError> {
+        write(&self.audit).extend(records);
+        Ok(())
+    }
+
+    async fn find_audit_records(
+        &self,
+        query: AuditQuery,
+    ) -> Result<Vec<AuditRecord>, RepositoryError> {
+        let mut records: Vec<AuditRecord> = read(&self.audit)
+            .iter()
+            .filter(|record| {
+                query
+                    .actor
+                    .as_ref()
+                    .is_none_or(|actor| &record.actor.subject == actor)
+                    && query
+                        .target
+                        .as_ref()
+                        .is_none_or(|target| record.target_id.as_ref() == Some(target))
+                    && query.from.is_none_or(|from| record.timestamp >= from)
+                    && query.to.is_none_or(|to| record.timestamp <= to)
+            })
+            .cloned()
+            .collect();
+        records.sort_by_key(|record| Reverse(record.timestamp));
+        records.truncate(query.limit() as usize);
+        Ok(records)
+    }
+}
@@ -1,4 +1,9 @@
 pub(crate) mod error;
+pub mod memory_repo;
 pub mod mongodb_repo;
+pub mod postgres_repo;
 pub mod repository;
+pub mod search;
+pub(crate) mod sql_common;
+pub mod sqlite_repo;
 mod tests;
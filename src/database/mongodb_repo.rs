@@ -1,18 +1,88 @@
 use async_trait::async_trait;
-use futures_util::TryStreamExt;
+use futures_util::{future, StreamExt, TryStreamExt};
 
 use crate::database::error::RepositoryError;
-use crate::database::repository::Repository;
-use crate::models::user_model::{CreateUserResult, DeleteUserResult, UpdateUserResult, User};
+use crate::database::repository::{
+    write_each, write_one, ApiKeyRepository, AuditRepository, Repository, UserStream,
+};
+use crate::database::search::{LOCATION_WEIGHT, NAME_WEIGHT, TITLE_WEIGHT};
+use crate::models::api_key_model::ApiKey;
+use crate::models::audit_model::{AuditQuery, AuditRecord};
+use crate::models::batch_model::{BulkWriteOptions, UserWrite};
+use crate::models::user_model::{
+    CreateUserResult, DeleteUserResult, UpdateUserResult, User, UserCursor, UserId, UserPage,
+    UserPatch, UserQuery, UserSearchQuery, UserSearchResult, UserSort, UserSortField,
+};
 use mongodb::{
-    bson::{doc, oid::ObjectId, Bson},
-    options::ClientOptions,
-    Client, Collection,
+    bson::{doc, oid::ObjectId, Bson, Document},
+    error::{BulkWriteError, BulkWriteFailure, Error as MongoError, ErrorKind, WriteFailure},
+    options::{
+        ClientOptions, FindOneAndUpdateOptions, FindOptions, IndexOptions, InsertManyOptions,
+        ReturnDocument,
+    },
+    Client, ClientSession, Collection, IndexModel,
 };
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 #[derive(Debug, Clone)]
 pub struct MongoRepo {
-    col: Collection<User>,
+    client: Client,
+    col: Collection<UserDocument>,
+    api_key_col: Collection<ApiKey>,
+    audit_col: Collection<AuditDocument>,
+}
+
+// A user as it's stored, its id is the ObjectId of the document
+#[derive(Debug, Serialize, Deserialize)]
+struct UserDocument {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    id: Option<ObjectId>,
+    name: String,
+    location: String,
+    title: String,
+    // Missing in the users written before it was added, read as 0
+    #[serde(default)]
+    version: u64,
+}
+
+impl UserDocument {
+    fn new(id: Option<ObjectId>, user: User) -> Self {
+        UserDocument {
+            id,
+            name: user.name,
+            location: user.location,
+            title: user.title,
+            version: 1,
+        }
+    }
+}
+
+impl From<UserDocument> for User {
+    fn from(document: UserDocument) -> Self {
+        User {
+            id: document.id.map(UserId::from),
+            name: document.name,
+            location: document.location,
+            title: document.title,
+            version: document.version,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchDocument {
+    #[serde(flatten)]
+    user: UserDocument,
+    score: f64,
+}
+
+// The timestamp of the record is also stored as a BSON date, so time ranges can be queried and sorted
+#[derive(Debug, Serialize, Deserialize)]
+struct AuditDocument {
+    #[serde(flatten)]
+    record: AuditRecord,
+    recorded_at: bson::DateTime,
 }
 
 impl MongoRepo {
@@ -20,28 +90,114 @@ impl MongoRepo {
         let client_options = ClientOptions::parse(uri).await.unwrap();
         let client = Client::with_options(client_options).unwrap();
         let db = client.database("rustDB");
-        let col: Collection<User> = db.collection("User");
-        MongoRepo { col }
+        let col: Collection<UserDocument> = db.collection("User");
+        let api_key_col: Collection<ApiKey> = db.collection("ApiKey");
+        let audit_col: Collection<AuditDocument> = db.collection("audit");
+        // The server still starts without the database, the index is created again on the next start
+        if let Err(err) = col.create_index(user_text_index(), None).await {
+            log::warn!("failed to create the text index of users: {}", err);
+        }
+        if let Err(err) = api_key_col.create_index(api_key_name_index(), None).await {
+            log::warn!("failed to create the name index of API keys: {}", err);
+        }
+        MongoRepo {
+            client,
+            col,
+            api_key_col,
+            audit_col,
+        }
+    }
+}
+
+const DUPLICATE_KEY: i32 = 11000;
+const MAX_TIME_MS_EXPIRED: i32 = 50;
+// Returned for transactions on a standalone server
+const ILLEGAL_OPERATION: i32 = 20;
+
+fn parse_object_id(id: &UserId) -> Result<ObjectId, RepositoryError> {
+    ObjectId::parse_str(id.as_str()).map_err(|_| RepositoryError::InvalidId(id.to_string()))
+}
+
+fn write_error_of(write_error: &BulkWriteError) -> RepositoryError {
+    match write_error.code {
+        DUPLICATE_KEY => RepositoryError::Conflict(write_error.message.to_owned()),
+        _ => RepositoryError::GeneralError(write_error.message.to_owned()),
+    }
+}
+
+// The result of each write of a group from the errors by index, an ordered group stops at its first error
+fn group_results(
+    ids: Vec<UserId>,
+    mut failures: HashMap<usize, RepositoryError>,
+    ordered: bool,
+) -> Vec<Result<UserId, RepositoryError>> {
+    let mut results = vec![];
+    for (index, id) in ids.into_iter().enumerate() {
+        match failures.remove(&index) {
+            Some(err) => {
+                results.push(Err(err));
+                if ordered {
+                    break;
+                }
+            }
+            None => results.push(Ok(id)),
+        }
+    }
+    results
+}
+
+// The command of the group failed as a whole, so none of its writes is known to have run
+fn failed_group(
+    err: &MongoError,
+    count: usize,
+    ordered: bool,
+) -> Vec<Result<UserId, RepositoryError>> {
+    let count = if ordered { count.min(1) } else { count };
+    (0..count)
+        .map(|_| {
+            Err(map_error(err.clone(), |err| {
+                RepositoryError::CreateUpdateUser(Box::from(err))
+            }))
+        })
+        .collect()
+}
+
+// Errors the caller can act on get their own variant, e.g. a 409 or a 503, the rest are mapped by fallback
+fn map_error(
+    err: MongoError,
+    fallback: impl FnOnce(MongoError) -> RepositoryError,
+) -> RepositoryError {
+    match err.kind.as_ref() {
+        ErrorKind::Write(WriteFailure::WriteError(write_error))
+            if write_error.code == DUPLICATE_KEY =>
+        {
+            RepositoryError::Conflict(write_error.message.to_owned())
+        }
+        ErrorKind::Command(command_error) if command_error.code == MAX_TIME_MS_EXPIRED => {
+            RepositoryError::Timeout(command_error.message.to_owned())
+        }
+        ErrorKind::Io(io_error) if io_error.kind() == std::io::ErrorKind::TimedOut => {
+            RepositoryError::Timeout(io_error.to_string())
+        }
+        ErrorKind::Io(_)
+        | ErrorKind::ServerSelection { .. }
+        | ErrorKind::ConnectionPoolCleared { .. }
+        | ErrorKind::DnsResolve { .. }
+        | ErrorKind::Shutdown => RepositoryError::Unavailable(err.to_string()),
+        _ => fallback(err),
     }
 }
 
 #[async_trait]
 impl Repository for MongoRepo {
     async fn create_user(&self, new_user: User) -> Result<CreateUserResult, RepositoryError> {
-        let new_doc = User {
-            id: None,
-            name: new_user.name,
-            location: new_user.location,
-            title: new_user.title,
-        };
-        let result = self
-            .col
-            .insert_one(new_doc, None)
-            .await
-            .map_err(|err| RepositoryError::CreateUpdateUser(Box::from(err)))?;
+        let new_doc = UserDocument::new(None, new_user);
+        let result = self.col.insert_one(new_doc, None).await.map_err(|err| {
+            map_error(err, |err| RepositoryError::CreateUpdateUser(Box::from(err)))
+        })?;
         match result.inserted_id {
             Bson::ObjectId(object_id) => Ok(CreateUserResult {
-                id: object_id.to_hex(),
+                id: object_id.into(),
             }),
             _ => Err(RepositoryError::GeneralError(
                 "Error parsing id of created user".to_string(),
@@ -49,31 +205,83 @@ impl Repository for MongoRepo {
         }
     }
 
-    async fn get_user(&self, id: String) -> Result<Option<User>, RepositoryError> {
-        let obj_id = ObjectId::parse_str(id).unwrap();
+    async fn create_users(
+        &self,
+        new_users: Vec<User>,
+    ) -> Result<Vec<Result<CreateUserResult, RepositoryError>>, RepositoryError> {
+        if new_users.is_empty() {
+            return Ok(vec![]);
+        }
+        // The ids are generated here, so the ids of the created users are known even when others fail
+        let new_docs: Vec<UserDocument> = new_users
+            .into_iter()
+            .map(|user| UserDocument::new(Some(ObjectId::new()), user))
+            .collect();
+        let ids: Vec<UserId> = new_docs
+            .iter()
+            .filter_map(|document| document.id)
+            .map(UserId::from)
+            .collect();
+        let failures = self
+            .insert_documents(new_docs, false)
+            .await
+            .map_err(|err| {
+                map_error(err, |err| RepositoryError::CreateUpdateUser(Box::from(err)))
+            })?;
+        Ok(group_results(ids, failures, false)
+            .into_iter()
+            .map(|result| result.map(|id| CreateUserResult { id }))
+            .collect())
+    }
+
+    async fn get_user(&self, id: &UserId) -> Result<Option<User>, RepositoryError> {
+        let obj_id = parse_object_id(id)?;
         let filter = doc! {"_id": obj_id};
-        let user_detail = self.col.find_one(filter, None).await.unwrap();
+        let document =
+            self.col.find_one(filter, None).await.map_err(|err| {
+                map_error(err, |err| RepositoryError::GeneralError(err.to_string()))
+            })?;
+        Ok(document.map(User::from))
+    }
 
-        Ok(user_detail)
+    async fn get_users(&self, ids: Vec<UserId>) -> Result<Vec<User>, RepositoryError> {
+        let obj_ids: Vec<ObjectId> = ids
+            .iter()
+            .filter_map(|id| parse_object_id(id).ok())
+            .collect();
+        if obj_ids.is_empty() {
+            return Ok(vec![]);
+        }
+        let read_error = |err| map_error(err, |err| RepositoryError::GeneralError(err.to_string()));
+        let documents: Vec<UserDocument> = self
+            .col
+            .find(doc! {"_id": {"$in": obj_ids}}, None)
+            .await
+            .map_err(read_error)?
+            .try_collect()
+            .await
+            .map_err(read_error)?;
+        Ok(documents.into_iter().map(User::from).collect())
     }
 
-    async fn update_user(&self, id: &str, user: User) -> Result<UpdateUserResult, RepositoryError> {
-        let obj_id = ObjectId::parse_str(id).unwrap();
-        let filter = doc! {"_id": obj_id};
-        let new_doc = doc! {
-                      "$set":
-                    {
-                        "id": user.id,
-                        "name": user.name,
-                        "location": user.location,
-                        "title": user.title
-                    },
-        };
+    async fn update_user(
+        &self,
+        id: &UserId,
+        user: User,
+        expected_version: Option<u64>,
+    ) -> Result<UpdateUserResult, RepositoryError> {
+        let obj_id = parse_object_id(id)?;
+        let filter = id_filter(obj_id, expected_version);
         let updated_doc = self
             .col
-            .update_one(filter, new_doc, None)
+            .update_one(filter, update_document(user), None)
             .await
-            .map_err(|err| RepositoryError::CreateUpdateUser(Box::from(err)))?;
+            .map_err(|err| {
+                map_error(err, |err| RepositoryError::CreateUpdateUser(Box::from(err)))
+            })?;
+        if updated_doc.matched_count == 0 {
+            return Err(self.missed_write_error(id, obj_id, expected_version).await);
+        }
         Ok(UpdateUserResult {
             matched_count: updated_doc.matched_count,
             modified_count: updated_doc.modified_count,
@@ -81,29 +289,632 @@ impl Repository for MongoRepo {
         })
     }
 
-    async fn delete_user(&self, id: &str) -> Result<DeleteUserResult, RepositoryError> {
-        let obj_id = ObjectId::parse_str(id).unwrap();
-        let filter = doc! {"_id": obj_id};
+    async fn delete_user(
+        &self,
+        id: &UserId,
+        expected_version: Option<u64>,
+    ) -> Result<DeleteUserResult, RepositoryError> {
+        let obj_id = parse_object_id(id)?;
+        let filter = id_filter(obj_id, expected_version);
         let delete_result = self
             .col
             .delete_one(filter, None)
             .await
-            .map_err(|err| RepositoryError::DeleteUser(Box::from(err)))?;
+            .map_err(|err| map_error(err, |err| RepositoryError::DeleteUser(Box::from(err))))?;
+        if delete_result.deleted_count == 0 {
+            return Err(self.missed_write_error(id, obj_id, expected_version).await);
+        }
         Ok(DeleteUserResult {
             deleted_count: delete_result.deleted_count,
         })
     }
 
-    async fn get_all_users(&self) -> Result<Vec<User>, RepositoryError> {
-        let mut cursors = self.col.find(None, None).await.map_err(|_| {
-            RepositoryError::GeneralError("Error getting list of users".to_string())
+    async fn patch_user(
+        &self,
+        id: &UserId,
+        patch: UserPatch,
+        expected_version: Option<u64>,
+    ) -> Result<User, RepositoryError> {
+        let obj_id = parse_object_id(id)?;
+        let filter = id_filter(obj_id, expected_version);
+        let mut set = doc! {};
+        for (field, value) in [
+            ("name", patch.name),
+            ("location", patch.location),
+            ("title", patch.title),
+        ] {
+            if let Some(value) = value {
+                set.insert(field, value);
+            }
+        }
+        // Older versions also wrote the id into an "id" field, unsetting it also keeps the update valid when
+        // nothing changed
+        let mut update = doc! {"$unset": {"id": ""}};
+        if !set.is_empty() {
+            update.insert("$set", set);
+            update.insert("$inc", doc! {"version": 1_i64});
+        }
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+        let document = self
+            .col
+            .find_one_and_update(filter, update, options)
+            .await
+            .map_err(|err| {
+                map_error(err, |err| RepositoryError::CreateUpdateUser(Box::from(err)))
+            })?;
+        match document {
+            Some(document) => Ok(document.into()),
+            None => Err(self.missed_write_error(id, obj_id, expected_version).await),
+        }
+    }
+
+    async fn list_users(&self, query: UserQuery) -> Result<UserPage, RepositoryError> {
+        let filter = user_filter(&query);
+        let total = match query.count {
+            Some(true) => Some(
+                self.col
+                    .count_documents(filter.clone(), None)
+                    .await
+                    .map_err(|err| {
+                        map_error(err, |_| {
+                            RepositoryError::GeneralError("Error counting users".to_string())
+                        })
+                    })?,
+            ),
+            _ => None,
+        };
+
+        let sort = query.sort();
+        let mut conditions = vec![filter];
+        if let Some(cursor) = &query.after {
+            conditions.push(after_cursor(cursor)?);
+        }
+        // One more user than the limit tells if there's a next page
+        let limit = query.limit();
+        let options = FindOptions::builder()
+            .sort(sort_document(sort))
+            .limit(limit as i64 + 1)
+            .build();
+        let cursor = self
+            .col
+            .find(doc! {"$and": conditions}, options)
+            .await
+            .map_err(|err| {
+                map_error(err, |_| {
+                    RepositoryError::GeneralError("Error getting list of users".to_string())
+                })
+            })?;
+        let documents: Vec<UserDocument> = cursor.try_collect().await.map_err(|err| {
+            map_error(err, |_| {
+                RepositoryError::GeneralError("Error mapping through cursor".to_string())
+            })
+        })?;
+        let mut users: Vec<User> = documents.into_iter().map(User::from).collect();
+        let next = if users.len() > limit as usize {
+            users.truncate(limit as usize);
+            users.last().and_then(|user| UserCursor::after(user, sort))
+        } else {
+            None
+        };
+        Ok(UserPage { users, next, total })
+    }
+
+    async fn stream_users(&self, query: UserQuery) -> Result<UserStream, RepositoryError> {
+        let options = FindOptions::builder()
+            .sort(sort_document(query.sort()))
+            .build();
+        let cursor = self
+            .col
+            .find(user_filter(&query), options)
+            .await
+            .map_err(|err| {
+                map_error(err, |_| {
+                    RepositoryError::GeneralError("Error getting list of users".to_string())
+                })
+            })?;
+        // The cursor fetches the next batch only when the previous one was consumed
+        let users = cursor.map(|document| {
+            document.map(User::from).map_err(|err| {
+                map_error(err, |_| {
+                    RepositoryError::GeneralError("Error mapping through cursor".to_string())
+                })
+            })
+        });
+        Ok(Box::pin(users))
+    }
+
+    async fn search_users(
+        &self,
+        query: UserSearchQuery,
+    ) -> Result<Vec<UserSearchResult>, RepositoryError> {
+        let score = doc! {"$meta": "textScore"};
+        let options = FindOptions::builder()
+            .projection(doc! {"score": score.clone()})
+            .sort(doc! {"score": score})
+            .limit(query.limit() as i64)
+            .build();
+        let cursor = self
+            .col
+            .clone_with_type::<SearchDocument>()
+            .find(doc! {"$text": {"$search": &query.q}}, options)
+            .await
+            .map_err(|err| {
+                map_error(err, |_| {
+                    RepositoryError::GeneralError("Error searching users".to_string())
+                })
+            })?;
+        let documents: Vec<SearchDocument> = cursor.try_collect().await.map_err(|err| {
+            map_error(err, |_| {
+                RepositoryError::GeneralError("Error mapping through cursor".to_string())
+            })
+        })?;
+        Ok(documents
+            .into_iter()
+            .map(|document| UserSearchResult {
+                user: document.user.into(),
+                score: document.score,
+            })
+            .collect())
+    }
+
+    async fn bulk_write(
+        &self,
+        writes: Vec<UserWrite>,
+        options: BulkWriteOptions,
+    ) -> Result<Vec<Result<UserId, RepositoryError>>, RepositoryError> {
+        if options.atomic {
+            return self.write_in_transaction(writes).await;
+        }
+        Ok(self.write_grouped(writes, options.ordered).await)
+    }
+}
+
+// The user with the id, and with the expected version when there's one
+fn id_filter(id: ObjectId, expected_version: Option<u64>) -> Document {
+    match expected_version {
+        // Also the users written before the version was added, which don't have the field
+        Some(0) => doc! {"_id": id, "version": {"$in": [0_i64, Bson::Null]}},
+        Some(version) => doc! {"_id": id, "version": version as i64},
+        None => doc! {"_id": id},
+    }
+}
+
+// A pipeline, so the version is only incremented when a field changes. The values are literals, so e.g. a title
+// starting with $ isn't read as a field path
+fn update_document(user: User) -> Vec<Document> {
+    let name = doc! {"$literal": user.name};
+    let location = doc! {"$literal": user.location};
+    let title = doc! {"$literal": user.title};
+    let unchanged = doc! {"$and": [
+        {"$eq": ["$name", name.clone()]},
+        {"$eq": ["$location", location.clone()]},
+        {"$eq": ["$title", title.clone()]},
+    ]};
+    let next_version = doc! {"$add": [{"$ifNull": ["$version", 0_i64]}, 1_i64]};
+    vec![
+        doc! {"$set": {
+            "version": {"$cond": [unchanged, "$version", next_version]},
+            "name": name,
+            "location": location,
+            "title": title,
+        }},
+        // Older versions also wrote the id into an "id" field
+        doc! {"$unset": "id"},
+    ]
+}
+
+impl MongoRepo {
+    // A write filtered on the id and the expected version matched no user, either because there's no user with
+    // the id, or because it has another version
+    async fn missed_write_error(
+        &self,
+        id: &UserId,
+        obj_id: ObjectId,
+        expected_version: Option<u64>,
+    ) -> RepositoryError {
+        if expected_version.is_none() {
+            return RepositoryError::NotFound(format!("No user found with id {}", id));
+        }
+        match self.col.count_documents(doc! {"_id": obj_id}, None).await {
+            Ok(0) => RepositoryError::NotFound(format!("No user found with id {}", id)),
+            Ok(_) => RepositoryError::VersionMismatch(format!(
+                "User {} is no longer at version {}",
+                id,
+                expected_version.unwrap_or_default()
+            )),
+            Err(err) => map_error(err, |err| RepositoryError::GeneralError(err.to_string())),
+        }
+    }
+
+    // The error of each document that wasn't inserted, by its index. An ordered insert stops at the first one
+    async fn insert_documents(
+        &self,
+        documents: Vec<UserDocument>,
+        ordered: bool,
+    ) -> Result<HashMap<usize, RepositoryError>, MongoError> {
+        let options = InsertManyOptions::builder().ordered(ordered).build();
+        let mut failures = HashMap::new();
+        if let Err(err) = self.col.insert_many(documents, options).await {
+            match err.kind.as_ref() {
+                ErrorKind::BulkWrite(BulkWriteFailure {
+                    write_errors: Some(write_errors),
+                    write_concern_error: None,
+                    ..
+                }) => {
+                    for write_error in write_errors {
+                        failures.insert(write_error.index, write_error_of(write_error));
+                    }
+                }
+                _ => return Err(err),
+            }
+        }
+        Ok(failures)
+    }
+
+    // Consecutive writes of the same kind are sent together, the creates in one command whose write errors give
+    // the result of each one
+    async fn write_grouped(
+        &self,
+        writes: Vec<UserWrite>,
+        ordered: bool,
+    ) -> Vec<Result<UserId, RepositoryError>> {
+        let mut results = vec![];
+        let mut writes = writes.into_iter().peekable();
+        while let Some(write) = writes.next() {
+            let mut group = vec![write];
+            while let Some(write) = writes
+                .next_if(|next| std::mem::discriminant(next) == std::mem::discriminant(&group[0]))
+            {
+                group.push(write);
+            }
+            let group_results = match group[0] {
+                UserWrite::Create(_) => self.insert_group(group, ordered).await,
+                UserWrite::Update(..) | UserWrite::Delete(_) => {
+                    self.update_or_delete_group(group, ordered).await
+                }
+            };
+            let failed = group_results.iter().any(Result::is_err);
+            results.extend(group_results);
+            if failed && ordered {
+                break;
+            }
+        }
+        results
+    }
+
+    async fn insert_group(
+        &self,
+        writes: Vec<UserWrite>,
+        ordered: bool,
+    ) -> Vec<Result<UserId, RepositoryError>> {
+        // The ids are generated here, so the ids of the created users are known even when others fail
+        let documents: Vec<UserDocument> = writes
+            .into_iter()
+            .filter_map(|write| match write {
+                UserWrite::Create(user) => Some(UserDocument::new(Some(ObjectId::new()), user)),
+                UserWrite::Update(..) | UserWrite::Delete(_) => None,
+            })
+            .collect();
+        let ids: Vec<UserId> = documents
+            .iter()
+            .filter_map(|document| document.id)
+            .map(UserId::from)
+            .collect();
+        let count = ids.len();
+        match self.insert_documents(documents, ordered).await {
+            Ok(failures) => group_results(ids, failures, ordered),
+            Err(err) => failed_group(&err, count, ordered),
+        }
+    }
+
+    // Each update or delete runs alone, so its matched count tells whether the user existed when it was written.
+    // The update and delete commands only count the users matched by all their statements
+    async fn update_or_delete_group(
+        &self,
+        writes: Vec<UserWrite>,
+        ordered: bool,
+    ) -> Vec<Result<UserId, RepositoryError>> {
+        if ordered {
+            return write_each(self, writes, true).await;
+        }
+        future::join_all(writes.into_iter().map(|write| write_one(self, write))).await
+    }
+
+    // Runs the writes in a transaction, committed only when all of them succeed
+    async fn write_in_transaction(
+        &self,
+        writes: Vec<UserWrite>,
+    ) -> Result<Vec<Result<UserId, RepositoryError>>, RepositoryError> {
+        let mut session = self
+            .client
+            .start_session(None)
+            .await
+            .map_err(map_transaction_error)?;
+        session
+            .start_transaction(None)
+            .await
+            .map_err(map_transaction_error)?;
+        let mut results = vec![];
+        for write in writes {
+            match self.write_with_session(write, &mut session).await {
+                Ok(id) => results.push(Ok(id)),
+                Err(err @ RepositoryError::Unsupported(_)) => return Err(err),
+                Err(err) => {
+                    if let Err(abort_err) = session.abort_transaction().await {
+                        log::warn!("failed to abort the transaction: {}", abort_err);
+                    }
+                    results.push(Err(err));
+                    return Ok(results);
+                }
+            }
+        }
+        session
+            .commit_transaction()
+            .await
+            .map_err(map_transaction_error)?;
+        Ok(results)
+    }
+
+    async fn write_with_session(
+        &self,
+        write: UserWrite,
+        session: &mut ClientSession,
+    ) -> Result<UserId, RepositoryError> {
+        match write {
+            UserWrite::Create(user) => {
+                let id = ObjectId::new();
+                let new_doc = UserDocument::new(Some(id), user);
+                self.col
+                    .insert_one_with_session(new_doc, None, session)
+                    .await
+                    .map_err(map_transaction_error)?;
+                Ok(id.into())
+            }
+            UserWrite::Update(id, user) => {
+                let filter = doc! {"_id": parse_object_id(&id)?};
+                let result = self
+                    .col
+                    .update_one_with_session(filter, update_document(user), None, session)
+                    .await
+                    .map_err(map_transaction_error)?;
+                if result.matched_count == 0 {
+                    return Err(RepositoryError::NotFound(format!(
+                        "No user found with id {}",
+                        id
+                    )));
+                }
+                Ok(id)
+            }
+            UserWrite::Delete(id) => {
+                let filter = doc! {"_id": parse_object_id(&id)?};
+                let result = self
+                    .col
+                    .delete_one_with_session(filter, None, session)
+                    .await
+                    .map_err(map_transaction_error)?;
+                if result.deleted_count == 0 {
+                    return Err(RepositoryError::NotFound(format!(
+                        "No user found with id {}",
+                        id
+                    )));
+                }
+                Ok(id)
+            }
+        }
+    }
+}
+
+fn map_transaction_error(err: MongoError) -> RepositoryError {
+    match err.kind.as_ref() {
+        ErrorKind::Command(command_error) if command_error.code == ILLEGAL_OPERATION => {
+            RepositoryError::Unsupported(format!(
+                "atomic batches require a replica set or a sharded cluster: {}",
+                command_error.message
+            ))
+        }
+        _ => map_error(err, |err| RepositoryError::CreateUpdateUser(Box::from(err))),
+    }
+}
+
+fn user_text_index() -> IndexModel {
+    IndexModel::builder()
+        .keys(doc! {"name": "text", "title": "text", "location": "text"})
+        .options(
+            IndexOptions::builder()
+                .name("user_text".to_string())
+                .weights(doc! {
+                    "name": NAME_WEIGHT as i32,
+                    "title": TITLE_WEIGHT as i32,
+                    "location": LOCATION_WEIGHT as i32,
+                })
+                .build(),
+        )
+        .build()
+}
+
+// Names identify the keys in the audit log and the rate limits, so enabled keys can't share them
+fn api_key_name_index() -> IndexModel {
+    IndexModel::builder()
+        .keys(doc! {"name": 1})
+        .options(
+            IndexOptions::builder()
+                .name("api_key_enabled_name".to_string())
+                .unique(true)
+                .partial_filter_expression(doc! {"enabled": true})
+                .build(),
+        )
+        .build()
+}
+
+fn user_filter(query: &UserQuery) -> Document {
+    let conditions: Vec<Document> = query
+        .filters()
+        .into_iter()
+        .map(|(field, value, prefix)| match prefix {
+            true => doc! {field: {"$regex": format!("^{}", escape_regex(value))}},
+            false => doc! {field: value},
+        })
+        .collect();
+    match conditions.is_empty() {
+        true => doc! {},
+        false => doc! {"$and": conditions},
+    }
+}
+
+// Ties are broken by the id, so the order is the same on every query
+fn sort_document(sort: UserSort) -> Document {
+    let direction = if sort.descending { -1 } else { 1 };
+    match sort.field {
+        UserSortField::Id => doc! {"_id": direction},
+        field => doc! {field.name(): direction, "_id": direction},
+    }
+}
+
+// Users sorted after the cursor, i.e. with a greater sort value, or the same one and a greater id
+fn after_cursor(cursor: &UserCursor) -> Result<Document, RepositoryError> {
+    let operator = if cursor.sort.descending { "$lt" } else { "$gt" };
+    let id = parse_object_id(&cursor.id)?;
+    Ok(match (cursor.sort.field, &cursor.value) {
+        (UserSortField::Id, _) | (_, None) => doc! {"_id": {operator: id}},
+        (field, Some(value)) => doc! {"$or": [
+            {field.name(): {operator: value}},
+            {field.name(): value, "_id": {operator: id}},
+        ]},
+    })
+}
+
+// Prefixes are matched literally
+fn escape_regex(value: &str) -> String {
+    value
+        .chars()
+        .flat_map(|c| match "\\.+*?()|[]{}^$#&-~".contains(c) {
+            true => vec!['\\', c],
+            false => vec![c],
+        })
+        .collect()
+}
+
+#[async_trait]
+impl ApiKeyRepository for MongoRepo {
+    async fn create_api_key(&self, api_key: ApiKey) -> Result<(), RepositoryError> {
+        self.api_key_col
+            .insert_one(api_key, None)
+            .await
+            .map_err(|err| {
+                map_error(err, |err| {
+                    RepositoryError::CreateUpdateApiKey(Box::from(err))
+                })
+            })?;
+        Ok(())
+    }
+
+    async fn get_api_key(&self, key_id: &str) -> Result<Option<ApiKey>, RepositoryError> {
+        let filter = doc! {"key_id": key_id};
+        self.api_key_col
+            .find_one(filter, None)
+            .await
+            .map_err(|err| {
+                map_error(err, |_| {
+                    RepositoryError::GeneralError("Error getting API key".to_string())
+                })
+            })
+    }
+
+    async fn list_api_keys(&self) -> Result<Vec<ApiKey>, RepositoryError> {
+        let cursor = self.api_key_col.find(None, None).await.map_err(|err| {
+            map_error(err, |_| {
+                RepositoryError::GeneralError("Error getting list of API keys".to_string())
+            })
         })?;
-        let mut users: Vec<User> = Vec::new();
-        while let Some(user) = cursors.try_next().await.map_err(|_| {
-            RepositoryError::GeneralError("Error mapping through cursor".to_string())
-        })? {
-            users.push(user)
+        cursor.try_collect().await.map_err(|err| {
+            map_error(err, |_| {
+                RepositoryError::GeneralError("Error mapping through cursor".to_string())
+            })
+        })
+    }
+
+    async fn update_api_key(&self, api_key: ApiKey) -> Result<bool, RepositoryError> {
+        let filter = doc! {"key_id": &api_key.key_id};
+        let result = self
+            .api_key_col
+            .replace_one(filter, api_key, None)
+            .await
+            .map_err(|err| {
+                map_error(err, |err| {
+                    RepositoryError::CreateUpdateApiKey(Box::from(err))
+                })
+            })?;
+        Ok(result.matched_count == 1)
+    }
+}
+
+#[async_trait]
+impl AuditRepository for MongoRepo {
+    async fn record_audit(&self, record: AuditRecord) -> Result<(), RepositoryError> {
+        self.record_audits(vec![record]).await
+    }
+
+    async fn record_audits(&self, records: Vec<AuditRecord>) -> Result<(), RepositoryError> {
+        // insert_many rejects an empty list
+        if records.is_empty() {
+            return Ok(());
         }
-        Ok(users)
+        let documents = records.into_iter().map(|record| AuditDocument {
+            recorded_at: bson::DateTime::from_millis(record.timestamp.timestamp_millis()),
+            record,
+        });
+        self.audit_col
+            .insert_many(documents, None)
+            .await
+            .map_err(|err| {
+                map_error(err, |err| {
+                    RepositoryError::CreateAuditRecord(Box::from(err))
+                })
+            })?;
+        Ok(())
+    }
+
+    async fn find_audit_records(
+        &self,
+        query: AuditQuery,
+    ) -> Result<Vec<AuditRecord>, RepositoryError> {
+        let mut filter = doc! {};
+        if let Some(actor) = &query.actor {
+            filter.insert("actor.subject", actor);
+        }
+        if let Some(target) = &query.target {
+            filter.insert("target_id", target);
+        }
+        let mut recorded_at = doc! {};
+        if let Some(from) = query.from {
+            recorded_at.insert("$gte", bson::DateTime::from_millis(from.timestamp_millis()));
+        }
+        if let Some(to) = query.to {
+            recorded_at.insert("$lte", bson::DateTime::from_millis(to.timestamp_millis()));
+        }
+        if !recorded_at.is_empty() {
+            filter.insert("recorded_at", recorded_at);
+        }
+        let options = FindOptions::builder()
+            .sort(doc! {"recorded_at": -1})
+            .limit(query.limit() as i64)
+            .build();
+
+        let cursor = self.audit_col.find(filter, options).await.map_err(|err| {
+            map_error(err, |_| {
+                RepositoryError::GeneralError("Error getting audit records".to_string())
+            })
+        })?;
+        let documents: Vec<AuditDocument> = cursor.try_collect().await.map_err(|err| {
+            map_error(err, |_| {
+                RepositoryError::GeneralError("Error mapping through cursor".to_string())
+            })
+        })?;
+        Ok(documents
+            .into_iter()
+            .map(|document| document.record)
+            .collect())
     }
 }
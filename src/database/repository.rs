@@ -1,18 +1,168 @@
 use crate::database::error::RepositoryError;
-use crate::models::user_model::{CreateUserResult, DeleteUserResult, UpdateUserResult, User};
+use crate::database::search;
+use crate::models::api_key_model::ApiKey;
+use crate::models::audit_model::{AuditQuery, AuditRecord};
+use crate::models::batch_model::{BulkWriteOptions, UserWrite};
+use crate::models::user_model::{
+    CreateUserResult, DeleteUserResult, UpdateUserResult, User, UserId, UserPage, UserPatch,
+    UserQuery, UserSearchQuery, UserSearchResult,
+};
 use async_trait::async_trait;
+use futures_util::stream::{self, BoxStream, TryStreamExt};
 use mockall::predicate::*;
 use mockall::*;
 use std::fmt::Debug;
 
+pub type UserStream = BoxStream<'static, Result<User, RepositoryError>>;
+
 #[automock]
 #[async_trait]
 pub trait Repository: Send + Sync {
     async fn create_user(&self, new_user: User) -> Result<CreateUserResult, RepositoryError>;
-    async fn get_user(&self, id: String) -> Result<Option<User>, RepositoryError>;
-    async fn update_user(&self, id: &str, user: User) -> Result<UpdateUserResult, RepositoryError>;
-    async fn delete_user(&self, id: &str) -> Result<DeleteUserResult, RepositoryError>;
-    async fn get_all_users(&self) -> Result<Vec<User>, RepositoryError>;
+    // One result per user, in the same order, a user that fails doesn't prevent the others from being created
+    async fn create_users(
+        &self,
+        new_users: Vec<User>,
+    ) -> Result<Vec<Result<CreateUserResult, RepositoryError>>, RepositoryError>;
+    // The ids are the ones the repository generated, others fail with RepositoryError::InvalidId
+    async fn get_user(&self, id: &UserId) -> Result<Option<User>, RepositoryError>;
+    // The users with the ids in one read, in no particular order. The ids that the repository can't have generated
+    // are left out, like the ones that no user has
+    async fn get_users(&self, ids: Vec<UserId>) -> Result<Vec<User>, RepositoryError>;
+    // update_user, delete_user and patch_user fail with RepositoryError::NotFound when no user has the id, and with
+    // RepositoryError::VersionMismatch when the user doesn't have the expected version, checked in the same
+    // atomic operation as the write
+    async fn update_user(
+        &self,
+        id: &UserId,
+        user: User,
+        expected_version: Option<u64>,
+    ) -> Result<UpdateUserResult, RepositoryError>;
+    async fn delete_user(
+        &self,
+        id: &UserId,
+        expected_version: Option<u64>,
+    ) -> Result<DeleteUserResult, RepositoryError>;
+    // Writes only the fields of the patch, and returns the updated user
+    async fn patch_user(
+        &self,
+        id: &UserId,
+        patch: UserPatch,
+        expected_version: Option<u64>,
+    ) -> Result<User, RepositoryError>;
+    // A page of the users matching the filters of the query, after its cursor
+    async fn list_users(&self, query: UserQuery) -> Result<UserPage, RepositoryError>;
+    // Every user matching the filters of the query, in its order, read lazily so the caller controls the
+    // memory used, the limit and the cursor of the query are ignored
+    async fn stream_users(&self, query: UserQuery) -> Result<UserStream, RepositoryError>;
+    // Users matching the words of the query in their name, title or location, the most relevant first
+    async fn search_users(
+        &self,
+        query: UserSearchQuery,
+    ) -> Result<Vec<UserSearchResult>, RepositoryError>;
+    // The id of the written user for each write that ran, in order. An ordered bulk write stops at the first
+    // failure, so it can have fewer results than writes. An atomic one keeps none of the writes when one fails,
+    // and fails with RepositoryError::Unsupported when the database has no transactions
+    async fn bulk_write(
+        &self,
+        writes: Vec<UserWrite>,
+        options: BulkWriteOptions,
+    ) -> Result<Vec<Result<UserId, RepositoryError>>, RepositoryError>;
+}
+
+// Every user matching the query, read a page at a time, for the backends whose cursors can't outlive the call
+pub fn paged_stream<R: Repository + Clone + 'static>(
+    repository: R,
+    query: UserQuery,
+) -> UserStream {
+    let query = UserQuery {
+        limit: Some(UserQuery::MAX_LIMIT),
+        count: None,
+        after: None,
+        ..query
+    };
+    let pages = stream::try_unfold(Some(query), move |query| {
+        let repository = repository.clone();
+        async move {
+            let Some(query) = query else {
+                return Ok(None);
+            };
+            let page = repository.list_users(query.clone()).await?;
+            let next = page.next.map(|next| UserQuery {
+                after: Some(next),
+                ..query
+            });
+            Ok(Some((stream::iter(page.users.into_iter().map(Ok)), next)))
+        }
+    });
+    Box::pin(pages.try_flatten())
+}
+
+// Bulk write through the single writes of the repository, for the backends without a native one
+pub async fn write_each<R: Repository + ?Sized>(
+    repository: &R,
+    writes: Vec<UserWrite>,
+    ordered: bool,
+) -> Vec<Result<UserId, RepositoryError>> {
+    let mut results = vec![];
+    for write in writes {
+        let result = write_one(repository, write).await;
+        let failed = result.is_err();
+        results.push(result);
+        if failed && ordered {
+            break;
+        }
+    }
+    results
+}
+
+pub async fn write_one<R: Repository + ?Sized>(
+    repository: &R,
+    write: UserWrite,
+) -> Result<UserId, RepositoryError> {
+    match write {
+        UserWrite::Create(user) => repository.create_user(user).await.map(|result| result.id),
+        UserWrite::Update(id, user) => repository.update_user(&id, user, None).await.map(|_| id),
+        UserWrite::Delete(id) => repository.delete_user(&id, None).await.map(|_| id),
+    }
+}
+
+// API keys created at runtime, the middleware reads them on every request, so revoking a key is immediate
+#[automock]
+#[async_trait]
+pub trait ApiKeyRepository: Send + Sync {
+    // create_api_key and update_api_key fail with RepositoryError::Conflict when another enabled key has the name
+    async fn create_api_key(&self, api_key: ApiKey) -> Result<(), RepositoryError>;
+    async fn get_api_key(&self, key_id: &str) -> Result<Option<ApiKey>, RepositoryError>;
+    async fn list_api_keys(&self) -> Result<Vec<ApiKey>, RepositoryError>;
+    // Returns false when there is no key with the same key_id
+    async fn update_api_key(&self, api_key: ApiKey) -> Result<bool, RepositoryError>;
+}
+
+impl Debug for dyn ApiKeyRepository {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "ApiKeyRepository{{}}")
+    }
+}
+
+// Append-only trail of the admin mutations
+#[automock]
+#[async_trait]
+pub trait AuditRepository: Send + Sync {
+    async fn record_audit(&self, record: AuditRecord) -> Result<(), RepositoryError>;
+    // Several records in one write, e.g. those of the users created by a batch of an import
+    async fn record_audits(&self, records: Vec<AuditRecord>) -> Result<(), RepositoryError>;
+    // Newest records first
+    async fn find_audit_records(
+        &self,
+        query: AuditQuery,
+    ) -> Result<Vec<AuditRecord>, RepositoryError>;
+}
+
+impl Debug for dyn AuditRepository {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "AuditRepository{{}}")
+    }
 }
 
 impl Debug for dyn Repository {
@@ -46,19 +196,79 @@ impl Repository for MockDatabase {
         self.return_result(self.create_user_result.clone()).await
     }
 
-    async fn get_user(&self, _: String) -> Result<Option<User>, RepositoryError> {
+    async fn create_users(
+        &self,
+        new_users: Vec<User>,
+    ) -> Result<Vec<Result<CreateUserResult, RepositoryError>>, RepositoryError> {
+        let results = new_users
+            .iter()
+            .map(|_| Ok(self.create_user_result.clone()))
+            .collect();
+        self.return_result(results).await
+    }
+
+    async fn get_user(&self, _: &UserId) -> Result<Option<User>, RepositoryError> {
         self.return_result(Some(self.test_user.clone())).await
     }
 
-    async fn update_user(&self, _: &str, _: User) -> Result<UpdateUserResult, RepositoryError> {
+    async fn get_users(&self, _: Vec<UserId>) -> Result<Vec<User>, RepositoryError> {
+        self.return_result(vec![self.test_user.clone()]).await
+    }
+
+    async fn update_user(
+        &self,
+        _: &UserId,
+        _: User,
+        _: Option<u64>,
+    ) -> Result<UpdateUserResult, RepositoryError> {
         self.return_result(self.update_user_result.clone()).await
     }
 
-    async fn delete_user(&self, _: &str) -> Result<DeleteUserResult, RepositoryError> {
+    async fn delete_user(
+        &self,
+        _: &UserId,
+        _: Option<u64>,
+    ) -> Result<DeleteUserResult, RepositoryError> {
         self.return_result(self.delete_user_result.clone()).await
     }
 
-    async fn get_all_users(&self) -> Result<Vec<User>, RepositoryError> {
-        self.return_result(vec![self.test_user.clone()]).await
+    async fn patch_user(
+        &self,
+        _: &UserId,
+        _: UserPatch,
+        _: Option<u64>,
+    ) -> Result<User, RepositoryError> {
+        self.return_result(self.test_user.clone()).await
+    }
+
+    async fn list_users(&self, _: UserQuery) -> Result<UserPage, RepositoryError> {
+        self.return_result(UserPage {
+            users: vec![self.test_user.clone()],
+            next: None,
+            total: None,
+        })
+        .await
+    }
+
+    async fn stream_users(&self, _: UserQuery) -> Result<UserStream, RepositoryError> {
+        let users = stream::iter(vec![Ok(self.test_user.clone())]);
+        self.return_result(Box::pin(users) as UserStream).await
+    }
+
+    async fn search_users(
+        &self,
+        query: UserSearchQuery,
+    ) -> Result<Vec<UserSearchResult>, RepositoryError> {
+        self.return_result(search::search([&self.test_user], &query))
+            .await
+    }
+
+    async fn bulk_write(
+        &self,
+        writes: Vec<UserWrite>,
+        options: BulkWriteOptions,
+    ) -> Result<Vec<Result<UserId, RepositoryError>>, RepositoryError> {
+        let results = write_each(self, writes, options.ordered || options.atomic).await;
+        self.return_result(results).await
     }
 }
@@ -0,0 +1,62 @@
+use crate::models::user_model::{User, UserSearchQuery, UserSearchResult};
+
+// Tokenised search for the repositories without a text index, the fields are weighted like the MongoDB
+// text index, and a term only matching the start of a word, e.g. "eng" for "engineer", counts half
+pub const NAME_WEIGHT: f64 = 10.0;
+pub const TITLE_WEIGHT: f64 = 5.0;
+pub const LOCATION_WEIGHT: f64 = 1.0;
+
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+pub fn score(user: &User, terms: &[String]) -> f64 {
+    let fields = [
+        (&user.name, NAME_WEIGHT),
+        (&user.title, TITLE_WEIGHT),
+        (&user.location, LOCATION_WEIGHT),
+    ];
+    fields
+        .iter()
+        .flat_map(|(text, weight)| {
+            tokenize(text)
+                .into_iter()
+                .map(move |token| (token, *weight))
+        })
+        .map(|(token, weight)| {
+            terms
+                .iter()
+                .map(|term| {
+                    if token == *term {
+                        weight
+                    } else if token.starts_with(term.as_str()) {
+                        weight / 2.0
+                    } else {
+                        0.0
+                    }
+                })
+                .sum::<f64>()
+        })
+        .sum()
+}
+
+pub fn search<'a>(
+    users: impl IntoIterator<Item = &'a User>,
+    query: &UserSearchQuery,
+) -> Vec<UserSearchResult> {
+    let terms = tokenize(&query.q);
+    let mut results: Vec<UserSearchResult> = users
+        .into_iter()
+        .map(|user| UserSearchResult {
+            user: user.clone(),
+            score: score(user, &terms),
+        })
+        .filter(|result| result.score > 0.0)
+        .collect();
+    results.sort_by(|a, b| b.score.total_cmp(&a.score));
+    results.truncate(query.limit() as usize);
+    results
+}
@@ -0,0 +1,484 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+use crate::database::error::RepositoryError;
+use crate::database::repository::{ApiKeyRepository, AuditRepository};
+use crate::models::api_key_model::ApiKey;
+use crate::models::audit_model::{AuditQuery, AuditRecord};
+use crate::models::batch_model::UserWrite;
+use crate::models::user_model::{User, UserCursor, UserId, UserPage, UserQuery, UserSortField};
+use mongodb::bson::oid::ObjectId;
+use sqlx::database::HasArguments;
+use sqlx::types::Json;
+use sqlx::{Arguments, Database, Encode, Executor, FromRow, IntoArguments, Pool, Type};
+use std::fmt::Display;
+
+// What differs between the SQL backends besides the queries of the users
+#[async_trait]
+pub trait Dialect: Database {
+    // The column of the audit records, so they are filtered and sorted by their time
+    type Timestamp: for<'q> Encode<'q, Self> + Type<Self> + Send;
+
+    fn timestamp(time: DateTime<Utc>) -> Self::Timestamp;
+
+    // Same variants as the errors of MongoRepo, so the API responds the same with every backend
+    fn map_error(
+        err: sqlx::Error,
+        fallback: impl FnOnce(sqlx::Error) -> RepositoryError,
+    ) -> RepositoryError;
+
+    // Appends the condition that the field starts with the value, matched literally
+    fn push_prefix(builder: &mut SqlBuilder<'_, Self>, field: &str, value: &str);
+
+    // One write of an atomic bulk write, in its transaction
+    async fn write_user(
+        connection: &mut Self::Connection,
+        write: UserWrite,
+    ) -> Result<UserId, RepositoryError>;
+
+    fn save_error(err: sqlx::Error) -> RepositoryError {
+        Self::map_error(err, |err| RepositoryError::CreateUpdateUser(Box::from(err)))
+    }
+
+    fn read_error(err: sqlx::Error) -> RepositoryError {
+        Self::map_error(err, |err| RepositoryError::GeneralError(err.to_string()))
+    }
+
+    fn api_key_error(err: sqlx::Error) -> RepositoryError {
+        Self::map_error(err, |err| {
+            RepositoryError::CreateUpdateApiKey(Box::from(err))
+        })
+    }
+}
+
+// A query and its arguments, with the placeholders of the dialect, e.g. ? with SQLite and $1 with Postgres.
+// Unlike with QueryBuilder, the query isn't borrowed for as long as its arguments, which generic code can't
+// express
+pub struct SqlBuilder<'q, D: Database> {
+    sql: String,
+    arguments: <D as HasArguments<'q>>::Arguments,
+}
+
+impl<'q, D: Database> SqlBuilder<'q, D> {
+    pub fn new(sql: impl Display) -> Self {
+        SqlBuilder {
+            sql: sql.to_string(),
+            arguments: Default::default(),
+        }
+    }
+
+    pub fn push(&mut self, sql: impl Display) -> &mut Self {
+        self.sql.push_str(&sql.to_string());
+        self
+    }
+
+    pub fn push_bind<T>(&mut self, value: T) -> &mut Self
+    where
+        T: 'q + Send + Encode<'q, D> + Type<D>,
+    {
+        self.arguments.add(value);
+        self.arguments
+            .format_placeholder(&mut self.sql)
+            .expect("Writing to a String can't fail");
+        self
+    }
+
+    // The query can then borrow the SQL for as long as the arguments
+    fn into_parts(self) -> (String, <D as HasArguments<'q>>::Arguments) {
+        (self.sql, self.arguments)
+    }
+}
+
+// The repository of a SQL backend, the queries of the users are written for each dialect
+#[derive(Debug)]
+pub struct SqlRepo<D: Database> {
+    pub(crate) pool: Pool<D>,
+}
+
+impl<D: Database> Clone for SqlRepo<D> {
+    fn clone(&self) -> Self {
+        SqlRepo {
+            pool: self.pool.clone(),
+        }
+    }
+}
+
+// The ids are the hex of ObjectIds, the same as with MongoDB
+pub fn parse_object_id(id: &str) -> Result<ObjectId, RepositoryError> {
+    ObjectId::parse_str(id).map_err(|_| RepositoryError::InvalidId(id.to_string()))
+}
+
+pub fn not_found(id: &UserId) -> RepositoryError {
+    RepositoryError::NotFound(format!("No user found with id {}", id))
+}
+
+// The version of the user when it was written, None when no user has the id
+pub fn check_version(
+    id: &UserId,
+    version: Option<i64>,
+    expected_version: Option<u64>,
+) -> Result<(), RepositoryError> {
+    match (version, expected_version) {
+        (None, _) => Err(not_found(id)),
+        (Some(version), Some(expected)) if version as u64 != expected => {
+            Err(RepositoryError::VersionMismatch(format!(
+                "User {} is no longer at version {}",
+                id, expected
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Read after a write filtered on the version matched no user. The versions only grow, so it still tells why
+pub async fn current_version<'c, D: Dialect>(
+    executor: impl Executor<'c, Database = D>,
+    id: &UserId,
+) -> Result<Option<i64>, RepositoryError>
+where
+    for<'q> <D as HasArguments<'q>>::Arguments: IntoArguments<'q, D>,
+    for<'q> String: Encode<'q, D> + Type<D>,
+    for<'r> (Option<i64>,): FromRow<'r, D::Row>,
+{
+    let mut builder = SqlBuilder::new("SELECT (SELECT version FROM users WHERE id = ");
+    builder.push_bind(id.to_string()).push(")");
+    let (sql, arguments) = builder.into_parts();
+    let (version,): (Option<i64>,) = sqlx::query_as_with(&sql, arguments)
+        .fetch_one(executor)
+        .await
+        .map_err(D::read_error)?;
+    Ok(version)
+}
+
+#[derive(FromRow)]
+pub struct UserRow {
+    id: String,
+    name: String,
+    location: String,
+    title: String,
+    version: i64,
+}
+
+impl TryFrom<UserRow> for User {
+    type Error = RepositoryError;
+
+    fn try_from(row: UserRow) -> Result<Self, Self::Error> {
+        Ok(User {
+            id: Some(parse_object_id(&row.id)?.into()),
+            name: row.name,
+            location: row.location,
+            title: row.title,
+            version: row.version as u64,
+        })
+    }
+}
+
+#[derive(FromRow)]
+pub struct SearchRow {
+    #[sqlx(flatten)]
+    pub user: UserRow,
+    pub score: f64,
+}
+
+fn column(field: UserSortField) -> &'static str {
+    match field {
+        UserSortField::Id => "id",
+        UserSortField::Name => "name",
+        UserSortField::Location => "location",
+        UserSortField::Title => "title",
+    }
+}
+
+impl<D: Dialect> SqlRepo<D>
+where
+    for<'c> &'c mut D::Connection: Executor<'c, Database = D>,
+    for<'q> <D as HasArguments<'q>>::Arguments: IntoArguments<'q, D>,
+    for<'q> String: Encode<'q, D> + Type<D>,
+    for<'q> i64: Encode<'q, D> + Type<D>,
+    for<'r> UserRow: FromRow<'r, D::Row>,
+    for<'r> (i64,): FromRow<'r, D::Row>,
+{
+    // Appends the filters of the query as a WHERE clause
+    fn push_filters(builder: &mut SqlBuilder<'_, D>, query: &UserQuery) {
+        for (index, (field, value, prefix)) in query.filters().into_iter().enumerate() {
+            builder.push(if index == 0 { " WHERE " } else { " AND " });
+            match prefix {
+                true => D::push_prefix(builder, field, value),
+                false => {
+                    builder.push(field).push(" = ").push_bind(value.to_string());
+                }
+            };
+        }
+    }
+
+    pub async fn users_with_ids(&self, ids: Vec<UserId>) -> Result<Vec<User>, RepositoryError> {
+        let ids: Vec<&UserId> = ids
+            .iter()
+            .filter(|id| parse_object_id(id.as_str()).is_ok())
+            .collect();
+        if ids.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut builder =
+            SqlBuilder::new("SELECT id, name, location, title, version FROM users WHERE id IN (");
+        for (index, id) in ids.into_iter().enumerate() {
+            if index > 0 {
+                builder.push(", ");
+            }
+            builder.push_bind(id.to_string());
+        }
+        builder.push(")");
+        let (sql, arguments) = builder.into_parts();
+        let rows: Vec<UserRow> = sqlx::query_as_with(&sql, arguments)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(D::read_error)?;
+        rows.into_iter().map(User::try_from).collect()
+    }
+
+    pub async fn page_of_users(&self, query: UserQuery) -> Result<UserPage, RepositoryError> {
+        let sort = query.sort();
+        let limit = query.limit();
+        let total = match query.count {
+            Some(true) => {
+                let mut builder = SqlBuilder::new("SELECT COUNT(*) FROM users");
+                Self::push_filters(&mut builder, &query);
+                let (sql, arguments) = builder.into_parts();
+                let (total,): (i64,) = sqlx::query_as_with(&sql, arguments)
+                    .fetch_one(&self.pool)
+                    .await
+                    .map_err(D::read_error)?;
+                Some(total as u64)
+            }
+            _ => None,
+        };
+
+        let mut builder = SqlBuilder::new("SELECT id, name, location, title, version FROM users");
+        Self::push_filters(&mut builder, &query);
+        // The condition of after_cursor in MongoRepo, as a comparison of rows
+        if let Some(cursor) = &query.after {
+            let operator = if cursor.sort.descending { " < " } else { " > " };
+            builder.push(match query.filters().is_empty() {
+                true => " WHERE ",
+                false => " AND ",
+            });
+            match (cursor.sort.field, &cursor.value) {
+                (UserSortField::Id, _) | (_, None) => {
+                    builder
+                        .push("id")
+                        .push(operator)
+                        .push_bind(cursor.id.to_string());
+                }
+                (field, Some(value)) => {
+                    builder
+                        .push(format!("({}, id)", column(field)))
+                        .push(operator)
+                        .push("(")
+                        .push_bind(value.to_string())
+                        .push(", ")
+                        .push_bind(cursor.id.to_string())
+                        .push(")");
+                }
+            }
+        }
+        // The order of sort_document in MongoRepo
+        let direction = if sort.descending { "DESC" } else { "ASC" };
+        match sort.field {
+            UserSortField::Id => builder.push(format!(" ORDER BY id {}", direction)),
+            field => builder.push(format!(
+                " ORDER BY {} {}, id {}",
+                column(field),
+                direction,
+                direction
+            )),
+        };
+        builder.push(" LIMIT ").push_bind(limit as i64 + 1);
+        let (sql, arguments) = builder.into_parts();
+        let rows: Vec<UserRow> = sqlx::query_as_with(&sql, arguments)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(D::read_error)?;
+        let mut users = rows
+            .into_iter()
+            .map(User::try_from)
+            .collect::<Result<Vec<User>, RepositoryError>>()?;
+        let next = if users.len() > limit as usize {
+            users.truncate(limit as usize);
+            users.last().and_then(|user| UserCursor::after(user, sort))
+        } else {
+            None
+        };
+        Ok(UserPage { users, next, total })
+    }
+
+    // Stops at the first failure, the transaction is then rolled back when it's dropped without a commit
+    pub async fn write_in_transaction(
+        &self,
+        writes: Vec<UserWrite>,
+    ) -> Result<Vec<Result<UserId, RepositoryError>>, RepositoryError> {
+        let mut transaction = self.pool.begin().await.map_err(D::save_error)?;
+        let mut results = vec![];
+        for write in writes {
+            let result = D::write_user(&mut transaction, write).await;
+            let failed = result.is_err();
+            results.push(result);
+            if failed {
+                return Ok(results);
+            }
+        }
+        transaction.commit().await.map_err(D::save_error)?;
+        Ok(results)
+    }
+}
+
+#[async_trait]
+impl<D: Dialect> ApiKeyRepository for SqlRepo<D>
+where
+    for<'c> &'c mut D::Connection: Executor<'c, Database = D>,
+    for<'q> <D as HasArguments<'q>>::Arguments: IntoArguments<'q, D>,
+    for<'q> String: Encode<'q, D> + Type<D>,
+    for<'q> Json<ApiKey>: Encode<'q, D> + Type<D>,
+    for<'q> DateTime<Utc>: Encode<'q, D> + Type<D>,
+    for<'r> (String,): FromRow<'r, D::Row>,
+    for<'r> (Json<ApiKey>,): FromRow<'r, D::Row>,
+{
+    // sqlx 0.7 keeps stepping a failed SQLite statement until its result is dropped, so an insert
+    // rejected by the unique name could still succeed once the other key is revoked. In a
+    // transaction, these retries are rolled back with it
+    async fn create_api_key(&self, api_key: ApiKey) -> Result<(), RepositoryError> {
+        let mut builder =
+            SqlBuilder::new("INSERT INTO api_keys (key_id, created_at, api_key) VALUES (");
+        builder
+            .push_bind(api_key.key_id.clone())
+            .push(", ")
+            .push_bind(api_key.created_at)
+            .push(", ");
+        builder.push_bind(Json(api_key)).push(")");
+        let (sql, arguments) = builder.into_parts();
+        let mut transaction = self.pool.begin().await.map_err(D::api_key_error)?;
+        sqlx::query_with(&sql, arguments)
+            .execute(&mut *transaction)
+            .await
+            .map_err(D::api_key_error)?;
+        transaction.commit().await.map_err(D::api_key_error)
+    }
+
+    async fn get_api_key(&self, key_id: &str) -> Result<Option<ApiKey>, RepositoryError> {
+        let mut builder = SqlBuilder::new("SELECT api_key FROM api_keys WHERE key_id = ");
+        builder.push_bind(key_id.to_string());
+        let (sql, arguments) = builder.into_parts();
+        let row: Option<(Json<ApiKey>,)> = sqlx::query_as_with(&sql, arguments)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(D::read_error)?;
+        Ok(row.map(|(api_key,)| api_key.0))
+    }
+
+    async fn list_api_keys(&self) -> Result<Vec<ApiKey>, RepositoryError> {
+        let rows: Vec<(Json<ApiKey>,)> =
+            sqlx::query_as("SELECT api_key FROM api_keys ORDER BY created_at, key_id")
+                .fetch_all(&self.pool)
+                .await
+                .map_err(D::read_error)?;
+        Ok(rows.into_iter().map(|(api_key,)| api_key.0).collect())
+    }
+
+    async fn update_api_key(&self, api_key: ApiKey) -> Result<bool, RepositoryError> {
+        let mut builder = SqlBuilder::new("UPDATE api_keys SET api_key = ");
+        builder.push_bind(Json(api_key.clone()));
+        builder
+            .push(" WHERE key_id = ")
+            .push_bind(api_key.key_id)
+            .push(" RETURNING key_id");
+        let (sql, arguments) = builder.into_parts();
+        let mut transaction = self.pool.begin().await.map_err(D::api_key_error)?;
+        let updated: Option<(String,)> = sqlx::query_as_with(&sql, arguments)
+            .fetch_optional(&mut *transaction)
+            .await
+            .map_err(D::api_key_error)?;
+        transaction.commit().await.map_err(D::api_key_error)?;
+        Ok(updated.is_some())
+    }
+}
+
+#[async_trait]
+impl<D: Dialect> AuditRepository for SqlRepo<D>
+where
+    for<'c> &'c mut D::Connection: Executor<'c, Database = D>,
+    for<'q> <D as HasArguments<'q>>::Arguments: IntoArguments<'q, D>,
+    for<'q> String: Encode<'q, D> + Type<D>,
+    for<'q> Option<String>: Encode<'q, D> + Type<D>,
+    for<'q> i64: Encode<'q, D> + Type<D>,
+    for<'q> Json<AuditRecord>: Encode<'q, D> + Type<D>,
+    for<'r> (Json<AuditRecord>,): FromRow<'r, D::Row>,
+{
+    async fn record_audit(&self, record: AuditRecord) -> Result<(), RepositoryError> {
+        self.record_audits(vec![record]).await
+    }
+
+    async fn record_audits(&self, records: Vec<AuditRecord>) -> Result<(), RepositoryError> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        let mut builder = SqlBuilder::new(
+            "INSERT INTO audit_records (actor_subject, target_id, recorded_at, record) VALUES ",
+        );
+        for (index, record) in records.into_iter().enumerate() {
+            if index > 0 {
+                builder.push(", ");
+            }
+            builder
+                .push("(")
+                .push_bind(record.actor.subject.clone())
+                .push(", ")
+                .push_bind(record.target_id.clone())
+                .push(", ")
+                .push_bind(D::timestamp(record.timestamp))
+                .push(", ");
+            builder.push_bind(Json(record)).push(")");
+        }
+        let (sql, arguments) = builder.into_parts();
+        sqlx::query_with(&sql, arguments)
+            .execute(&self.pool)
+            .await
+            .map_err(|err| {
+                D::map_error(err, |err| {
+                    RepositoryError::CreateAuditRecord(Box::from(err))
+                })
+            })?;
+        Ok(())
+    }
+
+    async fn find_audit_records(
+        &self,
+        query: AuditQuery,
+    ) -> Result<Vec<AuditRecord>, RepositoryError> {
+        let limit = query.limit();
+        let mut builder = SqlBuilder::new("SELECT record FROM audit_records WHERE TRUE");
+        if let Some(actor) = query.actor {
+            builder.push(" AND actor_subject = ").push_bind(actor);
+        }
+        if let Some(target) = query.target {
+            builder.push(" AND target_id = ").push_bind(target);
+        }
+        if let Some(from) = query.from {
+            builder
+                .push(" AND recorded_at >= ")
+                .push_bind(D::timestamp(from));
+        }
+        if let Some(to) = query.to {
+            builder
+                .push(" AND recorded_at <= ")
+                .push_bind(D::timestamp(to));
+        }
+        builder
+            .push(" ORDER BY recorded_at DESC, id DESC LIMIT ")
+            .push_bind(limit as i64);
+        let (sql, arguments) = builder.into_parts();
+        let rows: Vec<(Json<AuditRecord>,)> = sqlx::query_as_with(&sql, arguments)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(D::read_error)?;
+        Ok(rows.into_iter().map(|(record,)| record.0).collect())
+    }
+}
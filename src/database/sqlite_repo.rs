@@ -0,0 +1,377 @@
+use async_trait::async_trait;
+
+use crate::database::error::RepositoryError;
+use crate::database::repository::{paged_stream, write_each, Repository, UserStream};
+use crate::database::search::{self, LOCATION_WEIGHT, NAME_WEIGHT, TITLE_WEIGHT};
+use crate::database::sql_common::{
+    check_version, current_version, not_found, parse_object_id, Dialect, SearchRow, SqlBuilder,
+    SqlRepo, UserRow,
+};
+use crate::models::batch_model::{BulkWriteOptions, UserWrite};
+use crate::models::user_model::{
+    CreateUserResult, DeleteUserResult, UpdateUserResult, User, UserId, UserPage, UserPatch,
+    UserQuery, UserSearchQuery, UserSearchResult,
+};
+use chrono::{DateTime, Utc};
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous};
+use sqlx::{Sqlite, SqliteConnection, SqliteExecutor};
+use std::time::Duration;
+
+// The sqlite section of config.yaml, used with storage: sqlite
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct SqliteConfig {
+    // Created with the schema when it doesn't exist
+    #[serde(default = "default_path")]
+    pub path: String,
+    #[serde(default = "default_max_connections")]
+    pub max_connections: u32,
+    // How long a write waits for the one of another connection before failing with a 503
+    #[serde(default = "default_busy_timeout_secs")]
+    pub busy_timeout_secs: u64,
+}
+
+fn default_path() -> String {
+    "./users.db".to_string()
+}
+
+fn default_max_connections() -> u32 {
+    4
+}
+
+fn default_busy_timeout_secs() -> u64 {
+    5
+}
+
+impl Default for SqliteConfig {
+    fn default() -> Self {
+        SqliteConfig {
+            path: default_path(),
+            max_connections: default_max_connections(),
+            busy_timeout_secs: default_busy_timeout_secs(),
+        }
+    }
+}
+
+// Each connection runs on its own thread, so the queries don't block the actix workers
+pub type SqliteRepo = SqlRepo<Sqlite>;
+
+impl SqliteRepo {
+    // With WAL, the reads don't wait for the writes, and the schema is migrated on startup
+    pub async fn init(config: &SqliteConfig) -> Result<Self, RepositoryError> {
+        let options = SqliteConnectOptions::new()
+            .filename(&config.path)
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal)
+            .synchronous(SqliteSynchronous::Normal)
+            .busy_timeout(Duration::from_secs(config.busy_timeout_secs));
+        let pool = SqlitePoolOptions::new()
+            .max_connections(config.max_connections)
+            .connect_with(options)
+            .await
+            .map_err(|err| {
+                Sqlite::map_error(err, |err| RepositoryError::GeneralError(err.to_string()))
+            })?;
+        sqlx::migrate!("./migrations/sqlite")
+            .run(&pool)
+            .await
+            .map_err(|err| {
+                RepositoryError::GeneralError(format!("Error migrating the database: {}", err))
+            })?;
+        Ok(SqlRepo { pool })
+    }
+}
+
+// SQLITE_BUSY and its extended codes, the busy timeout expired
+const BUSY: [&str; 3] = ["5", "261", "517"];
+
+#[async_trait]
+impl Dialect for Sqlite {
+    // In milliseconds since the epoch, so the records are compared and sorted as numbers
+    type Timestamp = i64;
+
+    fn timestamp(time: DateTime<Utc>) -> i64 {
+        time.timestamp_millis()
+    }
+
+    fn map_error(
+        err: sqlx::Error,
+        fallback: impl FnOnce(sqlx::Error) -> RepositoryError,
+    ) -> RepositoryError {
+        match &err {
+            sqlx::Error::Database(db_error) if db_error.is_unique_violation() => {
+                RepositoryError::Conflict(db_error.message().to_string())
+            }
+            sqlx::Error::Database(db_error)
+                if db_error
+                    .code()
+                    .is_some_and(|code| BUSY.contains(&code.as_ref())) =>
+            {
+                RepositoryError::Timeout(db_error.message().to_string())
+            }
+            sqlx::Error::PoolTimedOut => RepositoryError::Timeout(err.to_string()),
+            sqlx::Error::Io(_) | sqlx::Error::PoolClosed | sqlx::Error::WorkerCrashed => {
+                RepositoryError::Unavailable(err.to_string())
+            }
+            _ => fallback(err),
+        }
+    }
+
+    // Case sensitively, unlike LIKE
+    fn push_prefix(builder: &mut SqlBuilder<'_, Sqlite>, field: &str, value: &str) {
+        builder
+            .push(format!("substr({}, 1, length(", field))
+            .push_bind(value.to_string())
+            .push(")) = ")
+            .push_bind(value.to_string());
+    }
+
+    async fn write_user(
+        connection: &mut SqliteConnection,
+        write: UserWrite,
+    ) -> Result<UserId, RepositoryError> {
+        match write {
+            UserWrite::Create(user) => create_user(connection, user).await.map(|result| result.id),
+            UserWrite::Update(id, user) => {
+                update_user(connection, &id, user, None).await.map(|_| id)
+            }
+            UserWrite::Delete(id) => delete_user(connection, &id, None).await.map(|_| id),
+        }
+    }
+}
+
+// Returns false when a user already has the id
+async fn insert_user(
+    executor: impl SqliteExecutor<'_>,
+    id: &UserId,
+    new_user: User,
+) -> Result<bool, RepositoryError> {
+    let result = sqlx::query(
+        "INSERT INTO users (id, name, location, title) VALUES (?, ?, ?, ?) \
+         ON CONFLICT (id) DO NOTHING",
+    )
+    .bind(id.as_str())
+    .bind(new_user.name)
+    .bind(new_user.location)
+    .bind(new_user.title)
+    .execute(executor)
+    .await
+    .map_err(Sqlite::save_error)?;
+    Ok(result.rows_affected() == 1)
+}
+
+async fn create_user(
+    executor: impl SqliteExecutor<'_>,
+    new_user: User,
+) -> Result<CreateUserResult, RepositoryError> {
+    let id = UserId::from(ObjectId::new());
+    match insert_user(executor, &id, new_user).await? {
+        true => Ok(CreateUserResult { id }),
+        false => Err(RepositoryError::Conflict(format!(
+            "A user with id {} already exists",
+            id
+        ))),
+    }
+}
+
+async fn update_user(
+    executor: &mut SqliteConnection,
+    id: &UserId,
+    user: User,
+    expected_version: Option<u64>,
+) -> Result<UpdateUserResult, RepositoryError> {
+    parse_object_id(id.as_str())?;
+    // A user with the same fields is matched, but not modified, like with MongoDB
+    let modified = sqlx::query(
+        "UPDATE users SET name = ?2, location = ?3, title = ?4, version = version + 1 \
+         WHERE id = ?1 AND (?5 IS NULL OR version = ?5) \
+         AND (name IS NOT ?2 OR location IS NOT ?3 OR title IS NOT ?4)",
+    )
+    .bind(id.as_str())
+    .bind(user.name)
+    .bind(user.location)
+    .bind(user.title)
+    .bind(expected_version.map(|version| version as i64))
+    .execute(&mut *executor)
+    .await
+    .map_err(Sqlite::save_error)?
+    .rows_affected();
+    if modified == 0 {
+        let version = current_version(&mut *executor, id).await?;
+        check_version(id, version, expected_version)?;
+    }
+    Ok(UpdateUserResult {
+        matched_count: 1,
+        modified_count: modified,
+        upserted_id: "".to_string(),
+    })
+}
+
+async fn delete_user(
+    executor: &mut SqliteConnection,
+    id: &UserId,
+    expected_version: Option<u64>,
+) -> Result<DeleteUserResult, RepositoryError> {
+    parse_object_id(id.as_str())?;
+    let result = sqlx::query("DELETE FROM users WHERE id = ?1 AND (?2 IS NULL OR version = ?2)")
+        .bind(id.as_str())
+        .bind(expected_version.map(|version| version as i64))
+        .execute(&mut *executor)
+        .await
+        .map_err(|err| Sqlite::map_error(err, |err| RepositoryError::DeleteUser(Box::from(err))))?;
+    if result.rows_affected() == 0 {
+        let version = current_version(&mut *executor, id).await?;
+        check_version(id, version, expected_version)?;
+        return Err(not_found(id));
+    }
+    Ok(DeleteUserResult {
+        deleted_count: result.rows_affected(),
+    })
+}
+
+#[async_trait]
+impl Repository for SqliteRepo {
+    async fn create_user(&self, new_user: User) -> Result<CreateUserResult, RepositoryError> {
+        create_user(&self.pool, new_user).await
+    }
+
+    // In a single transaction, so the batch is written at once
+    async fn create_users(
+        &self,
+        new_users: Vec<User>,
+    ) -> Result<Vec<Result<CreateUserResult, RepositoryError>>, RepositoryError> {
+        let mut transaction = self.pool.begin().await.map_err(Sqlite::save_error)?;
+        let mut results = vec![];
+        for new_user in new_users {
+            results.push(create_user(&mut *transaction, new_user).await);
+        }
+        transaction.commit().await.map_err(Sqlite::save_error)?;
+        Ok(results)
+    }
+
+    async fn get_user(&self, id: &UserId) -> Result<Option<User>, RepositoryError> {
+        parse_object_id(id.as_str())?;
+        let row: Option<UserRow> =
+            sqlx::query_as("SELECT id, name, location, title, version FROM users WHERE id = ?")
+                .bind(id.as_str())
+                .fetch_optional(&self.pool)
+                .await
+                .map_err(Sqlite::read_error)?;
+        row.map(User::try_from).transpose()
+    }
+
+    async fn get_users(&self, ids: Vec<UserId>) -> Result<Vec<User>, RepositoryError> {
+        self.users_with_ids(ids).await
+    }
+
+    async fn update_user(
+        &self,
+        id: &UserId,
+        user: User,
+        expected_version: Option<u64>,
+    ) -> Result<UpdateUserResult, RepositoryError> {
+        let mut connection = self.pool.acquire().await.map_err(Sqlite::save_error)?;
+        update_user(&mut connection, id, user, expected_version).await
+    }
+
+    async fn delete_user(
+        &self,
+        id: &UserId,
+        expected_version: Option<u64>,
+    ) -> Result<DeleteUserResult, RepositoryError> {
+        let mut connection = self.pool.acquire().await.map_err(Sqlite::save_error)?;
+        delete_user(&mut connection, id, expected_version).await
+    }
+
+    async fn patch_user(
+        &self,
+        id: &UserId,
+        patch: UserPatch,
+        expected_version: Option<u64>,
+    ) -> Result<User, RepositoryError> {
+        parse_object_id(id.as_str())?;
+        let increment = !patch.is_empty() as i64;
+        let mut connection = self.pool.acquire().await.map_err(Sqlite::save_error)?;
+        let row: Option<UserRow> = sqlx::query_as(
+            "UPDATE users SET name = COALESCE(?2, name), location = COALESCE(?3, location), \
+             title = COALESCE(?4, title), version = version + ?5 \
+             WHERE id = ?1 AND (?6 IS NULL OR version = ?6) \
+             RETURNING id, name, location, title, version",
+        )
+        .bind(id.as_str())
+        .bind(patch.name)
+        .bind(patch.location)
+        .bind(patch.title)
+        .bind(increment)
+        .bind(expected_version.map(|version| version as i64))
+        .fetch_optional(&mut *connection)
+        .await
+        .map_err(Sqlite::save_error)?;
+        if let Some(row) = row {
+            return row.try_into();
+        }
+        let version = current_version(&mut *connection, id).await?;
+        check_version(id, version, expected_version)?;
+        Err(not_found(id))
+    }
+
+    async fn list_users(&self, query: UserQuery) -> Result<UserPage, RepositoryError> {
+        self.page_of_users(query).await
+    }
+
+    async fn stream_users(&self, query: UserQuery) -> Result<UserStream, RepositoryError> {
+        Ok(paged_stream(self.clone(), query))
+    }
+
+    // Any word of the query matches the start of words of the users, ranked by bm25 with the weights of the
+    // MongoDB text index
+    async fn search_users(
+        &self,
+        query: UserSearchQuery,
+    ) -> Result<Vec<UserSearchResult>, RepositoryError> {
+        let terms = search::tokenize(&query.q);
+        if terms.is_empty() {
+            return Ok(vec![]);
+        }
+        let fts_query = terms
+            .iter()
+            .map(|term| format!("\"{}\"*", term))
+            .collect::<Vec<_>>()
+            .join(" OR ");
+        // bm25 is lower for the better matches, the columns are in the order of the index: name, title, location
+        let rows: Vec<SearchRow> = sqlx::query_as(
+            "SELECT users.id, users.name, users.location, users.title, users.version, \
+             -bm25(users_search, ?, ?, ?) AS score \
+             FROM users_search JOIN users ON users.rowid = users_search.rowid \
+             WHERE users_search MATCH ? ORDER BY score DESC, users.id LIMIT ?",
+        )
+        .bind(NAME_WEIGHT)
+        .bind(TITLE_WEIGHT)
+        .bind(LOCATION_WEIGHT)
+        .bind(fts_query)
+        .bind(query.limit() as i64)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(Sqlite::read_error)?;
+        rows.into_iter()
+            .map(|row| {
+                Ok(UserSearchResult {
+                    user: row.user.try_into()?,
+                    score: row.score,
+                })
+            })
+            .collect()
+    }
+
+    async fn bulk_write(
+        &self,
+        writes: Vec<UserWrite>,
+        options: BulkWriteOptions,
+    ) -> Result<Vec<Result<UserId, RepositoryError>>, RepositoryError> {
+        if !options.atomic {
+            return Ok(write_each(self, writes, options.ordered).await);
+        }
+        self.write_in_transaction(writes).await
+    }
+}
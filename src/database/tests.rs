@@ -1,8 +1,26 @@
 #[cfg(test)]
 mod tests {
+    use crate::auth::api_key::ApiKeyEntry;
+    use crate::auth::principal::Principal;
+    use crate::database::error::RepositoryError;
+    use crate::database::memory_repo::InMemoryRepo;
     use crate::database::mongodb_repo::MongoRepo;
-    use crate::database::repository::Repository;
-    use crate::models::user_model::User;
+    use crate::database::postgres_repo::{PostgresConfig, PostgresRepo};
+    use crate::database::repository::{
+        write_each, ApiKeyRepository, AuditRepository, MockRepository, Repository,
+    };
+    use crate::database::search;
+    use crate::database::sqlite_repo::{SqliteConfig, SqliteRepo};
+    use crate::models::api_key_model::ApiKey;
+    use crate::models::audit_model::{AuditAction, AuditQuery, AuditRecord};
+    use crate::models::batch_model::{BulkWriteOptions, UserWrite};
+    use crate::models::user_model::{
+        User, UserId, UserPatch, UserQuery, UserSearchQuery, UserSort, UserSortField,
+    };
+    use chrono::Utc;
+    use futures_util::TryStreamExt;
+    use mongodb::bson::oid::ObjectId;
+    use std::collections::HashSet;
     use testcontainers::clients::Cli;
     use testcontainers::GenericImage;
 
@@ -24,13 +42,14 @@ mod tests {
                 name: "test".to_string(),
                 location: "test".to_string(),
                 title: "test".to_string(),
+                version: 0,
             })
             .await;
         assert!(!create_result.is_err());
         let user_id = create_result.unwrap().id;
-        assert!(!user_id.is_empty());
+        assert!(ObjectId::parse_str(user_id.as_str()).is_ok());
 
-        let get_user_result = mongo_repo.get_user(user_id.clone()).await;
+        let get_user_result = mongo_repo.get_user(&user_id).await;
         assert!(!get_user_result.is_err());
         let user = get_user_result.unwrap().unwrap();
         assert_eq!(user.name, "test");
@@ -43,13 +62,578 @@ mod tests {
                     name: "updated".to_string(),
                     location: "updated".to_string(),
                     title: "updated".to_string(),
+                    version: 0,
                 },
+                None,
             )
             .await;
         assert!(!update_user_result.is_err());
         assert_eq!(update_user_result.unwrap().modified_count, 1);
+        // The update moved the user to version 2, so a write of version 1 is filtered out
+        assert!(matches!(
+            mongo_repo
+                .update_user(&user_id, user.clone(), Some(1))
+                .await,
+            Err(RepositoryError::VersionMismatch(_))
+        ));
 
-        let delete_user_result = mongo_repo.delete_user(&user_id.clone()).await;
-        assert!(!delete_user_result.is_err())
+        let patched = mongo_repo
+            .patch_user(
+                &user_id,
+                UserPatch {
+                    title: Some("patched".to_string()),
+                    ..Default::default()
+                },
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(patched.title, "patched");
+        assert_eq!(patched.name, "updated");
+
+        mongo_repo
+            .create_user(User {
+                id: None,
+                name: "another".to_string(),
+                location: "test".to_string(),
+                title: "test".to_string(),
+                version: 0,
+            })
+            .await
+            .unwrap();
+        let query = UserQuery {
+            location: Some("test".to_string()),
+            sort: Some(UserSort {
+                field: UserSortField::Name,
+                descending: true,
+            }),
+            limit: Some(1),
+            count: Some(true),
+            ..Default::default()
+        };
+        let first_page = mongo_repo.list_users(query.clone()).await.unwrap();
+        assert_eq!(first_page.total, Some(2));
+        assert_eq!(first_page.users[0].name, "updated");
+        let second_page = mongo_repo
+            .list_users(UserQuery {
+                after: first_page.next,
+                ..query
+            })
+            .await
+            .unwrap();
+        assert_eq!(second_page.users[0].name, "another");
+        assert!(second_page.next.is_none());
+
+        let prefixed = mongo_repo
+            .list_users(UserQuery {
+                name_prefix: Some("upd".to_string()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(prefixed.users.len(), 1);
+
+        let imported = mongo_repo
+            .create_users(vec![User {
+                id: None,
+                name: "imported".to_string(),
+                location: "elsewhere".to_string(),
+                title: "test".to_string(),
+                version: 0,
+            }])
+            .await
+            .unwrap();
+        let imported_id = imported[0].as_ref().unwrap().id.to_owned();
+        assert!(mongo_repo.get_user(&imported_id).await.unwrap().is_some());
+        mongo_repo.delete_user(&imported_id, None).await.unwrap();
+
+        let streamed: Vec<User> = mongo_repo
+            .stream_users(UserQuery::default())
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(streamed.len(), 2);
+
+        let found = mongo_repo
+            .search_users(UserSearchQuery {
+                q: "updated".to_string(),
+                limit: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 1);
+        assert!(found[0].score > 0.0);
+
+        // The second write fails, an ordered bulk write stops there, an unordered one runs the third write too
+        let writes = |name: &str| {
+            vec![
+                UserWrite::Create(User {
+                    id: None,
+                    name: name.to_string(),
+                    location: "Porto".to_string(),
+                    title: "test".to_string(),
+                    version: 0,
+                }),
+                UserWrite::Delete(ObjectId::new().into()),
+                UserWrite::Update(user_id.clone(), user.clone()),
+            ]
+        };
+        let ordered = BulkWriteOptions {
+            ordered: true,
+            atomic: false,
+        };
+        let ordered_results = mongo_repo
+            .bulk_write(writes("ordered"), ordered)
+            .await
+            .unwrap();
+        assert_eq!(ordered_results.len(), 2);
+        assert!(matches!(
+            ordered_results[1],
+            Err(RepositoryError::NotFound(_))
+        ));
+        let unordered_results = mongo_repo
+            .bulk_write(writes("unordered"), BulkWriteOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(unordered_results.len(), 3);
+        assert!(unordered_results[2].is_ok());
+        // The consecutive deletes are sent together, each with its own result
+        let deletes = vec![
+            UserWrite::Delete(ObjectId::new().into()),
+            UserWrite::Delete(UserId::from(1)),
+            UserWrite::Delete(ordered_results[0].as_ref().unwrap().clone()),
+            UserWrite::Delete(unordered_results[0].as_ref().unwrap().clone()),
+        ];
+        let delete_results = mongo_repo
+            .bulk_write(deletes, BulkWriteOptions::default())
+            .await
+            .unwrap();
+        assert!(matches!(
+            delete_results[0],
+            Err(RepositoryError::NotFound(_))
+        ));
+        assert!(matches!(
+            delete_results[1],
+            Err(RepositoryError::InvalidId(_))
+        ));
+        assert!(delete_results[2].is_ok() && delete_results[3].is_ok());
+        // The container is a standalone server, without transactions
+        let atomic = BulkWriteOptions {
+            ordered: true,
+            atomic: true,
+        };
+        assert!(matches!(
+            mongo_repo.bulk_write(writes("atomic"), atomic).await,
+            Err(RepositoryError::Unsupported(_))
+        ));
+
+        let delete_user_result = mongo_repo.delete_user(&user_id.clone(), None).await;
+        assert!(!delete_user_result.is_err());
+
+        assert!(matches!(
+            mongo_repo.delete_user(&user_id, None).await,
+            Err(RepositoryError::NotFound(_))
+        ));
+        assert!(matches!(
+            mongo_repo.get_user(&UserId::from(1)).await,
+            Err(RepositoryError::InvalidId(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_api_key_collection() {
+        let docker = Cli::default();
+        let image = GenericImage::new("mongo", "latest");
+        let container = docker.run(image);
+        let mongo_address = format!(
+            "mongodb://localhost:{}/",
+            container.get_host_port_ipv4(27017)
+        );
+
+        let mongo_repo = MongoRepo::init(mongo_address).await;
+
+        let mut api_key = ApiKey {
+            key_id: "key".to_string(),
+            entry: ApiKeyEntry {
+                name: "test".to_string(),
+                salt: "salt".to_string(),
+                hash: "hash".to_string(),
+                permissions: vec!["users:read".to_string()],
+                expires_at: None,
+                enabled: true,
+            },
+            created_at: Utc::now(),
+        };
+        assert!(mongo_repo.create_api_key(api_key.clone()).await.is_ok());
+
+        let stored = mongo_repo.get_api_key("key").await.unwrap().unwrap();
+        assert_eq!(stored.entry.permissions, api_key.entry.permissions);
+        assert!(mongo_repo.get_api_key("other").await.unwrap().is_none());
+        let same_name = ApiKey {
+            key_id: "other".to_string(),
+            ..api_key.clone()
+        };
+        assert!(matches!(
+            mongo_repo.create_api_key(same_name).await,
+            Err(RepositoryError::Conflict(_))
+        ));
+
+        api_key.entry.enabled = false;
+        assert!(mongo_repo.update_api_key(api_key).await.unwrap());
+        let api_keys = mongo_repo.list_api_keys().await.unwrap();
+        assert_eq!(api_keys.len(), 1);
+        assert!(!api_keys.first().unwrap().entry.enabled);
+    }
+
+    #[tokio::test]
+    async fn test_audit_collection() {
+        let docker = Cli::default();
+        let image = GenericImage::new("mongo", "latest");
+        let container = docker.run(image);
+        let mongo_address = format!(
+            "mongodb://localhost:{}/",
+            container.get_host_port_ipv4(27017)
+        );
+
+        let mongo_repo = MongoRepo::init(mongo_address).await;
+
+        let actor = Principal::api_key("ci", HashSet::new());
+        let mut older = AuditRecord::new(&actor, AuditAction::UserCreate, "1").target("a");
+        older.timestamp = Utc::now() - chrono::Duration::hours(1);
+        let newer = AuditRecord::new(&actor, AuditAction::UserDelete, "2").target("b");
+        assert!(mongo_repo.record_audit(older).await.is_ok());
+        assert!(mongo_repo.record_audit(newer).await.is_ok());
+
+        let records = mongo_repo
+            .find_audit_records(AuditQuery::default())
+            .await
+            .unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records.first().unwrap().request_id, "2");
+
+        let records = mongo_repo
+            .find_audit_records(AuditQuery {
+                actor: Some("ci".to_string()),
+                from: Some(Utc::now() - chrono::Duration::minutes(1)),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records.first().unwrap().target_id.as_deref(), Some("b"));
+
+        let records = mongo_repo
+            .find_audit_records(AuditQuery {
+                target: Some("a".to_string()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(records.len(), 1);
+    }
+
+    #[test]
+    fn test_tokenised_search() {
+        let user = |name: &str, title: &str| User {
+            id: None,
+            name: name.to_string(),
+            location: "Lisbon".to_string(),
+            title: title.to_string(),
+            version: 0,
+        };
+        let users = [
+            user("Engel Costa", "Manager"),
+            user("Anne Smith", "Software Engineer"),
+            user("Rui Silva", "Designer"),
+        ];
+        let query = |q: &str| UserSearchQuery {
+            q: q.to_string(),
+            limit: None,
+        };
+
+        // A whole word in the name ranks first, then the start of a word in the title
+        let results = search::search(&users, &query("engel"));
+        let names: Vec<&str> = results.iter().map(|r| r.user.name.as_str()).collect();
+        assert_eq!(names, vec!["Engel Costa"]);
+        let results = search::search(&users, &query("eng"));
+        let names: Vec<&str> = results.iter().map(|r| r.user.name.as_str()).collect();
+        assert_eq!(names, vec!["Engel Costa", "Anne Smith"]);
+
+        assert_eq!(search::search(&users, &query("LISBON")).len(), 3);
+        assert!(search::search(&users, &query("porto")).is_empty());
+        let limited = UserSearchQuery {
+            q: "lisbon".to_string(),
+            limit: Some(1),
+        };
+        assert_eq!(search::search(&users, &limited).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_write_each() {
+        let mut mock = MockRepository::new();
+        mock.expect_create_user()
+            .returning(|_| Err(RepositoryError::Conflict("duplicate key".to_string())));
+        mock.expect_delete_user()
+            .returning(|_, _| Ok(crate::models::user_model::DeleteUserResult { deleted_count: 1 }));
+        let writes = || {
+            vec![
+                UserWrite::Delete(UserId::from(1)),
+                UserWrite::Create(User {
+                    id: None,
+                    name: "Ana".to_string(),
+                    location: "Porto".to_string(),
+                    title: "test".to_string(),
+                    version: 0,
+                }),
+                UserWrite::Delete(UserId::from(2)),
+            ]
+        };
+
+        let results = write_each(&mock, writes(), true).await;
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].as_ref().ok(), Some(&UserId::from(1)));
+        assert!(matches!(results[1], Err(RepositoryError::Conflict(_))));
+
+        let results = write_each(&mock, writes(), false).await;
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[2].as_ref().ok(), Some(&UserId::from(2)));
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_repo() {
+        check_repository(&InMemoryRepo::default()).await;
+    }
+
+    // e.g. TEST_POSTGRES_URL=postgres://postgres@localhost:5432/users_test cargo test -- --ignored
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL server, set TEST_POSTGRES_URL"]
+    async fn test_postgres_repo() {
+        let url = std::env::var("TEST_POSTGRES_URL").expect("TEST_POSTGRES_URL isn't set");
+        let repo = PostgresRepo::init(&PostgresConfig {
+            url: url.clone(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let pool = sqlx::PgPool::connect(&url).await.unwrap();
+        sqlx::query("TRUNCATE users, api_keys, audit_records")
+            .execute(&pool)
+            .await
+            .unwrap();
+        check_repository(&repo).await;
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_repo() {
+        let path = std::env::temp_dir().join(format!("users-{}.db", ObjectId::new()));
+        let repo = SqliteRepo::init(&SqliteConfig {
+            path: path.to_string_lossy().to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        check_repository(&repo).await;
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.to_string_lossy(), suffix));
+        }
+    }
+
+    // The behaviour every backend shares, against an empty database
+    async fn check_repository(repo: &(impl Repository + ApiKeyRepository + AuditRepository)) {
+        let user = |name: &str, location: &str| User {
+            id: None,
+            name: name.to_string(),
+            location: location.to_string(),
+            title: "Engineer".to_string(),
+            version: 0,
+        };
+        let mut ids = vec![];
+        for (name, location) in [("Rui", "Porto"), ("Ana", "Lisbon"), ("Anne", "Porto")] {
+            ids.push(repo.create_user(user(name, location)).await.unwrap().id);
+        }
+        let found = repo.get_user(&ids[0]).await.unwrap().unwrap();
+        assert_eq!(found.id.as_ref(), Some(&ids[0]));
+        // The id of a deleted user has the format of the backend, but no user
+        let missing = repo.create_user(user("Tom", "Faro")).await.unwrap().id;
+        repo.delete_user(&missing, None).await.unwrap();
+        assert!(repo.get_user(&missing).await.unwrap().is_none());
+        // None of the backends generates UUIDs
+        let uuid = UserId::parse("6f1c2b1e-8d4a-4f5e-9c3b-2a1d0e9f8c7b").unwrap();
+        assert!(matches!(
+            repo.get_user(&uuid).await,
+            Err(RepositoryError::InvalidId(_))
+        ));
+        // The missing and invalid ids are left out
+        let mut found_ids: Vec<UserId> = repo
+            .get_users(vec![ids[2].clone(), missing.clone(), uuid, ids[0].clone()])
+            .await
+            .unwrap()
+            .into_iter()
+            .filter_map(|user| user.id)
+            .collect();
+        found_ids.sort();
+        let mut expected = vec![ids[0].clone(), ids[2].clone()];
+        expected.sort();
+        assert_eq!(found_ids, expected);
+
+        let updated = repo
+            .update_user(&ids[0], found.clone(), None)
+            .await
+            .unwrap();
+        assert_eq!((updated.matched_count, updated.modified_count), (1, 0));
+        // The version only changes with the user
+        assert_eq!(found.version, 1);
+        let updated = repo
+            .update_user(&ids[0], user("Rui", "Braga"), Some(1))
+            .await
+            .unwrap();
+        assert_eq!(updated.modified_count, 1);
+        let cto = UserPatch {
+            title: Some("CTO".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            repo.patch_user(&ids[0], cto.clone(), Some(1)).await,
+            Err(RepositoryError::VersionMismatch(_))
+        ));
+        let patched = repo.patch_user(&ids[0], cto, Some(2)).await.unwrap();
+        assert_eq!(
+            (patched.location.as_str(), patched.title.as_str()),
+            ("Braga", "CTO")
+        );
+        assert_eq!(patched.version, 3);
+        assert!(matches!(
+            repo.update_user(&ids[0], user("Rui", "Faro"), Some(2))
+                .await,
+            Err(RepositoryError::VersionMismatch(_))
+        ));
+        assert!(matches!(
+            repo.delete_user(&ids[0], Some(2)).await,
+            Err(RepositoryError::VersionMismatch(_))
+        ));
+        assert_eq!(repo.get_user(&ids[0]).await.unwrap().unwrap().version, 3);
+
+        // Pages of one user sorted by name, the cursor of each page leads to the next one
+        let mut query = UserQuery {
+            name_prefix: Some("An".to_string()),
+            sort: Some(UserSort {
+                field: UserSortField::Name,
+                descending: true,
+            }),
+            limit: Some(1),
+            count: Some(true),
+            ..Default::default()
+        };
+        let page = repo.list_users(query.clone()).await.unwrap();
+        assert_eq!(page.total, Some(2));
+        assert_eq!(page.users[0].name, "Anne");
+        query.after = page.next;
+        let page = repo.list_users(query).await.unwrap();
+        assert_eq!(page.users[0].name, "Ana");
+        assert!(page.next.is_none());
+
+        let streamed: Vec<User> = repo
+            .stream_users(UserQuery {
+                location: Some("Porto".to_string()),
+                ..Default::default()
+            })
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(streamed.len(), 1);
+        let found = repo
+            .search_users(UserSearchQuery {
+                q: "lisbon".to_string(),
+                limit: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(found[0].user.name, "Ana");
+
+        // Nothing of an atomic bulk write is kept when a write fails
+        let writes = vec![
+            UserWrite::Delete(ids[1].clone()),
+            UserWrite::Delete(missing),
+        ];
+        let atomic = BulkWriteOptions {
+            ordered: true,
+            atomic: true,
+        };
+        let results = repo.bulk_write(writes.clone(), atomic).await.unwrap();
+        assert!(matches!(results[1], Err(RepositoryError::NotFound(_))));
+        assert!(repo.get_user(&ids[1]).await.unwrap().is_some());
+        let results = repo
+            .bulk_write(writes, BulkWriteOptions::default())
+            .await
+            .unwrap();
+        assert!(results[0].is_ok());
+        assert!(repo.get_user(&ids[1]).await.unwrap().is_none());
+
+        repo.delete_user(&ids[0], Some(3)).await.unwrap();
+        assert!(matches!(
+            repo.delete_user(&ids[0], None).await,
+            Err(RepositoryError::NotFound(_))
+        ));
+        assert!(matches!(
+            repo.update_user(&ids[0], user("Rui", "Porto"), None).await,
+            Err(RepositoryError::NotFound(_))
+        ));
+
+        let api_key = ApiKey {
+            key_id: "ci".to_string(),
+            entry: ApiKeyEntry {
+                name: "ci".to_string(),
+                salt: "salt".to_string(),
+                hash: "hash".to_string(),
+                permissions: vec![],
+                expires_at: None,
+                enabled: true,
+            },
+            created_at: Utc::now(),
+        };
+        repo.create_api_key(api_key.clone()).await.unwrap();
+        assert!(repo.update_api_key(api_key.clone()).await.unwrap());
+        assert_eq!(repo.list_api_keys().await.unwrap().len(), 1);
+        // Enabled keys can't share a name, a revoked one frees it
+        let renamed = ApiKey {
+            key_id: "ci-2".to_string(),
+            ..api_key.clone()
+        };
+        assert!(matches!(
+            repo.create_api_key(renamed.clone()).await,
+            Err(RepositoryError::Conflict(_))
+        ));
+        let mut revoked = api_key;
+        revoked.entry.enabled = false;
+        assert!(repo.update_api_key(revoked).await.unwrap());
+        repo.create_api_key(renamed).await.unwrap();
+        assert!(repo.get_api_key("deploy").await.unwrap().is_none());
+
+        let principal = Principal::api_key("ci", HashSet::new());
+        let record = |target| {
+            AuditRecord::new(&principal, AuditAction::UserDelete, "request").target(target)
+        };
+        repo.record_audit(record("1")).await.unwrap();
+        repo.record_audits(vec![record("2"), record("3")])
+            .await
+            .unwrap();
+        repo.record_audits(vec![]).await.unwrap();
+        let records = repo
+            .find_audit_records(AuditQuery {
+                target: Some("2".to_string()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(records.len(), 1);
+        let records = repo
+            .find_audit_records(AuditQuery::default())
+            .await
+            .unwrap();
+        assert_eq!(records.len(), 3);
     }
 }
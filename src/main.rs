@@ -3,13 +3,19 @@ mod auth;
 mod configuration;
 mod database;
 mod models;
+mod rate_limit;
 
+use crate::api::error::error_handlers;
+use crate::api::request_id::RequestIdMiddleware;
 use crate::api::routes::routes;
-use crate::auth::{auth_middleware::AuthMiddleware, claims::AccessLevel};
+use crate::auth::{auth_middleware::AuthMiddleware, rbac::RoleMap, verifier::build_verifier};
+use crate::rate_limit::middleware::RateLimiters;
+use crate::rate_limit::store::{InMemoryStore, RateLimitStore};
 use actix_cors::Cors;
 use actix_web::{middleware::Logger, web, App, HttpServer};
 use dotenv::dotenv;
 use models::app::AppData;
+use std::sync::Arc;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -22,19 +28,42 @@ async fn main() -> std::io::Result<()> {
     let app_data = AppData::init().await;
     let wrapped_app_data = web::Data::new(app_data);
 
+    // Shared by all workers, the JWKS verifiers keep their key sets fresh with a background task
+    let verifier = build_verifier(&wrapped_app_data.config.issuers)
+        .expect("error building the JWT verifier from configuration");
+    // Role hierarchy is flattened once, so misconfigured roles fail the startup instead of the requests
+    let roles = Arc::new(
+        RoleMap::resolve(&wrapped_app_data.config.rbac)
+            .expect("error resolving the roles from configuration"),
+    );
+
+    // Created outside of the workers, so all of them count the requests of a client in the same bucket
+    let rate_limit_store: Arc<dyn RateLimitStore> = Arc::new(InMemoryStore::new());
+
     println!("🚀 Server started successfully");
 
     HttpServer::new(move || {
         let cors = Cors::permissive();
         App::new()
             .app_data(wrapped_app_data.clone())
+            .configure(error_handlers)
             .wrap(cors)
-            .wrap(Logger::default())
-            .service(routes(AuthMiddleware::new(
-                wrapped_app_data.config.api_key_data.clone(),
-                wrapped_app_data.config.auth0_data.clone(),
-                AccessLevel::Write,
-            )))
+            .wrap(RequestIdMiddleware)
+            .wrap(Logger::new(
+                r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %{x-request-id}o %T"#,
+            ))
+            .service(routes(
+                AuthMiddleware::new(
+                    wrapped_app_data.config.api_key_data.clone(),
+                    verifier.clone(),
+                    roles.clone(),
+                ),
+                wrapped_app_data.config.protect_read_endpoints,
+                RateLimiters::new(
+                    wrapped_app_data.config.rate_limit.clone(),
+                    rate_limit_store.clone(),
+                ),
+            ))
     })
     .bind(("127.0.0.1", 8000))?
     .run()
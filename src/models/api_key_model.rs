@@ -0,0 +1,61 @@
+use crate::auth::{api_key::ApiKeyEntry, error::ClientError, principal::Principal};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+// API key created at runtime, only the salted hash of the key is stored
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ApiKey {
+    pub key_id: String,
+    #[serde(flatten)]
+    pub entry: ApiKeyEntry,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CreateApiKeyRequest {
+    pub name: String,
+    #[serde(default)]
+    pub permissions: Vec<String>,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+// What the API returns about a key, without its hash and salt
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ApiKeyInfo {
+    pub key_id: String,
+    pub name: String,
+    pub permissions: Vec<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub enabled: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+// Returned when a key is created or rotated, the plaintext key can't be retrieved afterwards
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CreatedApiKey {
+    #[serde(flatten)]
+    pub info: ApiKeyInfo,
+    pub api_key: String,
+}
+
+impl ApiKey {
+    pub fn authenticate(&self, pepper: &str, api_key: &str) -> Result<Principal, ClientError> {
+        let mut principal = self.entry.authenticate(pepper, api_key)?;
+        principal.key_id = Some(self.key_id.to_string());
+        Ok(principal)
+    }
+}
+
+impl From<&ApiKey> for ApiKeyInfo {
+    fn from(api_key: &ApiKey) -> Self {
+        ApiKeyInfo {
+            key_id: api_key.key_id.to_string(),
+            name: api_key.entry.name.to_string(),
+            permissions: api_key.entry.permissions.clone(),
+            expires_at: api_key.entry.expires_at,
+            enabled: api_key.entry.enabled,
+            created_at: api_key.created_at,
+        }
+    }
+}
@@ -1,23 +1,65 @@
 use crate::configuration::config::load_default;
-use crate::configuration::config::Config;
+use crate::configuration::config::{Config, Storage};
+use crate::database::memory_repo::InMemoryRepo;
 use crate::database::mongodb_repo::MongoRepo;
-use crate::database::repository::Repository;
+use crate::database::postgres_repo::PostgresRepo;
+use crate::database::repository::{ApiKeyRepository, AuditRepository, Repository};
+use crate::database::sqlite_repo::SqliteRepo;
 use std::sync::Arc;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct AppData {
     pub db: Arc<dyn Repository>,
+    pub api_keys: Arc<dyn ApiKeyRepository>,
+    pub audit: Arc<dyn AuditRepository>,
     pub config: Config,
 }
 
 impl AppData {
     pub(crate) async fn init() -> AppData {
         let config = load_default().expect("error getting configuration.yaml file from ./");
-        let mongo_repo = MongoRepo::init(config.clone().mongo_uri).await;
-
-        AppData {
-            db: Arc::new(mongo_repo),
-            config,
+        match config.storage {
+            Storage::Mongo => {
+                let mongo_repo = MongoRepo::init(config.clone().mongo_uri).await;
+                AppData {
+                    db: Arc::new(mongo_repo.clone()),
+                    api_keys: Arc::new(mongo_repo.clone()),
+                    audit: Arc::new(mongo_repo),
+                    config,
+                }
+            }
+            Storage::Postgres => {
+                let postgres_repo = PostgresRepo::init(&config.postgres)
+                    .await
+                    .expect("error connecting to PostgreSQL");
+                AppData {
+                    db: Arc::new(postgres_repo.clone()),
+                    api_keys: Arc::new(postgres_repo.clone()),
+                    audit: Arc::new(postgres_repo),
+                    config,
+                }
+            }
+            Storage::Sqlite => {
+                let sqlite_repo = SqliteRepo::init(&config.sqlite)
+                    .await
+                    .expect("error opening the SQLite database");
+                AppData {
+                    db: Arc::new(sqlite_repo.clone()),
+                    api_keys: Arc::new(sqlite_repo.clone()),
+                    audit: Arc::new(sqlite_repo),
+                    config,
+                }
+            }
+            Storage::Memory => {
+                log::warn!("storage is memory, the data is lost when the server stops");
+                let memory_repo = InMemoryRepo::default();
+                AppData {
+                    db: Arc::new(memory_repo.clone()),
+                    api_keys: Arc::new(memory_repo.clone()),
+                    audit: Arc::new(memory_repo),
+                    config,
+                }
+            }
         }
     }
 }
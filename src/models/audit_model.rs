@@ -0,0 +1,110 @@
+use crate::auth::principal::Principal;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(strum_macros::Display, Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum AuditAction {
+    #[serde(rename = "user.create")]
+    #[strum(serialize = "user.create")]
+    UserCreate,
+    #[serde(rename = "user.update")]
+    #[strum(serialize = "user.update")]
+    UserUpdate,
+    #[serde(rename = "user.delete")]
+    #[strum(serialize = "user.delete")]
+    UserDelete,
+    #[serde(rename = "user.import")]
+    #[strum(serialize = "user.import")]
+    UserImport,
+    #[serde(rename = "user.batch")]
+    #[strum(serialize = "user.batch")]
+    UserBatch,
+    #[serde(rename = "api_key.create")]
+    #[strum(serialize = "api_key.create")]
+    ApiKeyCreate,
+    #[serde(rename = "api_key.rotate")]
+    #[strum(serialize = "api_key.rotate")]
+    ApiKeyRotate,
+    #[serde(rename = "api_key.revoke")]
+    #[strum(serialize = "api_key.revoke")]
+    ApiKeyRevoke,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "snake_case", tag = "status")]
+pub enum AuditOutcome {
+    Success,
+    Failure { error: String },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AuditRecord {
+    pub actor: Principal,
+    pub action: AuditAction,
+    // Id of the user or API key that was changed, unknown when a creation fails
+    pub target_id: Option<String>,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+    pub timestamp: DateTime<Utc>,
+    pub request_id: String,
+    pub outcome: AuditOutcome,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AuditQuery {
+    // Subject of the principal, i.e. the JWT sub or the API key name
+    pub actor: Option<String>,
+    pub target: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub limit: Option<u32>,
+}
+
+impl AuditQuery {
+    pub const DEFAULT_LIMIT: u32 = 100;
+    pub const MAX_LIMIT: u32 = 1000;
+
+    pub fn limit(&self) -> u32 {
+        self.limit
+            .unwrap_or(Self::DEFAULT_LIMIT)
+            .clamp(1, Self::MAX_LIMIT)
+    }
+}
+
+impl AuditRecord {
+    pub fn new(actor: &Principal, action: AuditAction, request_id: &str) -> Self {
+        AuditRecord {
+            actor: actor.clone(),
+            action,
+            target_id: None,
+            before: None,
+            after: None,
+            timestamp: Utc::now(),
+            request_id: request_id.to_string(),
+            outcome: AuditOutcome::Success,
+        }
+    }
+
+    pub fn target(mut self, target_id: &str) -> Self {
+        self.target_id = Some(target_id.to_string());
+        self
+    }
+
+    pub fn before<T: Serialize>(mut self, before: &T) -> Self {
+        self.before = serde_json::to_value(before).ok();
+        self
+    }
+
+    pub fn after<T: Serialize>(mut self, after: &T) -> Self {
+        self.after = serde_json::to_value(after).ok();
+        self
+    }
+
+    pub fn failed(mut self, error: &str) -> Self {
+        self.outcome = AuditOutcome::Failure {
+            error: error.to_string(),
+        };
+        self
+    }
+}
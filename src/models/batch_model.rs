@@ -0,0 +1,92 @@
+use crate::api::error::FieldError;
+use crate::models::user_model::{CreateUserRequest, UpdateUserRequest, User, UserId};
+use serde::{Deserialize, Serialize};
+
+// At most this many operations per batch, so a request can't hold the database for too long
+pub const MAX_BATCH_OPERATIONS: usize = 1000;
+
+// e.g. {"op": "update", "id": "65f0bbf848c60e78920bfd4c", "user": {"name": "Ana", ...}}
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum BatchOperation {
+    Create { user: CreateUserRequest },
+    Update { id: String, user: UpdateUserRequest },
+    Delete { id: String },
+}
+
+fn default_ordered() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BatchRequest {
+    pub operations: Vec<BatchOperation>,
+    // Stop at the first operation that fails, the next ones are skipped
+    #[serde(default = "default_ordered")]
+    pub ordered: bool,
+    // Keep none of the operations when one fails, requires a database with transactions
+    #[serde(default)]
+    pub atomic: bool,
+}
+
+// A write of Repository::bulk_write
+#[derive(Debug, Clone, PartialEq)]
+pub enum UserWrite {
+    Create(User),
+    Update(UserId, User),
+    Delete(UserId),
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct BulkWriteOptions {
+    pub ordered: bool,
+    pub atomic: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchStatus {
+    Created,
+    Updated,
+    Deleted,
+    Failed,
+    // Not run, an earlier operation of an ordered batch failed
+    Skipped,
+    // Succeeded, but undone because another operation of the atomic batch failed
+    RolledBack,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BatchResult {
+    // Position of the operation in the request, starting at 0
+    pub index: usize,
+    pub status: BatchStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<UserId>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BatchReport {
+    pub ordered: bool,
+    pub atomic: bool,
+    pub succeeded: usize,
+    pub failed: usize,
+    pub results: Vec<BatchResult>,
+}
+
+impl BatchReport {
+    pub fn push(&mut self, result: BatchResult) {
+        match result.status {
+            BatchStatus::Created | BatchStatus::Updated | BatchStatus::Deleted => {
+                self.succeeded += 1
+            }
+            BatchStatus::Failed | BatchStatus::Skipped | BatchStatus::RolledBack => {
+                self.failed += 1
+            }
+        }
+        self.results.push(result);
+    }
+}
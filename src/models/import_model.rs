@@ -0,0 +1,49 @@
+use crate::api::error::FieldError;
+use crate::models::user_model::UserId;
+use serde::{Deserialize, Serialize};
+
+// e.g. POST /api/admin/users:import?dry_run=true
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ImportQuery {
+    // Only validate the rows, nothing is written
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportRowStatus {
+    Created,
+    // Passed the validation of a dry run
+    Valid,
+    Failed,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImportRow {
+    // Number of the row in the file, starting at 1 and not counting the CSV header
+    pub row: usize,
+    pub status: ImportRowStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<UserId>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub succeeded: usize,
+    pub failed: usize,
+    pub rows: Vec<ImportRow>,
+}
+
+impl ImportReport {
+    pub fn push(&mut self, row: ImportRow) {
+        match row.status {
+            ImportRowStatus::Failed => self.failed += 1,
+            ImportRowStatus::Created | ImportRowStatus::Valid => self.succeeded += 1,
+        }
+        self.rows.push(row);
+    }
+}
@@ -1,3 +1,6 @@
+pub mod api_key_model;
 pub mod app;
-pub mod error;
+pub mod audit_model;
+pub mod batch_model;
+pub mod import_model;
 pub mod user_model;
@@ -1,18 +1,218 @@
+use crate::api::error::ApiError;
+use actix_web::{dev::Payload, FromRequest, HttpRequest};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use mongodb::bson::oid::ObjectId;
-use serde::{Deserialize, Serialize};
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt::{Display, Formatter};
+use std::future::{ready, Ready};
+use uuid::Uuid;
+use validator::{Validate, ValidationError, ValidationErrors};
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct User {
-    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
-    pub id: Option<ObjectId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<UserId>,
     pub name: String,
     pub location: String,
     pub title: String,
+    // Incremented by every write that changes the user, sent as its ETag. Set by the repository
+    #[serde(default)]
+    pub version: u64,
+}
+
+// Body of POST /api/admin/user, the id is generated by the repository so it can't be sent
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct CreateUserRequest {
+    #[serde(deserialize_with = "trimmed")]
+    pub name: String,
+    #[serde(deserialize_with = "trimmed")]
+    pub location: String,
+    #[serde(deserialize_with = "trimmed")]
+    pub title: String,
+}
+
+impl Validate for CreateUserRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        validate_user_fields(&self.name, &self.location, &self.title)
+    }
+}
+
+// Body of PUT /api/admin/user/{id}, every field is replaced, the id comes from the path
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct UpdateUserRequest {
+    #[serde(deserialize_with = "trimmed")]
+    pub name: String,
+    #[serde(deserialize_with = "trimmed")]
+    pub location: String,
+    #[serde(deserialize_with = "trimmed")]
+    pub title: String,
+}
+
+impl Validate for UpdateUserRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        validate_user_fields(&self.name, &self.location, &self.title)
+    }
+}
+
+// Changes of a PATCH, only the fields that are Some are written
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct UserPatch {
+    pub name: Option<String>,
+    pub location: Option<String>,
+    pub title: Option<String>,
+}
+
+impl UserPatch {
+    // Only the fields that differ from the current user, so unchanged fields aren't written
+    pub fn diff(current: &User, patched: UpdateUserRequest) -> Self {
+        let changed = |current: &str, patched: String| (current != patched).then_some(patched);
+        UserPatch {
+            name: changed(&current.name, patched.name),
+            location: changed(&current.location, patched.location),
+            title: changed(&current.title, patched.title),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self == &UserPatch::default()
+    }
+}
+
+fn trimmed<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    String::deserialize(deserializer).map(|value| value.trim().to_string())
+}
+
+// The rules of the fields of a user, the same for every body that writes them
+fn validate_user_fields(name: &str, location: &str, title: &str) -> Result<(), ValidationErrors> {
+    let mut errors = ValidationErrors::new();
+    validate_field(&mut errors, "name", name, validate_name);
+    validate_field(&mut errors, "location", location, validate_text);
+    validate_field(&mut errors, "title", title, validate_text);
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(errors),
+    }
+}
+
+fn validate_field(
+    errors: &mut ValidationErrors,
+    field: &'static str,
+    value: &str,
+    validate_characters: fn(&str) -> Result<(), ValidationError>,
+) {
+    if !(1..=100).contains(&value.chars().count()) {
+        errors.add(
+            field,
+            ValidationError::new("length")
+                .with_message("must be between 1 and 100 characters".into()),
+        );
+    }
+    if let Err(error) = validate_characters(value) {
+        errors.add(field, error);
+    }
+}
+
+// Letters of any language, spaces and the punctuation found in names, e.g. "Anne-Marie O'Neil Jr."
+fn validate_name(value: &str) -> Result<(), ValidationError> {
+    if value
+        .chars()
+        .all(|c| c.is_alphabetic() || " '-.".contains(c))
+    {
+        return Ok(());
+    }
+    Err(ValidationError::new("invalid_characters")
+        .with_message("may only contain letters, spaces, apostrophes, hyphens and dots".into()))
+}
+
+// Free text, anything printable
+fn validate_text(value: &str) -> Result<(), ValidationError> {
+    if value.chars().any(char::is_control) {
+        return Err(ValidationError::new("invalid_characters")
+            .with_message("may not contain control characters".into()));
+    }
+    Ok(())
+}
+
+// The id of a user, a plain string in JSON. Each repository maps it to the id type of its store, e.g. an ObjectId
+// in MongoDB or an integer in memory, so the API doesn't depend on the backend
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct UserId(String);
+
+impl UserId {
+    // Used for the ids sent by clients, e.g. the {id} segment of the user routes, so malformed ids are rejected with
+    // a 400 before they reach the repository
+    pub fn parse(id: &str) -> Result<Self, ApiError> {
+        UserId::try_from(id.to_string()).map_err(ApiError::InvalidId)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+// The formats of the ids the stores generate, ObjectIds, UUIDs or integers, always written the same way, so
+// the same user has a single id
+impl TryFrom<String> for UserId {
+    type Error = String;
+
+    fn try_from(id: String) -> Result<Self, Self::Error> {
+        if let Ok(object_id) = ObjectId::parse_str(&id) {
+            return Ok(object_id.into());
+        }
+        if let Ok(uuid) = Uuid::try_parse(&id) {
+            return Ok(UserId(uuid.hyphenated().to_string()));
+        }
+        match id.parse::<u64>() {
+            Ok(number) => Ok(number.into()),
+            Err(_) => Err(format!("{} is not a valid user id", id)),
+        }
+    }
+}
+
+impl From<UserId> for String {
+    fn from(id: UserId) -> Self {
+        id.0
+    }
+}
+
+impl From<ObjectId> for UserId {
+    fn from(id: ObjectId) -> Self {
+        UserId(id.to_hex())
+    }
+}
+
+impl From<u64> for UserId {
+    fn from(id: u64) -> Self {
+        UserId(id.to_string())
+    }
+}
+
+impl Display for UserId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromRequest for UserId {
+    type Error = ApiError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(match req.match_info().get("id") {
+            Some(id) => UserId::parse(id),
+            None => Err(ApiError::Internal(
+                "The route has no {id} segment".to_string(),
+            )),
+        })
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct CreateUserResult {
-    pub id: String,
+    pub id: UserId,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -27,10 +227,236 @@ pub struct DeleteUserResult {
     pub deleted_count: u64,
 }
 
-// TODO - This is the correct way of
-#[derive(Serialize, Deserialize)]
-pub struct Item {
-    #[serde(serialize_with = "bson::serde_helpers::serialize_object_id_as_hex_string")]
-    pub id: ObjectId,
-    // rest of fields
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UserSortField {
+    Id,
+    Name,
+    Location,
+    Title,
+}
+
+impl UserSortField {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Id => "_id",
+            Self::Name => "name",
+            Self::Location => "location",
+            Self::Title => "title",
+        }
+    }
+
+    // The value the users are sorted on, None when sorting on the id, which the cursor always stores
+    pub fn value(&self, user: &User) -> Option<String> {
+        match self {
+            Self::Id => None,
+            Self::Name => Some(user.name.to_owned()),
+            Self::Location => Some(user.location.to_owned()),
+            Self::Title => Some(user.title.to_owned()),
+        }
+    }
+}
+
+// e.g. sort=name or sort=-name for a descending order, ties are broken by the id
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct UserSort {
+    pub field: UserSortField,
+    pub descending: bool,
+}
+
+impl Default for UserSort {
+    fn default() -> Self {
+        UserSort {
+            field: UserSortField::Id,
+            descending: false,
+        }
+    }
+}
+
+impl TryFrom<String> for UserSort {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let (descending, name) = match value.strip_prefix('-') {
+            Some(name) => (true, name),
+            None => (false, value.as_str()),
+        };
+        let field = match name {
+            "_id" | "id" => UserSortField::Id,
+            "name" => UserSortField::Name,
+            "location" => UserSortField::Location,
+            "title" => UserSortField::Title,
+            _ => {
+                return Err(format!(
+                    "can't sort on {}, the sort fields are id, name, location and title",
+                    name
+                ))
+            }
+        };
+        Ok(UserSort { field, descending })
+    }
+}
+
+impl From<UserSort> for String {
+    fn from(sort: UserSort) -> Self {
+        let prefix = if sort.descending { "-" } else { "" };
+        format!("{}{}", prefix, sort.field.name())
+    }
+}
+
+// Position of the last user of a page, sent to the client as an opaque token
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserCursor {
+    pub sort: UserSort,
+    pub value: Option<String>,
+    pub id: UserId,
+}
+
+impl UserCursor {
+    pub fn after(user: &User, sort: UserSort) -> Option<Self> {
+        Some(UserCursor {
+            sort,
+            value: sort.field.value(user),
+            id: user.id.clone()?,
+        })
+    }
+
+    fn encode(&self) -> String {
+        let json = serde_json::to_vec(self).unwrap_or_default();
+        URL_SAFE_NO_PAD.encode(json)
+    }
+
+    fn decode(token: &str) -> Option<Self> {
+        let json = URL_SAFE_NO_PAD.decode(token).ok()?;
+        serde_json::from_slice(&json).ok()
+    }
+}
+
+fn serialize_cursor<S: Serializer>(
+    cursor: &Option<UserCursor>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match cursor {
+        Some(cursor) => serializer.serialize_str(&cursor.encode()),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn deserialize_cursor<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<UserCursor>, D::Error> {
+    match Option::<String>::deserialize(deserializer)? {
+        Some(token) => UserCursor::decode(&token)
+            .map(Some)
+            .ok_or_else(|| D::Error::custom("next isn't a valid cursor")),
+        None => Ok(None),
+    }
+}
+
+// e.g. GET /api/users?location=Lisbon&name_prefix=An&sort=-name&limit=20&count=true
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct UserQuery {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name_prefix: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location_prefix: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title_prefix: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sort: Option<UserSort>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u32>,
+    // Also count the users matching the filters, which costs another query
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub count: Option<bool>,
+    // Token of the next page, returned in the Link header of the previous one
+    #[serde(
+        default,
+        rename = "next",
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_cursor",
+        deserialize_with = "deserialize_cursor"
+    )]
+    pub after: Option<UserCursor>,
+}
+
+impl UserQuery {
+    pub const DEFAULT_LIMIT: u32 = 50;
+    pub const MAX_LIMIT: u32 = 500;
+
+    pub fn limit(&self) -> u32 {
+        self.limit
+            .unwrap_or(Self::DEFAULT_LIMIT)
+            .clamp(1, Self::MAX_LIMIT)
+    }
+
+    pub fn sort(&self) -> UserSort {
+        self.sort.unwrap_or_default()
+    }
+
+    // Filters as (field, value, prefix) tuples, shared by the repositories
+    pub fn filters(&self) -> Vec<(&'static str, &str, bool)> {
+        [
+            ("name", &self.name, false),
+            ("name", &self.name_prefix, true),
+            ("location", &self.location, false),
+            ("location", &self.location_prefix, true),
+            ("title", &self.title, false),
+            ("title", &self.title_prefix, true),
+        ]
+        .into_iter()
+        .filter_map(|(field, value, prefix)| Some((field, value.as_deref()?, prefix)))
+        .collect()
+    }
+
+    // A cursor only makes sense with the order of the page it was created from
+    pub fn check_cursor(&self) -> Result<(), ApiError> {
+        match &self.after {
+            Some(cursor) if cursor.sort != self.sort() => Err(ApiError::InvalidQuery(
+                "next was returned for another sort order".to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserPage {
+    pub users: Vec<User>,
+    // None on the last page
+    pub next: Option<UserCursor>,
+    // Only when requested with count
+    pub total: Option<u64>,
+}
+
+// e.g. GET /api/users/search?q=rust engineer&limit=10
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct UserSearchQuery {
+    pub q: String,
+    pub limit: Option<u32>,
+}
+
+impl UserSearchQuery {
+    pub const DEFAULT_LIMIT: u32 = 20;
+    pub const MAX_LIMIT: u32 = 100;
+
+    pub fn limit(&self) -> u32 {
+        self.limit
+            .unwrap_or(Self::DEFAULT_LIMIT)
+            .clamp(1, Self::MAX_LIMIT)
+    }
+}
+
+// A user matching a search, the most relevant ones have the highest score
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct UserSearchResult {
+    #[serde(flatten)]
+    pub user: User,
+    pub score: f64,
 }
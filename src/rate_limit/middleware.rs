@@ -0,0 +1,209 @@
+use std::{
+    collections::HashMap,
+    future::{ready, Ready},
+    rc::Rc,
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::api::error::ApiError;
+use crate::auth::principal::{AuthMethod, Principal};
+use crate::rate_limit::store::{RateLimit, RateLimitDecision, RateLimitStore};
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::{
+        header::{HeaderMap, HeaderName, HeaderValue, RETRY_AFTER},
+        StatusCode,
+    },
+    Error, HttpMessage,
+};
+use futures_util::future::LocalBoxFuture;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub struct RateLimitData {
+    // Limits per route group, e.g. admin and public, groups without limits aren't throttled
+    #[serde(default)]
+    pub groups: HashMap<String, RateLimit>,
+    // Only enable behind a proxy that sets Forwarded/X-Forwarded-For, otherwise clients can pick their own IP
+    #[serde(default)]
+    pub trust_forwarded_headers: bool,
+}
+
+// Group of the failed authentications, shared by the route groups so the guesses are counted together
+pub const AUTH_FAILURES: &str = "auth_failures";
+
+// Builds the RateLimiter of each route group, all of them sharing the same store
+#[derive(Clone)]
+pub struct RateLimiters {
+    data: Rc<RateLimitData>,
+    store: Arc<dyn RateLimitStore>,
+}
+
+impl RateLimiters {
+    pub fn new(data: RateLimitData, store: Arc<dyn RateLimitStore>) -> Self {
+        RateLimiters {
+            data: Rc::new(data),
+            store,
+        }
+    }
+
+    pub fn group(&self, name: &str) -> RateLimiter {
+        RateLimiter {
+            group: Rc::new(name.to_string()),
+            limit: self.data.groups.get(name).copied(),
+            trust_forwarded_headers: self.data.trust_forwarded_headers,
+            store: self.store.clone(),
+            failures_only: false,
+        }
+    }
+
+    // Wrapped outside AuthMiddleware, so the buckets are keyed by the client IP. Only the requests rejected with a
+    // 401 take a token, and once the bucket is empty the requests are rejected before their credentials are
+    // checked, so a right guess can't be told apart
+    pub fn auth_failures(&self) -> RateLimiter {
+        RateLimiter {
+            failures_only: true,
+            ..self.group(AUTH_FAILURES)
+        }
+    }
+}
+
+// RateLimiter keys the buckets by API key, JWT subject, or client IP, so it must be wrapped inside AuthMiddleware
+// for the principal to be known
+#[derive(Clone)]
+pub struct RateLimiter {
+    group: Rc<String>,
+    limit: Option<RateLimit>,
+    trust_forwarded_headers: bool,
+    store: Arc<dyn RateLimitStore>,
+    failures_only: bool,
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimiter
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RateLimiterMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimiterMiddleware {
+            service: Rc::new(service),
+            limiter: self.clone(),
+        }))
+    }
+}
+
+pub struct RateLimiterMiddleware<S> {
+    service: Rc<S>,
+    limiter: RateLimiter,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimiterMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let limiter = self.limiter.clone();
+
+        Box::pin(async move {
+            let limit = match limiter.limit {
+                Some(limit) => limit,
+                None => return Ok(service.call(req).await?.map_into_left_body()),
+            };
+            let key = format!(
+                "{}:{}",
+                limiter.group,
+                client_key(&req, limiter.trust_forwarded_headers)
+            );
+            let decision = match limiter.failures_only {
+                true => limiter.store.check(&key, &limit).await,
+                false => limiter.store.acquire(&key, &limit).await,
+            };
+
+            if !decision.allowed {
+                let mut res = req.error_response(ApiError::RateLimited);
+                insert_headers(res.headers_mut(), &decision);
+                return Ok(res.map_into_right_body());
+            }
+            if limiter.failures_only {
+                let result = service.call(req).await;
+                let status = match &result {
+                    Ok(res) => res.status(),
+                    Err(err) => err.as_response_error().status_code(),
+                };
+                if status == StatusCode::UNAUTHORIZED {
+                    limiter.store.acquire(&key, &limit).await;
+                }
+                return Ok(result?.map_into_left_body());
+            }
+
+            let mut res = service.call(req).await?;
+            insert_headers(res.headers_mut(), &decision);
+            Ok(res.map_into_left_body())
+        })
+    }
+}
+
+fn client_key(req: &ServiceRequest, trust_forwarded_headers: bool) -> String {
+    if let Some(principal) = req.extensions().get::<Principal>() {
+        return match principal.auth_method {
+            AuthMethod::ApiKey => format!(
+                "key:{}",
+                principal.key_id.as_deref().unwrap_or(&principal.subject)
+            ),
+            AuthMethod::Jwt => format!(
+                "sub:{}|{}",
+                principal.issuer.as_deref().unwrap_or_default(),
+                principal.subject
+            ),
+        };
+    }
+    let ip = if trust_forwarded_headers {
+        req.connection_info()
+            .realip_remote_addr()
+            .map(str::to_string)
+    } else {
+        req.peer_addr().map(|addr| addr.ip().to_string())
+    };
+    format!("ip:{}", ip.unwrap_or_default())
+}
+
+// RateLimit-* headers from the IETF draft, with the delays rounded up to whole seconds
+fn insert_headers(headers: &mut HeaderMap, decision: &RateLimitDecision) {
+    headers.insert(
+        HeaderName::from_static("ratelimit-limit"),
+        HeaderValue::from(decision.limit),
+    );
+    headers.insert(
+        HeaderName::from_static("ratelimit-remaining"),
+        HeaderValue::from(decision.remaining),
+    );
+    headers.insert(
+        HeaderName::from_static("ratelimit-reset"),
+        HeaderValue::from(ceil_secs(decision.reset_after)),
+    );
+    if let Some(retry_after) = decision.retry_after {
+        headers.insert(RETRY_AFTER, HeaderValue::from(ceil_secs(retry_after)));
+    }
+}
+
+fn ceil_secs(duration: Duration) -> u64 {
+    duration.as_secs_f64().ceil() as u64
+}
@@ -0,0 +1,189 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    // Sustained rate, tokens are added to the bucket continuously
+    pub requests_per_minute: u32,
+    // Bucket size, i.e. how many requests can be made at once after being idle
+    pub burst: u32,
+}
+
+impl RateLimit {
+    fn refill_per_sec(&self) -> f64 {
+        self.requests_per_minute as f64 / 60.0
+    }
+
+    // Time for the bucket to gain the given number of tokens
+    fn time_to_refill(&self, tokens: f64) -> Duration {
+        if tokens <= 0.0 {
+            return Duration::ZERO;
+        }
+        if self.requests_per_minute == 0 {
+            return Duration::MAX;
+        }
+        Duration::from_secs_f64(tokens / self.refill_per_sec())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimitDecision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    // Until the bucket is full again
+    pub reset_after: Duration,
+    // Until the next request is allowed, only set when this one isn't
+    pub retry_after: Option<Duration>,
+}
+
+// Buckets are kept behind a trait, so a store shared by all the instances (e.g. Redis) can replace the in-memory one
+#[async_trait]
+pub trait RateLimitStore: Send + Sync {
+    async fn acquire(&self, key: &str, limit: &RateLimit) -> RateLimitDecision;
+    // The decision acquire would make, without taking a token
+    async fn check(&self, key: &str, limit: &RateLimit) -> RateLimitDecision;
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+    // None when it's never full again, e.g. without requests per minute
+    full_at: Option<Instant>,
+}
+
+// Buckets that are full again are dropped every SWEEP_INTERVAL, and once MAX_BUCKETS are stored, the least
+// recently used one is evicted for each new client
+pub(crate) const MAX_BUCKETS: usize = 10_000;
+pub(crate) const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+pub struct InMemoryStore {
+    buckets: Mutex<Buckets>,
+}
+
+#[derive(Default)]
+struct Buckets {
+    by_key: HashMap<String, Bucket>,
+    // The same buckets by their last use, so the eviction doesn't scan them all
+    by_use: BTreeSet<(Instant, String)>,
+    swept_at: Option<Instant>,
+}
+
+impl InMemoryStore {
+    pub fn new() -> Self {
+        InMemoryStore::default()
+    }
+
+    pub(crate) fn acquire_at(
+        &self,
+        key: &str,
+        limit: &RateLimit,
+        now: Instant,
+    ) -> RateLimitDecision {
+        self.buckets.lock().unwrap().take(key, limit, now, 1.0)
+    }
+
+    pub(crate) fn check_at(&self, key: &str, limit: &RateLimit, now: Instant) -> RateLimitDecision {
+        self.buckets.lock().unwrap().take(key, limit, now, 0.0)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.buckets.lock().unwrap().by_key.len()
+    }
+}
+
+impl Buckets {
+    // A missing bucket is full, it isn't stored until a token is taken
+    fn take(&mut self, key: &str, limit: &RateLimit, now: Instant, cost: f64) -> RateLimitDecision {
+        self.sweep(now);
+        let Some(bucket) = self.by_key.get_mut(key) else {
+            let mut bucket = Bucket::full(limit, now);
+            let decision = bucket.take(limit, now, cost);
+            if cost > 0.0 {
+                if self.by_key.len() >= MAX_BUCKETS {
+                    if let Some((_, oldest)) = self.by_use.pop_first() {
+                        self.by_key.remove(&oldest);
+                    }
+                }
+                self.by_use.insert((now, key.to_string()));
+                self.by_key.insert(key.to_string(), bucket);
+            }
+            return decision;
+        };
+        self.by_use.remove(&(bucket.updated_at, key.to_string()));
+        let decision = bucket.take(limit, now, cost);
+        self.by_use.insert((bucket.updated_at, key.to_string()));
+        decision
+    }
+
+    fn sweep(&mut self, now: Instant) {
+        if self
+            .swept_at
+            .is_some_and(|swept_at| now.saturating_duration_since(swept_at) < SWEEP_INTERVAL)
+        {
+            return;
+        }
+        self.swept_at = Some(now);
+        let by_use = &mut self.by_use;
+        self.by_key.retain(|key, bucket| {
+            let full = bucket.full_at.is_some_and(|full_at| full_at <= now);
+            if full {
+                by_use.remove(&(bucket.updated_at, key.clone()));
+            }
+            !full
+        });
+    }
+}
+
+impl Bucket {
+    fn full(limit: &RateLimit, now: Instant) -> Self {
+        Bucket {
+            tokens: limit.burst as f64,
+            updated_at: now,
+            full_at: Some(now),
+        }
+    }
+
+    // Refills the bucket, then takes the cost when at least one token is left
+    fn take(&mut self, limit: &RateLimit, now: Instant, cost: f64) -> RateLimitDecision {
+        let capacity = limit.burst as f64;
+        self.tokens =
+            (self.tokens + limit.refill_per_sec() * elapsed_secs(self, now)).min(capacity);
+        self.updated_at = now;
+
+        let allowed = self.tokens >= 1.0;
+        if allowed {
+            self.tokens -= cost;
+        }
+        let reset_after = limit.time_to_refill(capacity - self.tokens);
+        self.full_at = now.checked_add(reset_after);
+        RateLimitDecision {
+            allowed,
+            limit: limit.burst,
+            remaining: self.tokens.floor() as u32,
+            reset_after,
+            retry_after: (!allowed).then(|| limit.time_to_refill(1.0 - self.tokens)),
+        }
+    }
+}
+
+fn elapsed_secs(bucket: &Bucket, now: Instant) -> f64 {
+    now.saturating_duration_since(bucket.updated_at)
+        .as_secs_f64()
+}
+
+#[async_trait]
+impl RateLimitStore for InMemoryStore {
+    async fn acquire(&self, key: &str, limit: &RateLimit) -> RateLimitDecision {
+        self.acquire_at(key, limit, Instant::now())
+    }
+
+    async fn check(&self, key: &str, limit: &RateLimit) -> RateLimitDecision {
+        self.check_at(key, limit, Instant::now())
+    }
+}
@@ -0,0 +1,187 @@
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod tests {
+    use crate::auth::principal::Principal;
+    use crate::rate_limit::middleware::{RateLimitData, RateLimiters};
+    use crate::rate_limit::store::{InMemoryStore, RateLimit, MAX_BUCKETS, SWEEP_INTERVAL};
+    use actix_web::dev::Service;
+    use actix_web::http::StatusCode;
+    use actix_web::{get, test, App, HttpMessage, HttpResponse};
+    use std::collections::{HashMap, HashSet};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    const LIMIT: RateLimit = RateLimit {
+        requests_per_minute: 60,
+        burst: 2,
+    };
+
+    #[actix_web::test]
+    async fn test_token_bucket() {
+        let store = InMemoryStore::new();
+        let now = Instant::now();
+
+        let first = store.acquire_at("client", &LIMIT, now);
+        assert!(first.allowed);
+        assert_eq!(first.remaining, 1);
+        assert!(store.acquire_at("client", &LIMIT, now).allowed);
+
+        let limited = store.acquire_at("client", &LIMIT, now);
+        assert!(!limited.allowed);
+        assert_eq!(limited.remaining, 0);
+        assert_eq!(limited.retry_after, Some(Duration::from_secs(1)));
+        assert_eq!(limited.reset_after, Duration::from_secs(2));
+
+        // Other clients have their own bucket
+        assert!(store.acquire_at("other", &LIMIT, now).allowed);
+
+        // One request per second is refilled, up to the burst
+        assert!(
+            store
+                .acquire_at("client", &LIMIT, now + Duration::from_secs(1))
+                .allowed
+        );
+        assert!(
+            !store
+                .acquire_at("client", &LIMIT, now + Duration::from_secs(1))
+                .allowed
+        );
+        let refilled = store.acquire_at("client", &LIMIT, now + Duration::from_secs(60));
+        assert!(refilled.allowed);
+        assert_eq!(refilled.remaining, 1);
+    }
+
+    #[actix_web::test]
+    async fn test_check_doesnt_take_tokens() {
+        let store = InMemoryStore::new();
+        let now = Instant::now();
+
+        assert_eq!(store.check_at("client", &LIMIT, now).remaining, 2);
+        store.acquire_at("client", &LIMIT, now);
+        store.acquire_at("client", &LIMIT, now);
+        for _ in 0..2 {
+            let checked = store.check_at("client", &LIMIT, now);
+            assert!(!checked.allowed);
+            assert_eq!(checked.retry_after, Some(Duration::from_secs(1)));
+        }
+        assert!(
+            store
+                .check_at("client", &LIMIT, now + Duration::from_secs(1))
+                .allowed
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_bucket_eviction() {
+        let store = InMemoryStore::new();
+        let now = Instant::now();
+
+        store.acquire_at("client", &LIMIT, now);
+        store.acquire_at("client", &LIMIT, now);
+        let later = now + Duration::from_millis(1);
+        for client in 1..MAX_BUCKETS {
+            store.acquire_at(&client.to_string(), &LIMIT, later);
+        }
+        assert_eq!(store.len(), MAX_BUCKETS);
+
+        // The least recently used bucket makes room for the new client
+        store.acquire_at("new", &LIMIT, later);
+        assert_eq!(store.len(), MAX_BUCKETS);
+        assert_eq!(store.acquire_at("client", &LIMIT, later).remaining, 1);
+        assert_eq!(store.len(), MAX_BUCKETS);
+
+        // The buckets that are full again are dropped by the next sweep
+        store.acquire_at("client", &LIMIT, now + SWEEP_INTERVAL);
+        assert_eq!(store.len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn test_bucket_without_refill() {
+        let store = InMemoryStore::new();
+        let now = Instant::now();
+        let limit = RateLimit {
+            requests_per_minute: 0,
+            burst: 1,
+        };
+
+        assert!(store.acquire_at("client", &limit, now).allowed);
+        // The bucket is never full again, so the sweeps keep it
+        let later = now + SWEEP_INTERVAL * 10;
+        let limited = store.acquire_at("client", &limit, later);
+        assert!(!limited.allowed);
+        assert_eq!(limited.retry_after, Some(Duration::MAX));
+        assert_eq!(store.len(), 1);
+    }
+
+    #[get("/limited")]
+    async fn limited_endpoint() -> HttpResponse {
+        HttpResponse::Ok().finish()
+    }
+
+    fn rate_limiters() -> RateLimiters {
+        RateLimiters::new(
+            RateLimitData {
+                groups: HashMap::from([("admin".to_string(), LIMIT)]),
+                trust_forwarded_headers: false,
+            },
+            Arc::new(InMemoryStore::new()),
+        )
+    }
+
+    #[actix_web::test]
+    async fn test_rate_limiter_middleware() {
+        let app = test::init_service(
+            App::new().service(
+                actix_web::web::scope("")
+                    .wrap(rate_limiters().group("admin"))
+                    .service(limited_endpoint),
+            ),
+        )
+        .await;
+
+        let request = || {
+            test::TestRequest::with_uri("/limited")
+                .peer_addr("10.0.0.1:1234".parse().unwrap())
+                .to_request()
+        };
+        let resp = test::call_service(&app, request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get("ratelimit-limit").unwrap(), "2");
+        assert_eq!(resp.headers().get("ratelimit-remaining").unwrap(), "1");
+        assert!(resp.headers().get("retry-after").is_none());
+        test::call_service(&app, request()).await;
+
+        let resp = test::call_service(&app, request()).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers().get("retry-after").unwrap(), "1");
+        assert_eq!(resp.headers().get("ratelimit-remaining").unwrap(), "0");
+
+        // Each principal has its own bucket, even from the same IP
+        let req = request();
+        req.extensions_mut().insert(Principal::api_key(
+            "ci",
+            HashSet::from(["Read".to_string()]),
+        ));
+        assert_eq!(app.call(req).await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn test_group_without_limit() {
+        let app = test::init_service(
+            App::new().service(
+                actix_web::web::scope("")
+                    .wrap(rate_limiters().group("public"))
+                    .service(limited_endpoint),
+            ),
+        )
+        .await;
+
+        for _ in 0..5 {
+            let resp =
+                test::call_service(&app, test::TestRequest::with_uri("/limited").to_request())
+                    .await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert!(resp.headers().get("ratelimit-limit").is_none());
+        }
+    }
+}